@@ -0,0 +1,13 @@
+//! The redis-cli work-alike, as `cargo run --example cli -- [options] [command]`.
+
+use std::env;
+
+use redis_starter_rust::cli;
+use tokio::runtime;
+
+fn main() -> anyhow::Result<()> {
+    runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(cli::run(env::args().skip(1)))
+}
@@ -0,0 +1,48 @@
+//! Append-only log of write commands, enabled with `--audit-log <path>`.
+//!
+//! One tab-separated line per write: unix milliseconds, client address,
+//! user, command name and the keys it touches.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+/// There is no AUTH, so every client acts as the default user.
+const USER: &str = "default";
+
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open audit log {path}"))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, peer: &SocketAddr, command: &str, keys: &[&[u8]]) {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let keys: Vec<_> = keys.iter().map(|k| k.escape_ascii().to_string()).collect();
+        let line = format!("{millis}\t{peer}\t{USER}\t{command}\t{}\n", keys.join(" "));
+        // a single write_all per line, so concurrent writers never interleave
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            eprintln!("failed to write audit log: {e}");
+        }
+    }
+}
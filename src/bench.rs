@@ -0,0 +1,204 @@
+//! A small redis-benchmark work-alike, run as `redis-starter-rust bench [options]`.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use anyhow::Context;
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    protocol::{DataType, Limits, RespReader},
+    random::Rng,
+};
+
+const USAGE: &str = "\
+usage: bench [-h host] [-p port] [-c clients] [-n requests] [-P pipeline]
+             [-d size] [-r keyspace] [-t tests]
+
+  -c  parallel connections (default 50)
+  -n  requests per test (default 100000)
+  -P  requests pipelined per round trip (default 1)
+  -d  SET value size in bytes (default 3)
+  -r  use random keys from 0 to keyspace-1 instead of a single key
+  -t  comma-separated tests out of ping,echo,set,get (default all)";
+
+const TESTS: [&str; 4] = ["ping", "echo", "set", "get"];
+
+struct Options {
+    host: String,
+    port: u16,
+    clients: usize,
+    requests: usize,
+    pipeline: usize,
+    value_size: usize,
+    keyspace: Option<u64>,
+    tests: Vec<String>,
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> anyhow::Result<Options> {
+    let mut options = Options {
+        host: "127.0.0.1".to_string(),
+        port: 6379,
+        clients: 50,
+        requests: 100_000,
+        pipeline: 1,
+        value_size: 3,
+        keyspace: None,
+        tests: TESTS.iter().map(|t| t.to_string()).collect(),
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().context(USAGE);
+        match arg.as_str() {
+            "-h" => options.host = value()?,
+            "-p" => options.port = value()?.parse().context(USAGE)?,
+            "-c" => options.clients = value()?.parse().context(USAGE)?,
+            "-n" => options.requests = value()?.parse().context(USAGE)?,
+            "-P" => options.pipeline = value()?.parse().context(USAGE)?,
+            "-d" => options.value_size = value()?.parse().context(USAGE)?,
+            "-r" => options.keyspace = Some(value()?.parse().context(USAGE)?),
+            "-t" => {
+                options.tests = value()?
+                    .split(',')
+                    .map(|t| t.trim().to_ascii_lowercase())
+                    .collect();
+            }
+            _ => anyhow::bail!("unknown option {arg}\n\n{USAGE}"),
+        }
+    }
+    anyhow::ensure!(
+        options.clients > 0 && options.pipeline > 0,
+        "clients and pipeline must be positive"
+    );
+    for test in &options.tests {
+        anyhow::ensure!(TESTS.contains(&test.as_str()), "unknown test {test}");
+    }
+    Ok(options)
+}
+
+pub async fn run(args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let options = Arc::new(parse_options(args)?);
+    for test in &options.tests {
+        run_test(&options, test).await?;
+    }
+    Ok(())
+}
+
+async fn run_test(options: &Arc<Options>, test: &str) -> anyhow::Result<()> {
+    let remaining = Arc::new(AtomicUsize::new(options.requests));
+    let started = Instant::now();
+    let mut clients = Vec::with_capacity(options.clients);
+    for _ in 0..options.clients {
+        let (options, remaining, test) = (
+            Arc::clone(options),
+            Arc::clone(&remaining),
+            test.to_string(),
+        );
+        clients.push(tokio::spawn(async move {
+            run_client(&options, &test, &remaining).await
+        }));
+    }
+    let mut latencies = Vec::with_capacity(options.requests);
+    for client in clients {
+        latencies.extend(client.await??);
+    }
+    report(test, started.elapsed(), latencies);
+    Ok(())
+}
+
+/// Sends batches of `pipeline` requests until the test's budget is used up,
+/// returning the latency of every request.
+async fn run_client(
+    options: &Options,
+    test: &str,
+    remaining: &AtomicUsize,
+) -> anyhow::Result<Vec<Duration>> {
+    let addr = format!("{}:{}", options.host, options.port);
+    let mut stream = TcpStream::connect(&addr)
+        .await
+        .with_context(|| format!("could not connect to {addr}"))?;
+    stream.set_nodelay(true)?;
+    let mut reader = RespReader::default();
+    let mut rng = Rng::new();
+    let value = "x".repeat(options.value_size);
+    let mut latencies = Vec::new();
+    let mut batch = Vec::new();
+    loop {
+        let claimed = remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left - options.pipeline.min(left))
+            })
+            .map_or(0, |left| options.pipeline.min(left));
+        if claimed == 0 {
+            return Ok(latencies);
+        }
+        batch.clear();
+        for _ in 0..claimed {
+            let key = match options.keyspace {
+                Some(keyspace) => format!("key:{:012}", rng.next() % keyspace.max(1)),
+                None => "key:__rand_int__".to_string(),
+            };
+            match test {
+                "ping" => encode(&mut batch, &["PING"]),
+                "echo" => encode(&mut batch, &["ECHO", &value]),
+                "set" => encode(&mut batch, &["SET", &key, &value]),
+                _ => encode(&mut batch, &["GET", &key]),
+            }
+        }
+        let sent = Instant::now();
+        stream.write_all(&batch).await?;
+        for _ in 0..claimed {
+            let reply = reader
+                .read_frame(&mut stream, &Limits::default())
+                .await?
+                .context("server closed the connection")?;
+            if let DataType::SimpleError(e) = reply {
+                anyhow::bail!("{test}: {e}");
+            }
+        }
+        // like redis-benchmark, every request in a batch counts the batch's
+        // round trip
+        latencies.extend(std::iter::repeat(sent.elapsed()).take(claimed));
+    }
+}
+
+fn encode(out: &mut Vec<u8>, words: &[&str]) {
+    out.extend_from_slice(format!("*{}\r\n", words.len()).as_bytes());
+    for word in words {
+        out.extend_from_slice(format!("${}\r\n", word.len()).as_bytes());
+        out.extend_from_slice(word.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+fn report(test: &str, elapsed: Duration, mut latencies: Vec<Duration>) {
+    latencies.sort_unstable();
+    let percentile = |p: f64| {
+        let index = ((latencies.len() as f64 * p).ceil() as usize).saturating_sub(1);
+        latencies
+            .get(index)
+            .map_or(0.0, |l| l.as_secs_f64() * 1000.0)
+    };
+    println!("====== {} ======", test.to_ascii_uppercase());
+    println!(
+        "  {} requests completed in {:.2} seconds",
+        latencies.len(),
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "  throughput summary: {:.2} requests per second",
+        latencies.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+    println!(
+        "  latency summary (msec): p50={:.3} p95={:.3} p99={:.3} max={:.3}",
+        percentile(0.50),
+        percentile(0.95),
+        percentile(0.99),
+        percentile(1.0)
+    );
+}
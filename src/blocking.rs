@@ -0,0 +1,168 @@
+//! Connections blocked until a key they wait on is written, for BLPOP and
+//! the other blocking commands.
+//!
+//! A waiter registers under its keys before it first looks at them, so no
+//! write can slip in between the look and the wait unnoticed. Waiters on a
+//! key queue up in the order they came, and a write wakes only the first,
+//! which takes it off the queue and holds the key's wake. If that waiter
+//! finds nothing after all it goes back to the front. If it is done without
+//! anything written to the key since, the wake is passed on to the next in
+//! line, who may find something left over.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::Bytes;
+use thiserror::Error;
+use tokio::sync::Notify;
+
+/// The client closed the connection while its command was blocked, which
+/// then has no one to reply to.
+#[derive(Debug, Error)]
+#[error("client hung up while blocked")]
+pub struct HungUp;
+
+#[derive(Debug, Default)]
+pub struct Waiters {
+    registry: Mutex<Registry>,
+    /// How many waiters there are, so writes can skip the lock when none.
+    count: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    next_id: u64,
+    queues: HashMap<Bytes, VecDeque<u64>>,
+    waiting: HashMap<u64, Waiting>,
+    /// Who was last woken for each key and hasn't acted on it yet.
+    woken: HashMap<Bytes, u64>,
+}
+
+#[derive(Debug)]
+struct Waiting {
+    notify: Arc<Notify>,
+    /// Keys this waiter has been taken off the queue of.
+    dequeued: Vec<Bytes>,
+}
+
+impl Registry {
+    /// Wakes the first waiter in line for `key`, who then holds its wake.
+    fn wake(&mut self, key: &[u8]) {
+        // whoever held it before needn't pass it on, as the key has been
+        // written since
+        self.woken.remove(key);
+        let Some((key, mut queue)) = self.queues.remove_entry(key) else {
+            return;
+        };
+        let Some(id) = queue.pop_front() else {
+            return;
+        };
+        if !queue.is_empty() {
+            self.queues.insert(key.clone(), queue);
+        }
+        let waiting = self.waiting.get_mut(&id).unwrap();
+        waiting.dequeued.push(key.clone());
+        waiting.notify.notify_one();
+        self.woken.insert(key, id);
+    }
+}
+
+impl Waiters {
+    /// Starts waiting on `keys`, until the returned [`Waiter`] is dropped.
+    pub fn register(&self, keys: &[Bytes]) -> Waiter<'_> {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        let notify = Arc::new(Notify::new());
+        let mut registry = self.registry.lock().unwrap();
+        let id = registry.next_id;
+        registry.next_id += 1;
+        for key in &keys {
+            registry
+                .queues
+                .entry(key.clone())
+                .or_default()
+                .push_back(id);
+        }
+        let waiting = Waiting {
+            notify: Arc::clone(&notify),
+            dequeued: Vec::new(),
+        };
+        registry.waiting.insert(id, waiting);
+        self.count.fetch_add(1, Ordering::SeqCst);
+        Waiter {
+            waiters: self,
+            id,
+            keys,
+            notify,
+        }
+    }
+
+    /// Wakes whoever has waited longest on `key`, which has just been
+    /// written.
+    pub fn wake(&self, key: &[u8]) {
+        // a waiter registers before it looks at the key, and the write was
+        // made after, so it would be counted by now
+        if self.count.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        self.registry.lock().unwrap().wake(key);
+    }
+}
+
+/// A registration made by [`Waiters::register`].
+pub struct Waiter<'w> {
+    waiters: &'w Waiters,
+    id: u64,
+    keys: Vec<Bytes>,
+    notify: Arc<Notify>,
+}
+
+impl Waiter<'_> {
+    /// Resolves once one of the keys has been written since the last call,
+    /// or since registering. Keys this waiter was woken by last time, but
+    /// found nothing on, put it back at the front of their queues.
+    pub async fn woken(&self) {
+        {
+            let mut registry = self.waiters.registry.lock().unwrap();
+            let dequeued =
+                std::mem::take(&mut registry.waiting.get_mut(&self.id).unwrap().dequeued);
+            for key in dequeued {
+                if registry.woken.get(&key) == Some(&self.id) {
+                    registry.woken.remove(&key);
+                }
+                registry.queues.entry(key).or_default().push_front(self.id);
+            }
+        }
+        self.notify.notified().await
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        let mut registry = self.waiters.registry.lock().unwrap();
+        registry.waiting.remove(&self.id);
+        for key in &self.keys {
+            let Some(queue) = registry.queues.get_mut(key) else {
+                continue;
+            };
+            queue.retain(|&id| id != self.id);
+            if queue.is_empty() {
+                registry.queues.remove(key);
+            }
+        }
+        // whatever this waiter left behind is for the next in line
+        for key in &self.keys {
+            if registry.woken.get(key) == Some(&self.id) {
+                registry.woken.remove(key);
+                registry.wake(key);
+            }
+        }
+        self.waiters.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,201 @@
+//! A small redis-cli work-alike, run as `cli [options] [command]` by the
+//! example of that name.
+
+use std::{
+    io::{IsTerminal, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{
+    config,
+    protocol::{self, DataType, Limits, RespReader},
+};
+
+const USAGE: &str = "usage: cli [-h host] [-p port] [-x] [--pipe] [command [arg ...]]";
+
+struct Options {
+    host: String,
+    port: u16,
+    /// Append stdin as the last argument of the command.
+    stdin_arg: bool,
+    /// Stream raw RESP from stdin (mass insertion).
+    pipe: bool,
+    command: Vec<String>,
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> anyhow::Result<Options> {
+    let mut options = Options {
+        host: "127.0.0.1".to_string(),
+        port: 6379,
+        stdin_arg: false,
+        pipe: false,
+        command: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" => options.host = args.next().context(USAGE)?,
+            "-p" => options.port = args.next().context(USAGE)?.parse().context(USAGE)?,
+            "-x" => options.stdin_arg = true,
+            "--pipe" => options.pipe = true,
+            _ => {
+                options.command.push(arg);
+                options.command.extend(args.by_ref());
+            }
+        }
+    }
+    Ok(options)
+}
+
+pub async fn run(args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let options = parse_options(args)?;
+    let addr = format!("{}:{}", options.host, options.port);
+    let mut stream = TcpStream::connect(&addr)
+        .await
+        .with_context(|| format!("could not connect to {addr}"))?;
+
+    if options.pipe {
+        return pipe(&mut stream).await;
+    }
+    let mut reader = RespReader::default();
+    let mut command: Vec<Bytes> = options.command.into_iter().map(Bytes::from).collect();
+    if options.stdin_arg {
+        // sent as is, so binary files can be stored with -x
+        let mut payload = Vec::new();
+        io::stdin().read_to_end(&mut payload).await?;
+        command.push(payload.into());
+    }
+    if !command.is_empty() {
+        let reply = roundtrip(&mut reader, &mut stream, command).await?;
+        println!("{}", pretty(&reply, 0));
+        return Ok(());
+    }
+
+    let interactive = std::io::stdin().is_terminal();
+    let mut lines = BufReader::new(io::stdin()).lines();
+    loop {
+        if interactive {
+            print!("{addr}> ");
+            std::io::stdout().flush()?;
+        }
+        let Some(line) = lines.next_line().await? else {
+            return Ok(());
+        };
+        let words = config::split_args(&line)?;
+        if words.is_empty() {
+            continue;
+        }
+        if words[0].eq_ignore_ascii_case("quit") || words[0].eq_ignore_ascii_case("exit") {
+            return Ok(());
+        }
+        let words = words.into_iter().map(Bytes::from).collect();
+        let reply = roundtrip(&mut reader, &mut stream, words).await?;
+        println!("{}", pretty(&reply, 0));
+    }
+}
+
+async fn roundtrip(
+    reader: &mut RespReader,
+    stream: &mut TcpStream,
+    words: Vec<Bytes>,
+) -> anyhow::Result<DataType<'static>> {
+    let request: Vec<_> = words.into_iter().map(DataType::BulkString).collect();
+    protocol::send_array(stream, &request).await?;
+    reader
+        .read_frame(stream, &Limits::default())
+        .await?
+        .context("server closed the connection")
+}
+
+/// Sends stdin verbatim, then waits for an ECHO of a unique marker to know
+/// that every reply has arrived.
+async fn pipe(stream: &mut TcpStream) -> anyhow::Result<()> {
+    let mut payload = Vec::new();
+    io::stdin().read_to_end(&mut payload).await?;
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let marker = format!("{:x}{:x}", nanos, std::process::id());
+    stream.write_all(&payload).await?;
+    protocol::send_array(
+        stream,
+        &[
+            DataType::BulkString(Bytes::from_static(b"ECHO")),
+            DataType::BulkString(marker.clone().into()),
+        ],
+    )
+    .await?;
+    eprintln!("All data transferred. Waiting for the last reply...");
+
+    let (mut replies, mut errors) = (0, 0);
+    let mut reader = RespReader::default();
+    loop {
+        let reply = reader
+            .read_frame(stream, &Limits::default())
+            .await?
+            .context("server closed the connection")?;
+        match reply {
+            DataType::BulkString(s) if s == marker => break,
+            DataType::SimpleError(e) => {
+                eprintln!("{e}");
+                errors += 1;
+            }
+            _ => {}
+        }
+        replies += 1;
+    }
+    eprintln!("Last reply received from server.");
+    eprintln!("errors: {errors}, replies: {replies}");
+    Ok(())
+}
+
+/// Formats a reply the way redis-cli does in its interactive mode.
+fn pretty(reply: &DataType, indent: usize) -> String {
+    match reply {
+        DataType::SimpleString(s) => s.to_string(),
+        DataType::SimpleError(e) => format!("(error) {e}"),
+        DataType::Integer(i) => format!("(integer) {i}"),
+        DataType::BulkString(s) => format!("\"{}\"", s.escape_ascii()),
+        DataType::Null => "(nil)".to_string(),
+        DataType::Double(d) => format!("(double) {d}"),
+        DataType::Boolean(b) => format!("({b})"),
+        DataType::BigNumber(n) => format!("(big number) {n}"),
+        DataType::Array(elements) | DataType::Push(elements) => {
+            pretty_list(elements.iter().map(|e| (e, None)), ')', indent)
+        }
+        DataType::Set(elements) => pretty_list(elements.iter().map(|e| (e, None)), '~', indent),
+        DataType::Map(pairs) => pretty_list(pairs.iter().map(|(k, v)| (k, Some(v))), '#', indent),
+    }
+}
+
+/// Numbers the elements of an aggregate, one per line; map values follow
+/// their keys after `=>`.
+fn pretty_list<'a, 'b: 'a>(
+    elements: impl ExactSizeIterator<Item = (&'a DataType<'b>, Option<&'a DataType<'b>>)>,
+    marker: char,
+    indent: usize,
+) -> String {
+    if elements.len() == 0 {
+        return "(empty array)".to_string();
+    }
+    let width = elements.len().to_string().len();
+    elements
+        .enumerate()
+        .map(|(i, (element, value))| {
+            let prefix = format!("{:>width$}{marker} ", i + 1);
+            let mut body = pretty(element, indent + prefix.len());
+            if let Some(value) = value {
+                body.push_str(" => ");
+                let value = pretty(value, indent + prefix.len() + body.len());
+                body.push_str(&value);
+            }
+            let pad = if i == 0 { 0 } else { indent };
+            format!("{:pad$}{prefix}{body}", "")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
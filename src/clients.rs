@@ -0,0 +1,102 @@
+//! Per-connection memory accounting and `maxmemory-clients` eviction.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::{
+    sync::Notify,
+    time::{self, Duration},
+};
+
+use crate::config::Config;
+
+/// How often the aggregate is checked against `maxmemory-clients`.
+const EVICTION_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What one connection holds on to.
+#[derive(Debug, Default)]
+pub struct ClientMemory {
+    /// Capacity of the query buffer, kept current by its `RespReader`.
+    pub query_buffer: Arc<AtomicUsize>,
+    /// Capacity of the reply buffer, kept current by its writers.
+    pub reply_buffer: Arc<AtomicUsize>,
+    /// Replicas are never evicted, whatever their buffers hold.
+    pub no_evict: AtomicBool,
+    /// Woken when the connection has been picked for eviction.
+    pub evicted: Notify,
+}
+
+impl ClientMemory {
+    pub fn total(&self) -> usize {
+        self.query_buffer.load(Ordering::Relaxed) + self.reply_buffer.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Clients {
+    connections: Mutex<HashMap<SocketAddr, Arc<ClientMemory>>>,
+}
+
+impl Clients {
+    pub fn register(&self, addr: SocketAddr) -> Arc<ClientMemory> {
+        let memory = Arc::new(ClientMemory::default());
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(addr, Arc::clone(&memory));
+        memory
+    }
+
+    pub fn unregister(&self, addr: &SocketAddr) {
+        self.connections.lock().unwrap().remove(addr);
+    }
+
+    /// Evicts the most memory-hungry clients until the rest fit in `limit`
+    /// bytes, returning how many were evicted.
+    pub fn evict_over(&self, limit: usize) -> usize {
+        let mut connections = self.connections.lock().unwrap();
+        let mut total: usize = connections.values().map(|c| c.total()).sum();
+        let mut candidates: Vec<_> = connections
+            .iter()
+            .filter(|(_, c)| !c.no_evict.load(Ordering::Relaxed))
+            .map(|(addr, c)| (c.total(), *addr))
+            .collect();
+        candidates.sort_unstable_by(|a, b| b.cmp(a));
+        let mut evicted = 0;
+        for (memory, addr) in candidates {
+            if total <= limit {
+                break;
+            }
+            if let Some(client) = connections.remove(&addr) {
+                eprintln!("evicting client {addr} using {memory} bytes (maxmemory-clients)");
+                client.evicted.notify_one();
+                total -= memory;
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+}
+
+/// Keeps client memory under `maxmemory-clients` for the lifetime of the
+/// server.
+pub async fn run_evictor(config: Arc<Config>) {
+    let mut interval = time::interval(EVICTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        let limit = config.tunables().maxmemory_clients;
+        if limit > 0 {
+            let evicted = config.clients.evict_over(limit);
+            config
+                .stats
+                .evicted_clients
+                .fetch_add(evicted as u64, Ordering::Relaxed);
+        }
+    }
+}
@@ -1,20 +1,1338 @@
-use std::{ops::Deref, sync::Arc};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Context;
 use bytes::Bytes;
 use tokio::{
-    io::AsyncWriteExt,
-    net::TcpStream,
-    time::{Duration, Instant},
+    io::{self, AsyncRead, AsyncWrite, AsyncWriteExt},
+    time::{self, Duration, Instant},
 };
 
 use crate::{
-    protocol::{self, DataType},
-    Config, Store, StoreValue,
+    blocking::HungUp,
+    clients::ClientMemory,
+    config::Config,
+    glob, json,
+    protocol::{self, DataType, RespReader},
+    random::Rng,
+    replication::{random_hex, ReplicaHandshake, ReplicaState},
+    store::{Store, StoreValue},
+    value::{Value, WrongType},
 };
 
+mod hash;
+mod list;
+mod set;
+mod stream;
+mod zset;
+
+use hash::Part;
+use list::End;
+use set::Algebra;
+use zset::RangeBy;
+
+/// What a command does, for the checks made before it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags {
+    /// Modifies the keyspace: refused by read-only servers and audited.
+    pub write: bool,
+    /// Reads the keyspace without modifying it.
+    pub readonly: bool,
+    /// Server administration rather than data access.
+    pub admin: bool,
+}
+
+impl Flags {
+    const NONE: Flags = Flags {
+        write: false,
+        readonly: false,
+        admin: false,
+    };
+    const WRITE: Flags = Flags {
+        write: true,
+        ..Flags::NONE
+    };
+    const READONLY: Flags = Flags {
+        readonly: true,
+        ..Flags::NONE
+    };
+    const ADMIN: Flags = Flags {
+        admin: true,
+        ..Flags::NONE
+    };
+
+    /// Whether the command touches the keyspace, and so is refused while a
+    /// replica that mustn't serve stale data is out of sync.
+    pub fn accesses_data(&self) -> bool {
+        self.write || self.readonly
+    }
+}
+
+/// Where a command's keys are, like redis' `(first, last, step)`: positions
+/// count the command name as 0, a negative `last` counts from the end, and a
+/// `first` of 0 means the command takes no keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeySpec {
+    pub first: usize,
+    pub last: isize,
+    pub step: usize,
+    /// A keyword searched for from `first` on, in place of a fixed range.
+    /// The keys are then the first half of the arguments after it, the
+    /// second half pairing something with each, as XREAD's IDs do.
+    pub keyword: Option<&'static str>,
+}
+
+impl KeySpec {
+    const NONE: KeySpec = KeySpec {
+        first: 0,
+        last: 0,
+        step: 0,
+        keyword: None,
+    };
+    const FIRST: KeySpec = KeySpec {
+        first: 1,
+        last: 1,
+        step: 1,
+        keyword: None,
+    };
+    const FIRST_TWO: KeySpec = KeySpec {
+        first: 1,
+        last: 2,
+        step: 1,
+        keyword: None,
+    };
+    /// Every argument is a key.
+    const ALL: KeySpec = KeySpec {
+        first: 1,
+        last: -1,
+        step: 1,
+        keyword: None,
+    };
+    /// Every argument but a trailing timeout is a key.
+    const ALL_BUT_LAST: KeySpec = KeySpec {
+        first: 1,
+        last: -2,
+        step: 1,
+        keyword: None,
+    };
+    /// The keys come after STREAMS, followed by an ID for each.
+    const STREAMS: KeySpec = KeySpec {
+        first: 1,
+        last: -1,
+        step: 1,
+        keyword: Some("STREAMS"),
+    };
+}
+
+/// The arguments after the command name.
+pub type Args = std::vec::IntoIter<DataType<'static>>;
+
+pub type CommandFuture<'c> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'c>>;
+
+/// The connection a command runs on, and the server state it may act on.
+pub struct Invocation<'i> {
+    pub stream: &'i mut (dyn AsyncWrite + Unpin + Send),
+    /// The connection's incoming side, which a blocked command keeps
+    /// reading to notice the client hang up, into `reader`.
+    pub input: &'i mut (dyn AsyncRead + Unpin + Send),
+    pub reader: &'i mut RespReader,
+    /// Unique per connection, as reported by `HELLO`.
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub store: &'i Store,
+    pub config: &'i Arc<Config>,
+    pub handshake: &'i mut ReplicaHandshake,
+    pub memory: &'i ClientMemory,
+}
+
+pub trait Command: Sync {
+    /// Canonical upper-case name.
+    fn name(&self) -> &'static str;
+
+    /// Number of arguments including the command name; negative means at
+    /// least that many, as in `COMMAND INFO`.
+    fn arity(&self) -> i32;
+
+    fn flags(&self) -> Flags;
+
+    fn keys(&self) -> KeySpec;
+
+    /// Runs the command, which writes its own reply.
+    fn execute<'c>(&self, cx: &'c mut Invocation<'_>, args: Args) -> CommandFuture<'c>;
+
+    fn accepts(&self, argc: usize) -> bool {
+        let arity = self.arity();
+        match usize::try_from(arity) {
+            Ok(exact) => argc == exact,
+            Err(_) => argc >= arity.unsigned_abs() as usize,
+        }
+    }
+
+    /// The keys among `args` (which exclude the command name).
+    fn key_args<'a>(&self, args: &'a [DataType]) -> Vec<&'a [u8]> {
+        let spec = self.keys();
+        if spec.first == 0 {
+            return Vec::new();
+        }
+        let bulk = |arg: &'a DataType| match arg {
+            DataType::BulkString(arg) => Some(arg.as_ref()),
+            _ => None,
+        };
+        if let Some(keyword) = spec.keyword {
+            let found = args.iter().skip(spec.first - 1).position(|arg| {
+                bulk(arg).is_some_and(|arg| arg.eq_ignore_ascii_case(keyword.as_bytes()))
+            });
+            let Some(found) = found else {
+                return Vec::new();
+            };
+            let after = &args[spec.first + found..];
+            return after[..after.len() / 2].iter().filter_map(bulk).collect();
+        }
+        let argc = args.len() + 1;
+        let last = match usize::try_from(spec.last) {
+            Ok(last) => last,
+            Err(_) => argc.saturating_sub(spec.last.unsigned_abs()),
+        };
+        (spec.first..=last.min(argc - 1))
+            .step_by(spec.step.max(1))
+            .filter_map(|i| bulk(&args[i - 1]))
+            .collect()
+    }
+}
+
+/// A command implemented in this module.
+struct Builtin {
+    name: &'static str,
+    arity: i32,
+    flags: Flags,
+    keys: KeySpec,
+    handler: for<'c, 'i> fn(&'c mut Invocation<'i>, Args) -> CommandFuture<'c>,
+}
+
+impl Command for Builtin {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn arity(&self) -> i32 {
+        self.arity
+    }
+
+    fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    fn keys(&self) -> KeySpec {
+        self.keys
+    }
+
+    fn execute<'c>(&self, cx: &'c mut Invocation<'_>, args: Args) -> CommandFuture<'c> {
+        (self.handler)(cx, args)
+    }
+}
+
+/// Every command the server knows. Adding one here is all it takes for it
+/// to be dispatched, renamed, traced, counted and audited.
+static COMMANDS: [&dyn Command; 116] = [
+    &Builtin {
+        name: "ECHO",
+        arity: 2,
+        flags: Flags::NONE,
+        keys: KeySpec::NONE,
+        handler: |cx, args| Box::pin(invoke_echo(&mut cx.stream, args)),
+    },
+    &Builtin {
+        name: "PING",
+        arity: -1,
+        flags: Flags::NONE,
+        keys: KeySpec::NONE,
+        handler: |cx, _| Box::pin(protocol::send_simple_string(&mut cx.stream, "PONG")),
+    },
+    &Builtin {
+        name: "SET",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_set(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "GET",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_get(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "APPEND",
+        arity: 3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_append(&mut cx.stream, args, cx.store, cx.config)),
+    },
+    &Builtin {
+        name: "STRLEN",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_strlen(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "GETRANGE",
+        arity: 4,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_getrange(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SETRANGE",
+        arity: 4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_setrange(&mut cx.stream, args, cx.store, cx.config)),
+    },
+    &Builtin {
+        name: "INCR",
+        arity: 2,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_incrby(&mut cx.stream, args, cx.store, 1, false)),
+    },
+    &Builtin {
+        name: "DECR",
+        arity: 2,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_incrby(&mut cx.stream, args, cx.store, -1, false)),
+    },
+    &Builtin {
+        name: "INCRBY",
+        arity: 3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_incrby(&mut cx.stream, args, cx.store, 1, true)),
+    },
+    &Builtin {
+        name: "DECRBY",
+        arity: 3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_incrby(&mut cx.stream, args, cx.store, -1, true)),
+    },
+    &Builtin {
+        name: "INCRBYFLOAT",
+        arity: 3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_incrbyfloat(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "EXISTS",
+        arity: -2,
+        flags: Flags::READONLY,
+        keys: KeySpec::ALL,
+        handler: |cx, args| Box::pin(invoke_exists(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "TYPE",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_type(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "RENAME",
+        arity: 3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST_TWO,
+        handler: |cx, args| Box::pin(invoke_rename(&mut cx.stream, args, cx.store, false)),
+    },
+    &Builtin {
+        name: "RENAMENX",
+        arity: 3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST_TWO,
+        handler: |cx, args| Box::pin(invoke_rename(&mut cx.stream, args, cx.store, true)),
+    },
+    &Builtin {
+        name: "COPY",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST_TWO,
+        handler: |cx, args| Box::pin(invoke_copy(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "DBSIZE",
+        arity: 1,
+        flags: Flags::READONLY,
+        keys: KeySpec::NONE,
+        handler: |cx, _| Box::pin(invoke_dbsize(&mut cx.stream, cx.store)),
+    },
+    // with a single database, the two are the same
+    &Builtin {
+        name: "FLUSHDB",
+        arity: -1,
+        flags: Flags::WRITE,
+        keys: KeySpec::NONE,
+        handler: |cx, args| Box::pin(invoke_flush(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "FLUSHALL",
+        arity: -1,
+        flags: Flags::WRITE,
+        keys: KeySpec::NONE,
+        handler: |cx, args| Box::pin(invoke_flush(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "RANDOMKEY",
+        arity: 1,
+        flags: Flags::READONLY,
+        keys: KeySpec::NONE,
+        handler: |cx, _| Box::pin(invoke_randomkey(&mut cx.stream, cx.store)),
+    },
+    &Builtin {
+        name: "OBJECT",
+        arity: -2,
+        flags: Flags::READONLY,
+        keys: KeySpec {
+            first: 2,
+            last: 2,
+            step: 1,
+            keyword: None,
+        },
+        handler: |cx, args| Box::pin(invoke_object(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "KEYS",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::NONE,
+        handler: |cx, args| Box::pin(invoke_keys(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SCAN",
+        arity: -2,
+        flags: Flags::READONLY,
+        keys: KeySpec::NONE,
+        handler: |cx, args| Box::pin(invoke_scan(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "DEL",
+        arity: -2,
+        flags: Flags::WRITE,
+        keys: KeySpec::ALL,
+        handler: |cx, args| Box::pin(invoke_del(&mut cx.stream, args, cx.store)),
+    },
+    // values are freed inline either way, as none is big enough to be
+    // worth handing to a background thread
+    &Builtin {
+        name: "UNLINK",
+        arity: -2,
+        flags: Flags::WRITE,
+        keys: KeySpec::ALL,
+        handler: |cx, args| Box::pin(invoke_del(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "TTL",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_ttl(&mut cx.stream, args, cx.store, false)),
+    },
+    &Builtin {
+        name: "PTTL",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_ttl(&mut cx.stream, args, cx.store, true)),
+    },
+    &Builtin {
+        name: "EXPIRE",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(invoke_expire(
+                &mut cx.stream,
+                args,
+                cx.store,
+                "expire",
+                b"EX",
+            ))
+        },
+    },
+    &Builtin {
+        name: "PEXPIRE",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(invoke_expire(
+                &mut cx.stream,
+                args,
+                cx.store,
+                "pexpire",
+                b"PX",
+            ))
+        },
+    },
+    &Builtin {
+        name: "EXPIREAT",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(invoke_expire(
+                &mut cx.stream,
+                args,
+                cx.store,
+                "expireat",
+                b"EXAT",
+            ))
+        },
+    },
+    &Builtin {
+        name: "PEXPIREAT",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(invoke_expire(
+                &mut cx.stream,
+                args,
+                cx.store,
+                "pexpireat",
+                b"PXAT",
+            ))
+        },
+    },
+    &Builtin {
+        name: "PERSIST",
+        arity: 2,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(invoke_persist(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "LPUSH",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(list::invoke_push(
+                &mut cx.stream,
+                args,
+                cx.store,
+                End::Left,
+                false,
+            ))
+        },
+    },
+    &Builtin {
+        name: "RPUSH",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(list::invoke_push(
+                &mut cx.stream,
+                args,
+                cx.store,
+                End::Right,
+                false,
+            ))
+        },
+    },
+    &Builtin {
+        name: "LPUSHX",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(list::invoke_push(
+                &mut cx.stream,
+                args,
+                cx.store,
+                End::Left,
+                true,
+            ))
+        },
+    },
+    &Builtin {
+        name: "RPUSHX",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(list::invoke_push(
+                &mut cx.stream,
+                args,
+                cx.store,
+                End::Right,
+                true,
+            ))
+        },
+    },
+    &Builtin {
+        name: "LPOP",
+        arity: -2,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(list::invoke_pop(&mut cx.stream, args, cx.store, End::Left)),
+    },
+    &Builtin {
+        name: "RPOP",
+        arity: -2,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(list::invoke_pop(&mut cx.stream, args, cx.store, End::Right)),
+    },
+    &Builtin {
+        name: "BLPOP",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::ALL_BUT_LAST,
+        handler: |cx, args| {
+            Box::pin(list::invoke_blocking_pop(
+                &mut cx.stream,
+                cx.reader.until_closed(&mut cx.input),
+                args,
+                cx.store,
+                End::Left,
+            ))
+        },
+    },
+    &Builtin {
+        name: "BRPOP",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::ALL_BUT_LAST,
+        handler: |cx, args| {
+            Box::pin(list::invoke_blocking_pop(
+                &mut cx.stream,
+                cx.reader.until_closed(&mut cx.input),
+                args,
+                cx.store,
+                End::Right,
+            ))
+        },
+    },
+    &Builtin {
+        name: "LLEN",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(list::invoke_llen(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "LRANGE",
+        arity: 4,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(list::invoke_lrange(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "LINDEX",
+        arity: 3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(list::invoke_lindex(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "LPOS",
+        arity: -3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(list::invoke_lpos(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "LSET",
+        arity: 4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(list::invoke_lset(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "LINSERT",
+        arity: 5,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(list::invoke_linsert(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "LREM",
+        arity: 4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(list::invoke_lrem(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "LTRIM",
+        arity: 4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(list::invoke_ltrim(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "HSET",
+        arity: -4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(hash::invoke_hset(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "HSETNX",
+        arity: 4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(hash::invoke_hsetnx(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "HINCRBY",
+        arity: 4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(hash::invoke_hincrby(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "HINCRBYFLOAT",
+        arity: 4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(hash::invoke_hincrbyfloat(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "HGET",
+        arity: 3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(hash::invoke_hget(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "HMGET",
+        arity: -3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(hash::invoke_hmget(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "HDEL",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(hash::invoke_hdel(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "HEXISTS",
+        arity: 3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(hash::invoke_hexists(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "HLEN",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(hash::invoke_hlen(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "HGETALL",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(hash::invoke_hgetall(
+                &mut cx.stream,
+                args,
+                cx.store,
+                Part::Both,
+            ))
+        },
+    },
+    &Builtin {
+        name: "HKEYS",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(hash::invoke_hgetall(
+                &mut cx.stream,
+                args,
+                cx.store,
+                Part::Fields,
+            ))
+        },
+    },
+    &Builtin {
+        name: "HVALS",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(hash::invoke_hgetall(
+                &mut cx.stream,
+                args,
+                cx.store,
+                Part::Values,
+            ))
+        },
+    },
+    &Builtin {
+        name: "HRANDFIELD",
+        arity: -2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(hash::invoke_hrandfield(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "HSCAN",
+        arity: -3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(hash::invoke_hscan(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SADD",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(set::invoke_sadd(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SREM",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(set::invoke_srem(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SMEMBERS",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(set::invoke_smembers(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SISMEMBER",
+        arity: 3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(set::invoke_sismember(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SMISMEMBER",
+        arity: -3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(set::invoke_smismember(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SCARD",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(set::invoke_scard(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SPOP",
+        arity: -2,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(set::invoke_spop(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SRANDMEMBER",
+        arity: -2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(set::invoke_srandmember(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SMOVE",
+        arity: 4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST_TWO,
+        handler: |cx, args| Box::pin(set::invoke_smove(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SSCAN",
+        arity: -3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(set::invoke_sscan(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "SINTER",
+        arity: -2,
+        flags: Flags::READONLY,
+        keys: KeySpec::ALL,
+        handler: |cx, args| {
+            Box::pin(set::invoke_algebra(
+                &mut cx.stream,
+                args,
+                cx.store,
+                Algebra::Inter,
+            ))
+        },
+    },
+    &Builtin {
+        name: "SUNION",
+        arity: -2,
+        flags: Flags::READONLY,
+        keys: KeySpec::ALL,
+        handler: |cx, args| {
+            Box::pin(set::invoke_algebra(
+                &mut cx.stream,
+                args,
+                cx.store,
+                Algebra::Union,
+            ))
+        },
+    },
+    &Builtin {
+        name: "SDIFF",
+        arity: -2,
+        flags: Flags::READONLY,
+        keys: KeySpec::ALL,
+        handler: |cx, args| {
+            Box::pin(set::invoke_algebra(
+                &mut cx.stream,
+                args,
+                cx.store,
+                Algebra::Diff,
+            ))
+        },
+    },
+    &Builtin {
+        name: "SINTERSTORE",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::ALL,
+        handler: |cx, args| {
+            Box::pin(set::invoke_algebra_store(
+                &mut cx.stream,
+                args,
+                cx.store,
+                Algebra::Inter,
+            ))
+        },
+    },
+    &Builtin {
+        name: "SUNIONSTORE",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::ALL,
+        handler: |cx, args| {
+            Box::pin(set::invoke_algebra_store(
+                &mut cx.stream,
+                args,
+                cx.store,
+                Algebra::Union,
+            ))
+        },
+    },
+    &Builtin {
+        name: "SDIFFSTORE",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::ALL,
+        handler: |cx, args| {
+            Box::pin(set::invoke_algebra_store(
+                &mut cx.stream,
+                args,
+                cx.store,
+                Algebra::Diff,
+            ))
+        },
+    },
+    &Builtin {
+        name: "SINTERCARD",
+        arity: -3,
+        flags: Flags::READONLY,
+        // the keys come after their count, and may be followed by LIMIT,
+        // which is listed along with them
+        keys: KeySpec {
+            first: 2,
+            last: -1,
+            step: 1,
+            keyword: None,
+        },
+        handler: |cx, args| Box::pin(set::invoke_sintercard(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "ZADD",
+        arity: -4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(zset::invoke_zadd(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "ZSCORE",
+        arity: 3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(zset::invoke_zscore(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "ZRANK",
+        arity: -3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(zset::invoke_zrank(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "ZRANGE",
+        arity: -4,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(zset::invoke_zrange(
+                &mut cx.stream,
+                args,
+                cx.store,
+                RangeBy::Rank,
+            ))
+        },
+    },
+    &Builtin {
+        name: "ZRANGEBYSCORE",
+        arity: -4,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(zset::invoke_zrange(
+                &mut cx.stream,
+                args,
+                cx.store,
+                RangeBy::Score,
+            ))
+        },
+    },
+    &Builtin {
+        name: "ZRANGEBYLEX",
+        arity: -4,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(zset::invoke_zrange(
+                &mut cx.stream,
+                args,
+                cx.store,
+                RangeBy::Lex,
+            ))
+        },
+    },
+    &Builtin {
+        name: "ZPOPMIN",
+        arity: -2,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(zset::invoke_zpop(&mut cx.stream, args, cx.store, false)),
+    },
+    &Builtin {
+        name: "ZPOPMAX",
+        arity: -2,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(zset::invoke_zpop(&mut cx.stream, args, cx.store, true)),
+    },
+    &Builtin {
+        name: "BZPOPMIN",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::ALL_BUT_LAST,
+        handler: |cx, args| {
+            Box::pin(zset::invoke_blocking_zpop(
+                &mut cx.stream,
+                cx.reader.until_closed(&mut cx.input),
+                args,
+                cx.store,
+                false,
+            ))
+        },
+    },
+    &Builtin {
+        name: "BZPOPMAX",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::ALL_BUT_LAST,
+        handler: |cx, args| {
+            Box::pin(zset::invoke_blocking_zpop(
+                &mut cx.stream,
+                cx.reader.until_closed(&mut cx.input),
+                args,
+                cx.store,
+                true,
+            ))
+        },
+    },
+    &Builtin {
+        name: "ZCOUNT",
+        arity: 4,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(zset::invoke_zcount(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "ZINCRBY",
+        arity: 4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(zset::invoke_zincrby(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "ZREM",
+        arity: -3,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(zset::invoke_zrem(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "ZREMRANGEBYSCORE",
+        arity: 4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| {
+            Box::pin(zset::invoke_zremrangebyscore(
+                &mut cx.stream,
+                args,
+                cx.store,
+            ))
+        },
+    },
+    &Builtin {
+        name: "ZCARD",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(zset::invoke_zcard(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "XADD",
+        arity: -5,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(stream::invoke_xadd(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "XLEN",
+        arity: 2,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(stream::invoke_xlen(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "XRANGE",
+        arity: -4,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(stream::invoke_xrange(&mut cx.stream, args, cx.store, false)),
+    },
+    &Builtin {
+        name: "XREVRANGE",
+        arity: -4,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(stream::invoke_xrange(&mut cx.stream, args, cx.store, true)),
+    },
+    &Builtin {
+        name: "XREAD",
+        arity: -4,
+        flags: Flags::READONLY,
+        keys: KeySpec::STREAMS,
+        handler: |cx, args| {
+            Box::pin(stream::invoke_xread(
+                &mut cx.stream,
+                cx.reader.until_closed(&mut cx.input),
+                args,
+                cx.store,
+            ))
+        },
+    },
+    &Builtin {
+        name: "XGROUP",
+        arity: -2,
+        flags: Flags::WRITE,
+        keys: KeySpec {
+            first: 2,
+            last: 2,
+            step: 1,
+            keyword: None,
+        },
+        handler: |cx, args| Box::pin(stream::invoke_xgroup(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "XREADGROUP",
+        arity: -7,
+        flags: Flags::WRITE,
+        // GROUP and its two arguments come first, and may well be called
+        // STREAMS
+        keys: KeySpec {
+            first: 4,
+            ..KeySpec::STREAMS
+        },
+        handler: |cx, args| {
+            Box::pin(stream::invoke_xreadgroup(
+                &mut cx.stream,
+                cx.reader.until_closed(&mut cx.input),
+                args,
+                cx.store,
+            ))
+        },
+    },
+    &Builtin {
+        name: "XACK",
+        arity: -4,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(stream::invoke_xack(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "XPENDING",
+        arity: -3,
+        flags: Flags::READONLY,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(stream::invoke_xpending(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "XCLAIM",
+        arity: -6,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(stream::invoke_xclaim(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "XAUTOCLAIM",
+        arity: -6,
+        flags: Flags::WRITE,
+        keys: KeySpec::FIRST,
+        handler: |cx, args| Box::pin(stream::invoke_xautoclaim(&mut cx.stream, args, cx.store)),
+    },
+    &Builtin {
+        name: "INFO",
+        arity: -1,
+        flags: Flags::NONE,
+        keys: KeySpec::NONE,
+        handler: |cx, args| Box::pin(invoke_info(&mut cx.stream, args, cx.config)),
+    },
+    &Builtin {
+        name: "REPLCONF",
+        arity: -1,
+        flags: Flags::ADMIN,
+        keys: KeySpec::NONE,
+        handler: |cx, args| Box::pin(invoke_replconf(&mut cx.stream, args, cx.handshake)),
+    },
+    &Builtin {
+        name: "PSYNC",
+        arity: -3,
+        flags: Flags::ADMIN,
+        keys: KeySpec::NONE,
+        handler: |cx, _| Box::pin(invoke_psync(cx)),
+    },
+    &Builtin {
+        name: "SYNC",
+        arity: 1,
+        flags: Flags::ADMIN,
+        keys: KeySpec::NONE,
+        handler: |cx, _| Box::pin(invoke_sync(cx)),
+    },
+    &Builtin {
+        name: "DEBUG",
+        arity: -2,
+        flags: Flags::ADMIN,
+        keys: KeySpec::NONE,
+        handler: |cx, args| Box::pin(invoke_debug(cx, args)),
+    },
+    &Builtin {
+        name: "CONFIG",
+        arity: -2,
+        flags: Flags::ADMIN,
+        keys: KeySpec::NONE,
+        handler: |cx, args| Box::pin(invoke_config(&mut cx.stream, args, cx.config)),
+    },
+    &Builtin {
+        name: "SHUTDOWN",
+        arity: -1,
+        flags: Flags::ADMIN,
+        keys: KeySpec::NONE,
+        handler: |cx, args| Box::pin(async { invoke_shutdown(args, cx.config) }),
+    },
+    &Builtin {
+        name: "HELLO",
+        arity: -1,
+        flags: Flags::NONE,
+        keys: KeySpec::NONE,
+        handler: |cx, args| Box::pin(invoke_hello(cx, args)),
+    },
+    &Builtin {
+        name: "METRICS",
+        arity: 1,
+        flags: Flags::NONE,
+        keys: KeySpec::NONE,
+        handler: |cx, _| Box::pin(invoke_metrics(&mut cx.stream, cx.store, cx.config)),
+    },
+];
+
+/// The canonical names of all commands.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    COMMANDS.iter().map(|command| command.name())
+}
+
+/// [`COMMANDS`] bucketed by the length of their names, built on first use.
+static BY_LENGTH: OnceLock<Vec<Vec<&dyn Command>>> = OnceLock::new();
+
+/// Finds a command by name, in any case, without allocating: only the
+/// commands with a name of the same length are compared against it.
+pub fn lookup(name: &str) -> Option<&'static dyn Command> {
+    let by_length = BY_LENGTH.get_or_init(|| {
+        let longest = COMMANDS.iter().map(|c| c.name().len()).max().unwrap_or(0);
+        let mut by_length = vec![Vec::new(); longest + 1];
+        for command in COMMANDS {
+            by_length[command.name().len()].push(command);
+        }
+        by_length
+    });
+    by_length
+        .get(name.len())?
+        .iter()
+        .find(|command| command.name().eq_ignore_ascii_case(name))
+        .copied()
+}
+
+/// Parses a numeric argument, naming what it was meant to be on failure.
+fn parse_arg<T: FromStr>(arg: &[u8], what: &str) -> anyhow::Result<T> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .with_context(|| format!("{} is not a valid {what}", arg.escape_ascii()))
+}
+
+/// The remaining arguments, which must all be bulk strings, naming them
+/// `what` if one isn't.
+fn bulk_args<'a>(
+    args: impl Iterator<Item = DataType<'a>>,
+    what: &str,
+) -> anyhow::Result<Vec<Bytes>> {
+    args.map(|arg| match arg {
+        DataType::BulkString(arg) => Ok(arg),
+        _ => anyhow::bail!("{what} must be bulk strings"),
+    })
+    .collect()
+}
+
+/// A textual argument such as a file name, which must be UTF-8.
+fn text_arg(arg: &[u8]) -> anyhow::Result<&str> {
+    std::str::from_utf8(arg).with_context(|| format!("{} is not UTF-8", arg.escape_ascii()))
+}
+
 pub async fn invoke_echo<'a>(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
     mut args: impl Iterator<Item = DataType<'a>>,
 ) -> anyhow::Result<()> {
     let Some(DataType::BulkString(echo_string)) = args.next() else {
@@ -24,7 +1342,7 @@ pub async fn invoke_echo<'a>(
 }
 
 pub async fn invoke_set<'a>(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
     mut args: impl Iterator<Item = DataType<'a>>,
     store: &Store,
 ) -> anyhow::Result<()> {
@@ -32,83 +1350,1093 @@ pub async fn invoke_set<'a>(
     else {
         anyhow::bail!("key and value must be bulk strings");
     };
-    let mut value = StoreValue {
-        value: v.into_owned(),
-        expiry: None,
+    let mut expiry = None;
+    // NX and XX as Some(false) and Some(true): whether the key must exist
+    let mut exists = None;
+    let mut keep_ttl = false;
+    let mut get = false;
+    let mut expiry_given = false;
+    while let Some(DataType::BulkString(arg)) = args.next() {
+        let option = arg.to_ascii_uppercase();
+        match &option[..] {
+            b"NX" | b"XX" => {
+                let wanted = option == b"XX";
+                anyhow::ensure!(exists.unwrap_or(wanted) == wanted, "syntax error");
+                exists = Some(wanted);
+            }
+            b"GET" => get = true,
+            b"KEEPTTL" => {
+                anyhow::ensure!(!expiry_given, "syntax error");
+                keep_ttl = true;
+            }
+            b"EX" | b"PX" | b"EXAT" | b"PXAT" => {
+                anyhow::ensure!(!expiry_given && !keep_ttl, "syntax error");
+                let Some(DataType::BulkString(time)) = args.next() else {
+                    anyhow::bail!("syntax error");
+                };
+                let time = parse_arg(&time, "integer")?;
+                anyhow::ensure!(time > 0, "invalid expire time in 'set' command");
+                expiry_given = true;
+                expiry = Some(expiry_from(&option, time, "set")?);
+            }
+            _ => anyhow::bail!("syntax error"),
+        }
+    }
+    let (applied, old) = store.update(k, |entry| {
+        let old = match entry {
+            Some(old) if get => Some(old.value.as_string()?.clone()),
+            _ => None,
+        };
+        if exists.is_some_and(|wanted| wanted != entry.is_some()) {
+            return Ok((false, old));
+        }
+        if keep_ttl {
+            expiry = entry.as_ref().and_then(|old| old.expiry);
+        }
+        *entry = Some(StoreValue::new(Value::String(v), expiry));
+        Ok::<_, WrongType>((true, old))
+    })?;
+    if get {
+        match old {
+            Some(old) => protocol::send_bulk_string(stream, old).await,
+            None => protocol::send_null(stream).await,
+        }
+    } else if applied {
+        protocol::send_simple_string(stream, "OK").await
+    } else {
+        protocol::send_null(stream).await
+    }
+}
+
+/// When a key given `time` by `option` (EX, PX, EXAT or PXAT, as SET
+/// spells them) expires. Times already in the past give an expiry of now.
+fn expiry_from(option: &[u8], time: i64, command: &str) -> anyhow::Result<Instant> {
+    let invalid = || anyhow::anyhow!("invalid expire time in '{command}' command");
+    let now = Instant::now();
+    let Ok(time) = u64::try_from(time) else {
+        return Ok(now);
     };
-    if let Some(DataType::BulkString(arg)) = args.next() {
-        if arg == "px" {
-            let Some(DataType::BulkString(millis)) = args.next() else {
-                anyhow::bail!("PX without expiry");
-            };
-            let millis = millis
-                .parse()
-                .with_context(|| format!("{millis} is not a valid integer"))?;
-            value.expiry = Some(Instant::now() + Duration::from_millis(millis));
+    let time = match option {
+        b"EX" | b"EXAT" => Duration::from_secs(time),
+        _ => Duration::from_millis(time),
+    };
+    let left = match option {
+        b"EX" | b"PX" => time,
+        _ => time.saturating_sub(SystemTime::now().duration_since(UNIX_EPOCH)?),
+    };
+    now.checked_add(left).ok_or_else(invalid)
+}
+
+/// EXPIRE and friends, with `option` saying how `time` is given as in SET.
+/// Replies 1 if the TTL was set, and 0 if the key doesn't exist or the NX,
+/// XX, GT or LT condition failed.
+pub async fn invoke_expire<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    command: &str,
+    option: &[u8],
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(time))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and time must be bulk strings");
+    };
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for arg in args {
+        let DataType::BulkString(arg) = arg else {
+            anyhow::bail!("options must be bulk strings");
+        };
+        match &arg.to_ascii_uppercase()[..] {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            b"GT" => gt = true,
+            b"LT" => lt = true,
+            _ => anyhow::bail!("Unsupported option {}", arg.escape_ascii()),
         }
     }
-    store.lock().await.insert(k.into_owned(), value);
-    protocol::send_simple_string(stream, "OK").await
+    anyhow::ensure!(
+        !(nx && (xx || gt || lt)),
+        "NX and XX, GT or LT options at the same time are not compatible"
+    );
+    anyhow::ensure!(
+        !(gt && lt),
+        "GT and LT options at the same time are not compatible"
+    );
+    let expiry = expiry_from(option, parse_arg(&time, "integer")?, command)?;
+    let applied = store.update(k, |entry| {
+        let Some(entry) = entry else {
+            return false;
+        };
+        // no TTL counts as an infinite one
+        let allowed = match entry.expiry {
+            None => !(xx || gt),
+            Some(current) => !nx && (!gt || expiry > current) && (!lt || expiry < current),
+        };
+        if allowed {
+            entry.expiry = Some(expiry);
+        }
+        allowed
+    });
+    protocol::send(stream, &DataType::Integer(applied.into())).await
+}
+
+/// Removes the TTL of a key, replying 1 if it had one.
+pub async fn invoke_persist<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let applied = store.update(k, |entry| {
+        entry
+            .as_mut()
+            .and_then(|entry| entry.expiry.take())
+            .is_some()
+    });
+    protocol::send(stream, &DataType::Integer(applied.into())).await
 }
 
 pub async fn invoke_get<'a>(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
     mut args: impl Iterator<Item = DataType<'a>>,
     store: &Store,
 ) -> anyhow::Result<()> {
     let Some(DataType::BulkString(k)) = args.next() else {
         anyhow::bail!("key must be given!");
     };
-    // println!("get '{}': {:?}", k, store.lock().await.get(k));
-    // println!("store atm: {:?}", store);
-    match store.lock().await.get(k.deref()) {
-        Some(v) => match v.expiry {
-            Some(expiry) if expiry <= Instant::now() => {
-                // entry exists but is expired
-                protocol::send_null(stream).await
+    match store.get(&k)? {
+        Some(value) => protocol::send_bulk_string(stream, &value).await,
+        None => protocol::send_null(stream).await,
+    }
+}
+
+/// Counts how many of the given keys exist, repeats included.
+pub async fn invoke_exists<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let mut count = 0;
+    for arg in args {
+        let DataType::BulkString(key) = arg else {
+            anyhow::bail!("keys must be bulk strings");
+        };
+        count += i64::from(store.exists(&key));
+    }
+    protocol::send(stream, &DataType::Integer(count)).await
+}
+
+/// Appends to a string, creating it if missing, and replies with the new
+/// length.
+pub async fn invoke_append<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    config: &Arc<Config>,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(suffix))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and value must be bulk strings");
+    };
+    let max_len = config.tunables().limits.max_bulk_len;
+    let len = store.update(k, |entry| {
+        let mut value = string_of(entry)?.to_vec();
+        check_string_len(value.len() + suffix.len(), max_len)?;
+        value.extend_from_slice(&suffix);
+        let len = value.len();
+        put_string(entry, value.into());
+        anyhow::Ok(len)
+    })?;
+    protocol::send(stream, &DataType::Integer(len as i64)).await
+}
+
+pub async fn invoke_strlen<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let len = store.get(&k)?.map_or(0, |value| value.len());
+    protocol::send(stream, &DataType::Integer(len as i64)).await
+}
+
+/// The substring between two inclusive offsets, where negative ones count
+/// from the end. Offsets beyond the string are clamped to it.
+pub async fn invoke_getrange<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(start)),
+        Some(DataType::BulkString(end)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, start and end must be bulk strings");
+    };
+    let (start, end) = (integer_value(&start)?, integer_value(&end)?);
+    let value = store.get(&k)?.unwrap_or_default();
+    let len = value.len() as i64;
+    let clamp = |i: i64| if i < 0 { (len + i).max(0) } else { i };
+    let (start, end) = (clamp(start), clamp(end).min(len - 1));
+    let range = if start > end {
+        Bytes::new()
+    } else {
+        value.slice(start as usize..=end as usize)
+    };
+    protocol::send_bulk_string(stream, range).await
+}
+
+/// Overwrites part of a string, zero-padding it if the offset is past its
+/// end, and replies with the new length.
+pub async fn invoke_setrange<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    config: &Arc<Config>,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(offset)),
+        Some(DataType::BulkString(patch)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, offset and value must be bulk strings");
+    };
+    let offset: usize = integer_value(&offset)?
+        .try_into()
+        .ok()
+        .context("offset is out of range")?;
+    let max_len = config.tunables().limits.max_bulk_len;
+    let len = store.update(k, |entry| {
+        let old = string_of(entry)?;
+        // an empty patch changes nothing, and doesn't create the key
+        if patch.is_empty() {
+            return Ok(old.len());
+        }
+        let mut value = old.to_vec();
+        let end = offset.saturating_add(patch.len());
+        check_string_len(end.max(value.len()), max_len)?;
+        if value.len() < end {
+            value.resize(end, 0);
+        }
+        value[offset..end].copy_from_slice(&patch);
+        put_string(entry, value.into());
+        anyhow::Ok(end.max(old.len()))
+    })?;
+    protocol::send(stream, &DataType::Integer(len as i64)).await
+}
+
+/// The string at `entry`, where a missing key counts as an empty string.
+fn string_of(entry: &Option<StoreValue>) -> Result<Bytes, WrongType> {
+    match entry {
+        Some(entry) => entry.value.as_string().cloned(),
+        None => Ok(Bytes::new()),
+    }
+}
+
+/// Stores a string at `entry`, keeping the TTL of whatever was there.
+fn put_string(entry: &mut Option<StoreValue>, value: Bytes) {
+    match entry {
+        Some(entry) => entry.value = Value::String(value),
+        None => *entry = Some(StoreValue::new(Value::String(value), None)),
+    }
+}
+
+/// Refuses to grow a string past `proto-max-bulk-len`, as it could never
+/// be sent back.
+fn check_string_len(len: usize, max_len: usize) -> anyhow::Result<usize> {
+    anyhow::ensure!(
+        len <= max_len,
+        "string exceeds maximum allowed size (proto-max-bulk-len)"
+    );
+    Ok(len)
+}
+
+/// INCR, DECR, INCRBY and DECRBY: `sign` is -1 for the decrements, and the
+/// amount is 1 unless `by_arg` says it follows the key. A missing key
+/// counts as 0, and the TTL of an existing one is kept.
+pub async fn invoke_incrby<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    sign: i64,
+    by_arg: bool,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let by = match args.next() {
+        Some(DataType::BulkString(by)) if by_arg => integer_value(&by)?,
+        _ => 1,
+    };
+    let by = by.checked_mul(sign).context("decrement would overflow")?;
+    let n = store.update(k, |entry| {
+        let n = match entry {
+            Some(entry) => integer_value(entry.value.as_string()?)?,
+            None => 0,
+        };
+        let n = n
+            .checked_add(by)
+            .context("increment or decrement would overflow")?;
+        put_string(entry, n.to_string().into());
+        anyhow::Ok(n)
+    })?;
+    protocol::send(stream, &DataType::Integer(n)).await
+}
+
+/// Like INCRBY, for floating point amounts.
+pub async fn invoke_incrbyfloat<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(by))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and increment must be bulk strings");
+    };
+    let by = float_value(&by)?;
+    let n = store.update(k, |entry| {
+        let n = match entry {
+            Some(entry) => float_value(entry.value.as_string()?)?,
+            None => 0.0,
+        } + by;
+        anyhow::ensure!(n.is_finite(), "increment would produce NaN or Infinity");
+        put_string(entry, n.to_string().into());
+        Ok(n)
+    })?;
+    protocol::send_bulk_string(stream, n.to_string()).await
+}
+
+/// A stored value or argument that must be an integer, for the counters.
+fn integer_value(value: &[u8]) -> anyhow::Result<i64> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .context("value is not an integer or out of range")
+}
+
+/// The count of SRANDMEMBER or HRANDFIELD, which when negative is negated,
+/// so may not be `i64::MIN`.
+fn pick_count(value: &[u8]) -> anyhow::Result<i64> {
+    let count = integer_value(value)?;
+    anyhow::ensure!(
+        count != i64::MIN,
+        "value is out of range, value must between {} and {}",
+        -i64::MAX,
+        i64::MAX
+    );
+    Ok(count)
+}
+
+/// The most picks made under one lock by [`send_repeated_picks`].
+const PICK_CHUNK: usize = 1024;
+
+/// Sends `total` independent picks from a collection as an array of
+/// `width` frames per pick, made into frames by `frames`. `pick(n)` makes
+/// `n` picks under one lock, or gives `None` for a missing key. Picking a
+/// chunk at a time, a huge count never builds its whole reply nor holds a
+/// lock for long; should the key go part way, the rest are picked from the
+/// last chunk.
+async fn send_repeated_picks<T: Clone>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    total: usize,
+    width: usize,
+    mut pick: impl FnMut(usize) -> Result<Option<Vec<T>>, WrongType>,
+    frames: impl Fn(T) -> Vec<DataType<'static>>,
+) -> anyhow::Result<()> {
+    let picked = pick(total.min(PICK_CHUNK))?.filter(|picked| !picked.is_empty());
+    let Some(mut picked) = picked else {
+        return protocol::send_array(stream, &[]).await;
+    };
+    protocol::send_array_header(stream, total * width).await?;
+    let mut left = total;
+    let mut rng = Rng::new();
+    loop {
+        let elements: Vec<_> = picked.iter().cloned().flat_map(&frames).collect();
+        protocol::send_elements(stream, &elements).await?;
+        left -= picked.len();
+        if left == 0 {
+            return Ok(());
+        }
+        let n = left.min(PICK_CHUNK);
+        picked = match pick(n) {
+            Ok(Some(next)) if !next.is_empty() => next,
+            _ => (0..n)
+                .map(|_| picked[rng.below(picked.len())].clone())
+                .collect(),
+        };
+    }
+}
+
+fn float_value(value: &[u8]) -> anyhow::Result<f64> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value: &f64| value.is_finite())
+        .context("value is not a valid float")
+}
+
+/// A SCAN cursor, as returned by an earlier call.
+fn cursor_value(cursor: &[u8]) -> anyhow::Result<u64> {
+    std::str::from_utf8(cursor)
+        .ok()
+        .and_then(|cursor| cursor.parse().ok())
+        .context("invalid cursor")
+}
+
+/// Resolves the inclusive `start` and `stop` indexes of LRANGE, ZRANGE and
+/// friends against a collection of `len` elements, clamping them to it.
+/// Negative indexes count from the end.
+fn index_range(len: usize, start: i64, stop: i64) -> std::ops::Range<usize> {
+    let len = len as i64;
+    let resolve = |i: i64| if i < 0 { len + i } else { i };
+    let start = resolve(start).max(0);
+    let stop = resolve(stop).min(len - 1);
+    if start > stop {
+        return 0..0;
+    }
+    start as usize..stop as usize + 1
+}
+
+/// When a blocking command given `timeout` seconds gives up, if ever.
+fn deadline_from(timeout: &[u8]) -> anyhow::Result<Option<Instant>> {
+    let timeout: f64 = std::str::from_utf8(timeout)
+        .ok()
+        .and_then(|timeout| timeout.parse().ok())
+        .filter(|timeout: &f64| timeout.is_finite())
+        .context("timeout is not a float or out of range")?;
+    anyhow::ensure!(timeout >= 0.0, "timeout is negative");
+    // 0 waits forever, as does a timeout too long to reach
+    Ok(Duration::try_from_secs_f64(timeout)
+        .ok()
+        .filter(|timeout| !timeout.is_zero())
+        .and_then(|timeout| Instant::now().checked_add(timeout)))
+}
+
+/// Runs `attempt` until it finds something, each time after one of `keys`
+/// has been written, giving up with `None` at `deadline`. Should `closed`
+/// resolve first, the client has hung up and stops waiting with [`HungUp`].
+async fn block_on<T>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    closed: impl Future<Output = io::Result<()>>,
+    store: &Store,
+    keys: &[Bytes],
+    deadline: Option<Instant>,
+    mut attempt: impl FnMut() -> anyhow::Result<Option<T>>,
+) -> anyhow::Result<Option<T>> {
+    let waiter = store.wait_on(keys);
+    tokio::pin!(closed);
+    loop {
+        if let Some(found) = attempt()? {
+            return Ok(Some(found));
+        }
+        // replies to commands pipelined before this one mustn't wait too
+        stream.flush().await?;
+        let woken = async {
+            match deadline {
+                Some(deadline) => time::timeout_at(deadline, waiter.woken()).await.is_ok(),
+                None => {
+                    waiter.woken().await;
+                    true
+                }
             }
-            _ => protocol::send_bulk_string(stream, &v.value).await,
-        },
+        };
+        // dropping the waiter on the way out takes it out of the queues
+        tokio::select! {
+            woken = woken => if !woken {
+                return Ok(None);
+            },
+            closed = &mut closed => {
+                closed?;
+                return Err(HungUp.into());
+            }
+        }
+    }
+}
+
+pub async fn invoke_type<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let type_name = store.peek(&k, |v| v.value.type_name()).unwrap_or("none");
+    protocol::send_simple_string(stream, type_name).await
+}
+
+/// RENAME, or RENAMENX if `nx`, which leaves an existing destination alone
+/// and replies whether it renamed.
+pub async fn invoke_rename<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    nx: bool,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(source)), Some(DataType::BulkString(destination))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("keys must be bulk strings");
+    };
+    let renamed = if source == destination {
+        anyhow::ensure!(store.exists(&source), "no such key");
+        !nx
+    } else {
+        store.update_many(&[source, destination], |entries| {
+            let [source, destination] = entries else {
+                unreachable!("two keys were given");
+            };
+            anyhow::ensure!(source.is_some(), "no such key");
+            if nx && destination.is_some() {
+                return Ok(false);
+            }
+            *destination = source.take();
+            Ok(true)
+        })?
+    };
+    if nx {
+        protocol::send(stream, &DataType::Integer(renamed.into())).await
+    } else {
+        protocol::send_simple_string(stream, "OK").await
+    }
+}
+
+/// COPY with its DB and REPLACE options. There is only database 0.
+pub async fn invoke_copy<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(source)), Some(DataType::BulkString(destination))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("keys must be bulk strings");
+    };
+    let mut replace = false;
+    while let Some(DataType::BulkString(option)) = args.next() {
+        match &option.to_ascii_uppercase()[..] {
+            b"REPLACE" => replace = true,
+            b"DB" => {
+                let Some(DataType::BulkString(db)) = args.next() else {
+                    anyhow::bail!("syntax error");
+                };
+                anyhow::ensure!(integer_value(&db)? == 0, "DB index is out of range");
+            }
+            _ => anyhow::bail!("syntax error"),
+        }
+    }
+    anyhow::ensure!(
+        source != destination,
+        "source and destination objects are the same"
+    );
+    let copied = store.update_many(&[source, destination], |entries| {
+        let [source, destination] = entries else {
+            unreachable!("two keys were given");
+        };
+        if source.is_none() || (destination.is_some() && !replace) {
+            return false;
+        }
+        destination.clone_from(source);
+        true
+    });
+    protocol::send(stream, &DataType::Integer(copied.into())).await
+}
+
+/// Number of keys, including expired ones not removed yet.
+pub async fn invoke_dbsize(
+    stream: &mut (impl AsyncWrite + Unpin),
+    store: &Store,
+) -> anyhow::Result<()> {
+    protocol::send(stream, &DataType::Integer(store.len() as i64)).await
+}
+
+/// FLUSHDB and FLUSHALL. With ASYNC the old keyspace is freed on a
+/// blocking thread, so a big one doesn't hold up this connection's runtime
+/// thread.
+pub async fn invoke_flush<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let asynchronous = match args.next() {
+        None => false,
+        Some(DataType::BulkString(mode)) if mode.eq_ignore_ascii_case(b"async") => true,
+        Some(DataType::BulkString(mode)) if mode.eq_ignore_ascii_case(b"sync") => false,
+        Some(_) => anyhow::bail!("syntax error"),
+    };
+    anyhow::ensure!(args.next().is_none(), "syntax error");
+    let flushed = store.clear();
+    if asynchronous {
+        tokio::task::spawn_blocking(move || drop(flushed));
+    }
+    protocol::send_simple_string(stream, "OK").await
+}
+
+pub async fn invoke_randomkey(
+    stream: &mut (impl AsyncWrite + Unpin),
+    store: &Store,
+) -> anyhow::Result<()> {
+    match store.random_key() {
+        Some(key) => protocol::send_bulk_string(stream, key).await,
+        None => protocol::send_null(stream).await,
+    }
+}
+
+/// OBJECT ENCODING, IDLETIME, FREQ and HELP. Looking a key up this way
+/// doesn't count as an access to it.
+pub async fn invoke_object<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(subcommand)) = args.next() else {
+        anyhow::bail!("OBJECT subcommand must be given!");
+    };
+    let subcommand = subcommand.to_ascii_uppercase();
+    if subcommand == b"HELP" {
+        let help = [
+            "OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+            "ENCODING <key>",
+            "    Return the kind of internal representation used in order to store the value",
+            "    associated with a <key>. Values are never stored compactly, so this is one",
+            "    of raw, quicklist, hashtable, skiplist or stream.",
+            "FREQ <key>",
+            "    Return the access frequency index of the <key>. The returned integer is",
+            "    proportional to the logarithm of the recent access frequency of the key.",
+            "IDLETIME <key>",
+            "    Return the idle time of the <key>, that is the approximated number of",
+            "    seconds elapsed since the last access to the key.",
+            "HELP",
+            "    Print this help.",
+        ];
+        let help: Vec<_> = help
+            .into_iter()
+            .map(|line| DataType::SimpleString(line.into()))
+            .collect();
+        return protocol::send_array(stream, &help).await;
+    }
+    let (Some(DataType::BulkString(k)), None) = (args.next(), args.next()) else {
+        anyhow::bail!(
+            "unknown subcommand or wrong number of arguments for '{}'. Try OBJECT HELP.",
+            subcommand.escape_ascii()
+        );
+    };
+    let now = Instant::now();
+    let reply = store.peek(&k, |entry| match &subcommand[..] {
+        b"ENCODING" => Some(DataType::BulkString(entry.value.encoding().into())),
+        b"IDLETIME" => Some(DataType::Integer(entry.idle_time(now).as_secs() as i64)),
+        b"FREQ" => Some(DataType::Integer(entry.frequency(now).into())),
+        _ => None,
+    });
+    match reply {
+        Some(Some(reply)) => protocol::send(stream, &reply).await,
+        Some(None) => anyhow::bail!(
+            "unknown subcommand '{}'. Try OBJECT HELP.",
+            subcommand.escape_ascii()
+        ),
         None => protocol::send_null(stream).await,
     }
 }
 
+/// Every key matching a glob pattern. This walks the whole keyspace, so is
+/// meant for debugging rather than production use.
+pub async fn invoke_keys<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(pattern)) = args.next() else {
+        anyhow::bail!("pattern must be given!");
+    };
+    let keys: Vec<_> = store
+        .keys(|key| glob::matches(&pattern, key))
+        .into_iter()
+        .map(DataType::BulkString)
+        .collect();
+    protocol::send_array(stream, &keys).await
+}
+
+/// SCAN with its MATCH, COUNT and TYPE options. Replies with the next
+/// cursor and a batch of keys.
+pub async fn invoke_scan<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(cursor)) = args.next() else {
+        anyhow::bail!("cursor must be given!");
+    };
+    let cursor = cursor_value(&cursor)?;
+    let mut pattern = None;
+    let mut count = 10;
+    let mut type_name = None;
+    while let Some(DataType::BulkString(option)) = args.next() {
+        let Some(DataType::BulkString(value)) = args.next() else {
+            anyhow::bail!("syntax error");
+        };
+        match &option.to_ascii_uppercase()[..] {
+            b"MATCH" => pattern = Some(value),
+            b"COUNT" => {
+                count = integer_value(&value)?;
+                anyhow::ensure!(count >= 1, "syntax error");
+            }
+            b"TYPE" => type_name = Some(value),
+            _ => anyhow::bail!("syntax error"),
+        }
+    }
+    let (cursor, keys) = store.scan(cursor, count as usize, |key, value| {
+        pattern
+            .as_ref()
+            .map_or(true, |pattern| glob::matches(pattern, key))
+            && type_name.as_ref().map_or(true, |t| {
+                t.eq_ignore_ascii_case(value.value.type_name().as_bytes())
+            })
+    });
+    let keys = keys.into_iter().map(DataType::BulkString).collect();
+    protocol::send_array(
+        stream,
+        &[
+            DataType::BulkString(cursor.to_string().into()),
+            DataType::Array(keys),
+        ],
+    )
+    .await
+}
+
+/// Removes the given keys, counting those that existed.
+pub async fn invoke_del<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let mut count = 0;
+    for arg in args {
+        let DataType::BulkString(key) = arg else {
+            anyhow::bail!("keys must be bulk strings");
+        };
+        count += i64::from(store.remove(&key));
+    }
+    protocol::send(stream, &DataType::Integer(count)).await
+}
+
+/// TTL (in seconds, rounded) or PTTL: -2 for a missing key, -1 for one
+/// without a TTL.
+pub async fn invoke_ttl<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    millis: bool,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let ttl = match store.ttl(&k) {
+        None => -2,
+        Some(None) => -1,
+        Some(Some(left)) if millis => left.as_millis() as i64,
+        Some(Some(left)) => ((left.as_millis() + 500) / 1000) as i64,
+    };
+    protocol::send(stream, &DataType::Integer(ttl)).await
+}
+
 pub async fn invoke_info<'a>(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    config: &Arc<Config>,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(section)) = args.next() else {
+        return protocol::send_bulk_string(stream, &all_info_sections(config)).await;
+    };
+    if section.eq_ignore_ascii_case(b"replication") {
+        return protocol::send_bulk_string(stream, &replication_info(config)).await;
+    }
+    if section.eq_ignore_ascii_case(b"stats") {
+        return protocol::send_bulk_string(stream, &config.stats.info()).await;
+    }
+    if [&b"all"[..], b"default", b"everything"]
+        .iter()
+        .any(|all| section.eq_ignore_ascii_case(all))
+    {
+        return protocol::send_bulk_string(stream, &all_info_sections(config)).await;
+    }
+    anyhow::bail!(
+        "INFO section {} is not yet implemented",
+        section.escape_ascii()
+    )
+}
+
+/// Every supported INFO section, each under its `# Name` header as redis
+/// does when no section is asked for.
+fn all_info_sections(config: &Config) -> String {
+    format!(
+        "# Replication\r\n{}\r\n\r\n# Stats\r\n{}",
+        replication_info(config),
+        config.stats.info()
+    )
+}
+
+fn replication_info(config: &Config) -> String {
+    let mut info = match &config.replica_of {
+        None => {
+            let replicas = config.replicas.lock().unwrap();
+            let mut replicas: Vec<_> = replicas.iter().collect();
+            replicas.sort_by_key(|(addr, _)| **addr);
+            let mut info = format!("role:master\r\nconnected_slaves:{}\r\n", replicas.len());
+            for (i, (_, replica)) in replicas.iter().enumerate() {
+                info.push_str(&format!(
+                    "slave{i}:ip={},port={},state=online,offset=0,lag=0,priority={}\r\n",
+                    replica.ip, replica.port, replica.priority
+                ));
+            }
+            info
+        }
+        Some(master) => {
+            let state = config.replica_state();
+            format!(
+                "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\nmaster_sync_in_progress:{}\r\nreplica_sync_state:{}\r\nslave_priority:{}\r\n",
+                master.master_host,
+                master.master_port,
+                if state == ReplicaState::Connected { "up" } else { "down" },
+                u8::from(state == ReplicaState::Transfer),
+                state,
+                config.tunables().replica_priority,
+            )
+        }
+    };
+    info.push_str(&format!(
+        "master_replid:{}\r\nmaster_repl_offset:{}",
+        config.replication_id, config.replication_offset
+    ));
+    info
+}
+
+pub async fn invoke_config<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
     mut args: impl Iterator<Item = DataType<'a>>,
     config: &Arc<Config>,
 ) -> anyhow::Result<()> {
-    let Some(DataType::BulkString(command)) = args.next() else {
-        anyhow::bail!("command must be given!")
+    let Some(DataType::BulkString(subcommand)) = args.next() else {
+        anyhow::bail!("CONFIG subcommand must be given!");
     };
-    if command == "replication" {
-        let role = if config.replica_of.is_none() {
-            "master"
+    if subcommand.eq_ignore_ascii_case(b"get") {
+        let mut reply = Vec::new();
+        for arg in args {
+            let DataType::BulkString(name) = arg else {
+                anyhow::bail!("parameter names must be bulk strings");
+            };
+            let Ok(name) = std::str::from_utf8(&name) else {
+                continue;
+            };
+            if let Some(value) = config.get(name) {
+                reply.push((
+                    DataType::BulkString(name.to_ascii_lowercase().into()),
+                    DataType::BulkString(value.into()),
+                ));
+            }
+        }
+        return protocol::send(stream, &DataType::Map(reply)).await;
+    }
+    if subcommand.eq_ignore_ascii_case(b"set") {
+        let mut pairs = Vec::new();
+        let mut args = args.map(|arg| match arg {
+            DataType::BulkString(s) => Ok(text_arg(&s)?.to_string()),
+            _ => anyhow::bail!("CONFIG SET arguments must be bulk strings"),
+        });
+        while let Some(name) = args.next() {
+            let Some(value) = args.next() else {
+                return protocol::send_simple_error(
+                    stream,
+                    "ERR wrong number of arguments for 'config|set' command",
+                )
+                .await;
+            };
+            pairs.push((name?, value?));
+        }
+        return match config.set(&pairs) {
+            Ok(()) => protocol::send_simple_string(stream, "OK").await,
+            Err(e) => protocol::send_simple_error(stream, &format!("ERR {e:#}")).await,
+        };
+    }
+    if subcommand.eq_ignore_ascii_case(b"resetstat") {
+        config.stats.reset();
+        return protocol::send_simple_string(stream, "OK").await;
+    }
+    anyhow::bail!(
+        "CONFIG subcommand {} is not yet implemented",
+        subcommand.escape_ascii()
+    )
+}
+
+pub fn invoke_shutdown<'a>(
+    args: impl Iterator<Item = DataType<'a>>,
+    config: &Arc<Config>,
+) -> anyhow::Result<()> {
+    for arg in args {
+        let DataType::BulkString(flag) = arg else {
+            anyhow::bail!("SHUTDOWN flags must be bulk strings");
+        };
+        // nothing is persisted, so the save flags make no difference
+        let known = ["NOSAVE", "SAVE", "NOW", "FORCE"];
+        anyhow::ensure!(
+            known
+                .iter()
+                .any(|k| flag.eq_ignore_ascii_case(k.as_bytes())),
+            "SHUTDOWN flag {} is not supported",
+            flag.escape_ascii()
+        );
+    }
+    // no reply: like redis, the connection is simply closed once draining starts
+    config.shutdown.send_replace(true);
+    Ok(())
+}
+
+pub async fn invoke_replconf<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    handshake: &mut ReplicaHandshake,
+) -> anyhow::Result<()> {
+    while let Some(DataType::BulkString(option)) = args.next() {
+        let Some(DataType::BulkString(value)) = args.next() else {
+            anyhow::bail!("REPLCONF {} without value", option.escape_ascii());
+        };
+        if option.eq_ignore_ascii_case(b"listening-port") {
+            handshake.listening_port = Some(parse_arg(&value, "port")?);
+        } else if option.eq_ignore_ascii_case(b"ip-address") {
+            handshake.ip_address = Some(text_arg(&value)?.to_string());
+        } else if option.eq_ignore_ascii_case(b"priority") {
+            handshake.priority = Some(parse_arg(&value, "priority")?);
+        } else if option.eq_ignore_ascii_case(b"capa") {
+            handshake
+                .capabilities
+                .push(text_arg(&value)?.to_ascii_lowercase());
+        }
+        // other options (ack, getack, ...) are accepted and ignored
+    }
+    protocol::send_simple_string(stream, "OK").await
+}
+
+/// Switches the connection to the requested protocol version, if any, and
+/// describes the server.
+pub async fn invoke_hello(cx: &mut Invocation<'_>, mut args: Args) -> anyhow::Result<()> {
+    let mut version = None;
+    if let Some(DataType::BulkString(requested)) = args.next() {
+        match parse_arg(&requested, "protocol version") {
+            Ok(v @ 2..=3) => version = Some(v),
+            _ => {
+                return protocol::send_simple_error(
+                    &mut cx.stream,
+                    "NOPROTO unsupported protocol version",
+                )
+                .await
+            }
+        }
+    }
+    while let Some(DataType::BulkString(option)) = args.next() {
+        if option.eq_ignore_ascii_case(b"auth") {
+            let (Some(DataType::BulkString(user)), Some(DataType::BulkString(_))) =
+                (args.next(), args.next())
+            else {
+                anyhow::bail!("syntax error");
+            };
+            // no passwords are configured, so the default user is the only
+            // one and any password will do for it
+            if user != "default" {
+                return protocol::send_simple_error(
+                    &mut cx.stream,
+                    "WRONGPASS invalid username-password pair or user is disabled.",
+                )
+                .await;
+            }
+        } else if option.eq_ignore_ascii_case(b"setname") {
+            let Some(DataType::BulkString(name)) = args.next() else {
+                anyhow::bail!("syntax error");
+            };
+            anyhow::ensure!(
+                name.iter().all(|b| b.is_ascii_graphic()),
+                "Client names cannot contain spaces, newlines or special characters."
+            );
+            // accepted for compatibility; client names aren't kept yet
         } else {
-            "slave"
-        };
-        return protocol::send_bulk_string(
-            stream,
-            &format!(
-                "role:{}\r\nmaster_replid:{}\r\nmaster_repl_offset:{}",
-                role, config.replication_id, config.replication_offset
-            ),
-        )
-        .await;
+            anyhow::bail!("syntax error");
+        }
+    }
+    if let Some(version) = version {
+        let _ = protocol::PROTOCOL.try_with(|protocol| protocol.set(version));
     }
-    // send_bulk_string(stream, "").await
-    todo!()
+    let field = |name: &'static str| DataType::BulkString(Bytes::from_static(name.as_bytes()));
+    let role = if cx.config.replica_of.is_some() {
+        "replica"
+    } else {
+        "master"
+    };
+    let reply = DataType::Map(vec![
+        (field("server"), field("redis")),
+        (field("version"), field("7.2.0")),
+        (
+            field("proto"),
+            DataType::Integer(protocol::protocol_version().into()),
+        ),
+        (field("id"), DataType::Integer(cx.id as i64)),
+        (field("mode"), field("standalone")),
+        (field("role"), field(role)),
+        (field("modules"), DataType::Array(Vec::new())),
+    ]);
+    protocol::send(&mut cx.stream, &reply).await
 }
 
-pub async fn invoke_psync(stream: &mut TcpStream, config: &Arc<Config>) -> anyhow::Result<()> {
+pub async fn invoke_psync(cx: &mut Invocation<'_>) -> anyhow::Result<()> {
+    // there is no backlog to continue from, so every PSYNC (psync2-capable
+    // or not) gets a full resync
     protocol::send_simple_string(
-        stream,
+        &mut cx.stream,
         &format!(
             "FULLRESYNC {} {}",
-            config.replication_id, config.replication_offset
+            cx.config.replication_id, cx.config.replication_offset
         ),
     )
     .await?;
+    // replicas that understand it get the diskless format, delimited by a
+    // random mark instead of a length prefix
+    let eof_mark = cx.handshake.supports("eof").then(|| random_hex(40));
+    send_rdb(&mut cx.stream, cx.config, eof_mark).await?;
+    attach_replica(cx);
+    Ok(())
+}
+
+/// The pre-PSYNC handshake used by ancient replicas and tools: just the
+/// length-prefixed RDB, without a FULLRESYNC line.
+pub async fn invoke_sync(cx: &mut Invocation<'_>) -> anyhow::Result<()> {
+    send_rdb(&mut cx.stream, cx.config, None).await?;
+    attach_replica(cx);
+    Ok(())
+}
+
+/// From here on the connection is a replica: listed by `INFO replication`
+/// and exempt from client eviction.
+fn attach_replica(cx: &Invocation<'_>) {
+    cx.config
+        .replicas
+        .lock()
+        .unwrap()
+        .insert(cx.peer, cx.handshake.announced(&cx.peer));
+    cx.memory.no_evict.store(true, Ordering::Relaxed);
+}
+
+async fn send_rdb(
+    stream: &mut (impl AsyncWrite + Unpin),
+    config: &Arc<Config>,
+    eof_mark: Option<String>,
+) -> anyhow::Result<()> {
     let rdb = Bytes::from_static(&[
         82, 69, 68, 73, 83, 48, 48, 49, 49, 250, 9, 114, 101, 100, 105, 115, 45, 118, 101, 114, 5,
         55, 46, 50, 46, 48, 250, 10, 114, 101, 100, 105, 115, 45, 98, 105, 116, 115, 192, 64, 250,
@@ -116,8 +2444,132 @@ pub async fn invoke_psync(stream: &mut TcpStream, config: &Arc<Config>) -> anyho
         109, 194, 176, 196, 16, 0, 250, 8, 97, 111, 102, 45, 98, 97, 115, 101, 192, 0, 255, 240,
         110, 59, 254, 192, 255, 90, 162,
     ]);
+    match &eof_mark {
+        Some(mark) => {
+            stream
+                .write_all(format!("$EOF:{mark}\r\n").as_bytes())
+                .await?
+        }
+        None => {
+            stream
+                .write_all(format!("${}\r\n", rdb.len()).as_bytes())
+                .await?
+        }
+    }
+    let cut = config.faults.truncate_rdb.swap(0, Ordering::Relaxed);
+    if cut > 0 && cut < rdb.len() {
+        stream.write_all(&rdb[..cut]).await?;
+        // what was sent has to reach the replica before the connection drops
+        stream.flush().await?;
+        // an I/O error, so the connection is dropped rather than answered
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            format!("RDB transfer truncated after {cut} bytes (fault injection)"),
+        )
+        .into());
+    }
     stream
-        .write_all(format!("${}\r\n", rdb.len()).as_bytes())
-        .await?;
-    stream.write_all(&rdb).await.context("failed to send file")
+        .write_all(&rdb)
+        .await
+        .context("failed to send file")?;
+    if let Some(mark) = eof_mark {
+        stream.write_all(mark.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+pub async fn invoke_debug(cx: &mut Invocation<'_>, mut args: Args) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        cx.config.tunables().enable_debug_command.allows(&cx.peer),
+        "DEBUG command not allowed by enable-debug-command"
+    );
+    let (stream, store, config) = (&mut cx.stream, cx.store, cx.config);
+    let Some(DataType::BulkString(subcommand)) = args.next() else {
+        anyhow::bail!("DEBUG subcommand must be given!");
+    };
+    if subcommand.eq_ignore_ascii_case(b"truncate-rdb") {
+        let Some(DataType::BulkString(bytes)) = args.next() else {
+            anyhow::bail!("TRUNCATE-RDB without byte count");
+        };
+        let bytes = parse_arg(&bytes, "byte count")?;
+        config.faults.truncate_rdb.store(bytes, Ordering::Relaxed);
+        return protocol::send_simple_string(stream, "OK").await;
+    }
+    if subcommand.eq_ignore_ascii_case(b"dump-json") {
+        let Some(DataType::BulkString(path)) = args.next() else {
+            anyhow::bail!("DUMP-JSON without file name");
+        };
+        let path = text_arg(&path)?;
+        let dump = json::dump(store.entries());
+        tokio::fs::write(path, dump)
+            .await
+            .with_context(|| format!("failed to write {path}"))?;
+        return protocol::send_simple_string(stream, "OK").await;
+    }
+    if subcommand.eq_ignore_ascii_case(b"load-json") {
+        let Some(DataType::BulkString(path)) = args.next() else {
+            anyhow::bail!("LOAD-JSON without file name");
+        };
+        let path = text_arg(&path)?;
+        let input = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("failed to read {path}"))?;
+        let loaded = json::load(&input).with_context(|| format!("failed to load {path}"))?;
+        store.replace(loaded);
+        return protocol::send_simple_string(stream, "OK").await;
+    }
+    anyhow::bail!(
+        "DEBUG subcommand {} is not yet implemented",
+        subcommand.escape_ascii()
+    )
+}
+
+pub async fn invoke_metrics(
+    stream: &mut (impl AsyncWrite + Unpin),
+    store: &Store,
+    config: &Arc<Config>,
+) -> anyhow::Result<()> {
+    let keys = store.len();
+    protocol::send_bulk_string(stream, &config.stats.to_prometheus(keys)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::BufWriter;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn truncated_rdb_still_reaches_the_replica() {
+        let config = Arc::new(Config::default());
+        config.faults.truncate_rdb.store(5, Ordering::Relaxed);
+        let mut stream = BufWriter::new(Vec::new());
+        let e = send_rdb(&mut stream, &config, None).await.unwrap_err();
+        assert!(e.downcast_ref::<io::Error>().is_some());
+        let sent = stream.get_ref();
+        let body = sent.splitn(2, |&b| b == b'\n').nth(1).unwrap();
+        assert!(sent.starts_with(b"$"));
+        assert_eq!(body, b"REDIS");
+    }
+
+    fn keys_of(command: &str, args: &[&'static str]) -> Vec<String> {
+        let args: Vec<_> = args
+            .iter()
+            .map(|arg| DataType::BulkString(Bytes::from_static(arg.as_bytes())))
+            .collect();
+        let keys = lookup(command).unwrap().key_args(&args);
+        keys.iter()
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn stream_reads_list_only_their_keys() {
+        let args = ["COUNT", "2", "streams", "a", "b", "0", "$"];
+        assert_eq!(keys_of("XREAD", &args), ["a", "b"]);
+        let args = ["GROUP", "STREAMS", "c", "STREAMS", "a", ">"];
+        assert_eq!(keys_of("XREADGROUP", &args), ["a"]);
+        assert!(keys_of("XREAD", &["COUNT", "2"]).is_empty());
+        assert_eq!(keys_of("BLPOP", &["a", "b", "0"]), ["a", "b"]);
+    }
 }
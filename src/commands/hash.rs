@@ -0,0 +1,506 @@
+//! Hash commands. A hash is created by the first HSET to its key and
+//! removed once its last field has been deleted.
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::io::AsyncWrite;
+
+use super::{bulk_args, cursor_value, float_value, integer_value, pick_count, send_repeated_picks};
+use crate::{
+    glob,
+    protocol::{self, DataType},
+    random::Rng,
+    store::{Store, StoreValue},
+    value::{Value, WrongType},
+};
+
+/// The hash at `entry`, which is created empty if the key doesn't exist.
+fn hash_or_create(entry: &mut Option<StoreValue>) -> Result<&mut HashMap<Bytes, Bytes>, WrongType> {
+    entry
+        .get_or_insert_with(|| StoreValue::new(Value::Hash(HashMap::new()), None))
+        .value
+        .as_hash_mut()
+}
+
+/// Runs `f` on the hash at `key`, if there is one, without changing it.
+fn read_hash<R>(
+    store: &Store,
+    key: &[u8],
+    f: impl FnOnce(&HashMap<Bytes, Bytes>) -> R,
+) -> Result<Option<R>, WrongType> {
+    store
+        .read(key, |entry| entry.value.as_hash().map(f))
+        .transpose()
+}
+
+/// HSET, replying with how many of the fields are new.
+pub async fn invoke_hset<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let pairs = bulk_args(args, "fields and values")?;
+    anyhow::ensure!(
+        pairs.len() % 2 == 0,
+        "wrong number of arguments for 'hset' command"
+    );
+    let added = store.update(k, |entry| {
+        let hash = hash_or_create(entry)?;
+        let mut added = 0;
+        for pair in pairs.chunks_exact(2) {
+            added += i64::from(hash.insert(pair[0].clone(), pair[1].clone()).is_none());
+        }
+        Ok::<_, WrongType>(added)
+    })?;
+    protocol::send(stream, &DataType::Integer(added)).await
+}
+
+/// HSETNX: sets a field only if the hash doesn't have it yet.
+pub async fn invoke_hsetnx<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(field)),
+        Some(DataType::BulkString(value)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, field and value must be bulk strings");
+    };
+    let set = store.update(k, |entry| {
+        let hash = hash_or_create(entry)?;
+        let set = !hash.contains_key(&field);
+        if set {
+            hash.insert(field, value);
+        }
+        Ok::<_, WrongType>(set)
+    })?;
+    protocol::send(stream, &DataType::Integer(set.into())).await
+}
+
+/// HINCRBY, replying with the new value of the field, which is created as
+/// 0 if missing.
+pub async fn invoke_hincrby<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(field)),
+        Some(DataType::BulkString(by)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, field and increment must be bulk strings");
+    };
+    let by = integer_value(&by)?;
+    let n = store.update(k, |entry| {
+        let hash = hash_or_create(entry)?;
+        let n = match hash.get(&field) {
+            Some(n) => integer_value(n)
+                .ok()
+                .context("hash value is not an integer")?,
+            None => 0,
+        };
+        let n = n
+            .checked_add(by)
+            .context("increment or decrement would overflow")?;
+        hash.insert(field, n.to_string().into());
+        anyhow::Ok(n)
+    })?;
+    protocol::send(stream, &DataType::Integer(n)).await
+}
+
+/// Like HINCRBY, for floating point amounts.
+pub async fn invoke_hincrbyfloat<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(field)),
+        Some(DataType::BulkString(by)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, field and increment must be bulk strings");
+    };
+    let by = float_value(&by)?;
+    let n = store.update(k, |entry| {
+        let hash = hash_or_create(entry)?;
+        let n = match hash.get(&field) {
+            Some(n) => float_value(n).ok().context("hash value is not a float")?,
+            None => 0.0,
+        } + by;
+        anyhow::ensure!(n.is_finite(), "increment would produce NaN or Infinity");
+        hash.insert(field, n.to_string().into());
+        Ok(n)
+    })?;
+    protocol::send_bulk_string(stream, n.to_string()).await
+}
+
+pub async fn invoke_hget<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(field))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and field must be bulk strings");
+    };
+    match read_hash(store, &k, |hash| hash.get(&field).cloned())?.flatten() {
+        Some(value) => protocol::send_bulk_string(stream, &value).await,
+        None => protocol::send_null(stream).await,
+    }
+}
+
+/// HMGET, replying with the value of each field, or null where it is
+/// missing.
+pub async fn invoke_hmget<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let fields = bulk_args(args, "fields")?;
+    let values = read_hash(store, &k, |hash| {
+        fields
+            .iter()
+            .map(|field| hash.get(field).cloned())
+            .collect::<Vec<_>>()
+    })?
+    .unwrap_or_else(|| vec![None; fields.len()]);
+    let values: Vec<_> = values
+        .into_iter()
+        .map(|value| value.map_or(DataType::Null, DataType::BulkString))
+        .collect();
+    protocol::send_array(stream, &values).await
+}
+
+/// HDEL, replying with how many of the fields existed.
+pub async fn invoke_hdel<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let fields = bulk_args(args, "fields")?;
+    let removed = store.update(k, |entry| {
+        let Some(entry) = entry else {
+            return Ok(0);
+        };
+        let hash = entry.value.as_hash_mut()?;
+        let removed = fields.iter().filter(|f| hash.remove(*f).is_some()).count();
+        Ok::<_, WrongType>(removed as i64)
+    })?;
+    protocol::send(stream, &DataType::Integer(removed)).await
+}
+
+pub async fn invoke_hexists<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(field))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and field must be bulk strings");
+    };
+    let exists = read_hash(store, &k, |hash| hash.contains_key(&field))?.unwrap_or(false);
+    protocol::send(stream, &DataType::Integer(exists.into())).await
+}
+
+pub async fn invoke_hlen<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let len = read_hash(store, &k, HashMap::len)?.unwrap_or(0);
+    protocol::send(stream, &DataType::Integer(len as i64)).await
+}
+
+/// What of each field HGETALL, HKEYS and HVALS reply with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Part {
+    Fields,
+    Values,
+    Both,
+}
+
+/// HGETALL, HKEYS and HVALS. HGETALL replies with a map, which RESP2
+/// clients get as a flat array of fields and values.
+pub async fn invoke_hgetall<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    part: Part,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let pairs = read_hash(store, &k, |hash| {
+        hash.iter()
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect::<Vec<_>>()
+    })?
+    .unwrap_or_default();
+    let reply = match part {
+        Part::Fields => DataType::Array(
+            pairs
+                .into_iter()
+                .map(|(field, _)| DataType::BulkString(field))
+                .collect(),
+        ),
+        Part::Values => DataType::Array(
+            pairs
+                .into_iter()
+                .map(|(_, value)| DataType::BulkString(value))
+                .collect(),
+        ),
+        Part::Both => DataType::Map(
+            pairs
+                .into_iter()
+                .map(|(field, value)| (DataType::BulkString(field), DataType::BulkString(value)))
+                .collect(),
+        ),
+    };
+    protocol::send(stream, &reply).await
+}
+
+/// HRANDFIELD: one random field, or with a count, that many distinct ones,
+/// or if it is negative, that many picked independently. WITHVALUES pairs
+/// each field with its value.
+pub async fn invoke_hrandfield<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let Some(DataType::BulkString(count)) = args.next() else {
+        let picked = read_hash(store, &k, |hash| {
+            let place = Rng::new().below(hash.len());
+            hash.keys().nth(place).cloned()
+        })?;
+        return match picked.flatten() {
+            Some(field) => protocol::send_bulk_string(stream, &field).await,
+            None => protocol::send_null(stream).await,
+        };
+    };
+    let count = pick_count(&count)?;
+    let with_values = match args.next() {
+        Some(DataType::BulkString(option)) if option.eq_ignore_ascii_case(b"WITHVALUES") => true,
+        None => false,
+        _ => anyhow::bail!("syntax error"),
+    };
+    let resp3 = protocol::protocol_version() >= 3;
+    let width = if with_values && !resp3 { 2 } else { 1 };
+    let frames = |(field, value): (Bytes, Bytes)| match (with_values, resp3) {
+        (false, _) => vec![DataType::BulkString(field)],
+        (true, true) => vec![DataType::Array(vec![
+            DataType::BulkString(field),
+            DataType::BulkString(value),
+        ])],
+        (true, false) => vec![DataType::BulkString(field), DataType::BulkString(value)],
+    };
+    let mut rng = Rng::new();
+    if count < 0 {
+        return send_repeated_picks(
+            stream,
+            count.unsigned_abs() as usize,
+            width,
+            |n| {
+                read_hash(store, &k, |hash| {
+                    let places: Vec<_> = (0..n).map(|_| rng.below(hash.len())).collect();
+                    fields_at(hash, &places)
+                })
+            },
+            frames,
+        )
+        .await;
+    }
+    let picked = read_hash(store, &k, |hash| {
+        fields_at(hash, &rng.sample(hash.len(), count as usize))
+    })?
+    .unwrap_or_default();
+    let reply: Vec<_> = picked.into_iter().flat_map(frames).collect();
+    protocol::send_array(stream, &reply).await
+}
+
+/// The fields and values at `places` in the hash's order, in the order
+/// given, found in one pass. A place may be given more than once.
+fn fields_at(hash: &HashMap<Bytes, Bytes>, places: &[usize]) -> Vec<(Bytes, Bytes)> {
+    let mut order: Vec<_> = (0..places.len()).collect();
+    order.sort_unstable_by_key(|&i| places[i]);
+    let mut order = order.into_iter().peekable();
+    let mut found = vec![None; places.len()];
+    for (place, (field, value)) in hash.iter().enumerate() {
+        if order.peek().is_none() {
+            break;
+        }
+        while let Some(i) = order.next_if(|&i| places[i] == place) {
+            found[i] = Some((field.clone(), value.clone()));
+        }
+    }
+    found.into_iter().flatten().collect()
+}
+
+/// HSCAN: like SCAN, over the fields of a hash, replying with each field
+/// followed by its value unless NOVALUES is given.
+pub async fn invoke_hscan<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(cursor))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and cursor must be bulk strings");
+    };
+    let cursor = cursor_value(&cursor)?;
+    let mut pattern = None;
+    let mut count = 10;
+    let mut values = true;
+    while let Some(DataType::BulkString(option)) = args.next() {
+        let option = option.to_ascii_uppercase();
+        if option == b"NOVALUES" {
+            values = false;
+            continue;
+        }
+        let Some(DataType::BulkString(value)) = args.next() else {
+            anyhow::bail!("syntax error");
+        };
+        match &option[..] {
+            b"MATCH" => pattern = Some(value),
+            b"COUNT" => {
+                count = integer_value(&value)?;
+                anyhow::ensure!(count >= 1, "syntax error");
+            }
+            _ => anyhow::bail!("syntax error"),
+        }
+    }
+    let (cursor, pairs) = read_hash(store, &k, |hash| {
+        let (cursor, pairs) = store.scan_members(cursor, count as usize, hash.iter());
+        let pairs: Vec<_> = pairs
+            .into_iter()
+            .filter(|(field, _)| {
+                pattern
+                    .as_ref()
+                    .map_or(true, |pattern| glob::matches(pattern, field))
+            })
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect();
+        (cursor, pairs)
+    })?
+    .unwrap_or_default();
+    let items = pairs
+        .into_iter()
+        .flat_map(|(field, value)| [Some(field), values.then_some(value)])
+        .flatten()
+        .map(DataType::BulkString)
+        .collect();
+    protocol::send_array(
+        stream,
+        &[
+            DataType::BulkString(cursor.to_string().into()),
+            DataType::Array(items),
+        ],
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::config::Config;
+
+    fn store_with_hash(fields: usize) -> Store {
+        let store = Store::new(&Config::default());
+        store.update(Bytes::from_static(b"h"), |entry| {
+            let hash = hash_or_create(entry).unwrap();
+            hash.extend((0..fields).map(|i| (i.to_string().into(), Bytes::from_static(b"v"))));
+        });
+        store
+    }
+
+    async fn hrandfield(
+        stream: &mut (impl AsyncWrite + Unpin),
+        store: &Store,
+        args: &[&'static str],
+    ) -> anyhow::Result<()> {
+        let args = ["h"].iter().chain(args);
+        let args = args.map(|arg| DataType::BulkString(Bytes::from_static(arg.as_bytes())));
+        invoke_hrandfield(stream, args, store).await
+    }
+
+    fn hash_len(store: &Store) -> usize {
+        read_hash(store, b"h", HashMap::len).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_the_most_negative_count() {
+        let store = store_with_hash(10);
+        let mut out = Vec::new();
+        let err = hrandfield(&mut out, &store, &["-9223372036854775808"])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("value is out of range"));
+        assert!(out.is_empty());
+        assert_eq!(hash_len(&store), 10);
+    }
+
+    #[tokio::test]
+    async fn streams_a_huge_negative_count() {
+        let store = store_with_hash(10);
+        // the client stops reading once this fills
+        let mut buf = vec![0; 1 << 16];
+        let mut out = Cursor::new(&mut buf[..]);
+        let args = ["-9223372036854775807", "WITHVALUES"];
+        assert!(hrandfield(&mut out, &store, &args).await.is_err());
+        assert!(buf.starts_with(b"*18446744073709551614\r\n$"));
+        assert_eq!(hash_len(&store), 10);
+    }
+
+    #[tokio::test]
+    async fn repeats_picks_across_chunks() {
+        let store = store_with_hash(3);
+        let mut out = Vec::new();
+        hrandfield(&mut out, &store, &["-3000", "WITHVALUES"])
+            .await
+            .unwrap();
+        let lines: Vec<_> = out.split(|&b| b == b'\n').collect();
+        let values: Vec<_> = lines[4..].iter().step_by(4).collect();
+        assert!(out.starts_with(b"*6000\r\n"));
+        assert_eq!(values.len(), 3000);
+        assert!(values.iter().all(|value| value == &b"v\r"));
+    }
+
+    #[tokio::test]
+    async fn picks_distinct_fields() {
+        let store = store_with_hash(50);
+        let mut out = Vec::new();
+        hrandfield(&mut out, &store, &["20"]).await.unwrap();
+        let lines: Vec<_> = out.split(|&b| b == b'\n').collect();
+        let fields: std::collections::HashSet<_> = lines[2..].iter().step_by(2).collect();
+        assert!(out.starts_with(b"*20\r\n"));
+        assert_eq!(fields.len(), 20);
+    }
+}
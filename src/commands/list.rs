@@ -0,0 +1,427 @@
+//! List commands. A list is created by the first push to its key and
+//! removed once its last element has been popped.
+
+use std::{collections::VecDeque, future::Future};
+
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::io::{self, AsyncWrite};
+
+use super::{block_on, bulk_args, deadline_from, index_range, integer_value};
+use crate::{
+    protocol::{self, DataType},
+    store::{Store, StoreValue},
+    value::{Value, WrongType},
+};
+
+/// Which end of a list a command works on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+/// The list at `entry`, which is created empty if the key doesn't exist.
+fn list_or_create(entry: &mut Option<StoreValue>) -> Result<&mut VecDeque<Bytes>, WrongType> {
+    entry
+        .get_or_insert_with(|| StoreValue::new(Value::List(VecDeque::new()), None))
+        .value
+        .as_list_mut()
+}
+
+/// LPUSH and RPUSH, replying with the new length of the list. LPUSHX and
+/// RPUSHX, with `existing`, leave a missing key alone and reply 0.
+pub async fn invoke_push<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    end: End,
+    existing: bool,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let elements = bulk_args(args, "elements")?;
+    let len = store.update(k, |entry| {
+        if existing && entry.is_none() {
+            return Ok(0);
+        }
+        let list = list_or_create(entry)?;
+        match end {
+            End::Left => elements.into_iter().for_each(|e| list.push_front(e)),
+            End::Right => list.extend(elements),
+        }
+        Ok::<_, WrongType>(list.len())
+    })?;
+    protocol::send(stream, &DataType::Integer(len as i64)).await
+}
+
+/// LPOP and RPOP: one element, or with a count, an array of up to that
+/// many.
+pub async fn invoke_pop<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    end: End,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let count = match args.next() {
+        Some(DataType::BulkString(count)) => {
+            let count = integer_value(&count)?;
+            anyhow::ensure!(count >= 0, "value is out of range, must be positive");
+            Some(count as usize)
+        }
+        _ => None,
+    };
+    let popped = pop(store, k, count.unwrap_or(1), end)?;
+    match (popped, count) {
+        (None, None) => protocol::send_null(stream).await,
+        (None, Some(_)) => protocol::send_null_array(stream).await,
+        (Some(popped), None) => protocol::send_bulk_string(stream, &popped[0]).await,
+        (Some(popped), Some(_)) => {
+            let popped: Vec<_> = popped.into_iter().map(DataType::BulkString).collect();
+            protocol::send_array(stream, &popped).await
+        }
+    }
+}
+
+/// BLPOP and BRPOP: pops from the first of the keys holding a non-empty
+/// list, waiting for one to be pushed to if none does.
+pub async fn invoke_blocking_pop<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    closed: impl Future<Output = io::Result<()>>,
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    end: End,
+) -> anyhow::Result<()> {
+    let mut keys = bulk_args(args, "keys and timeout")?;
+    let Some(timeout) = keys.pop() else {
+        anyhow::bail!("timeout must be given!");
+    };
+    let deadline = deadline_from(&timeout)?;
+    let popped = block_on(stream, closed, store, &keys, deadline, || {
+        for key in &keys {
+            if let Some(mut popped) = pop(store, key.clone(), 1, end)? {
+                return Ok(Some((key.clone(), popped.remove(0))));
+            }
+        }
+        Ok(None)
+    })
+    .await?;
+    match popped {
+        Some((key, element)) => {
+            let reply = [DataType::BulkString(key), DataType::BulkString(element)];
+            protocol::send_array(stream, &reply).await
+        }
+        None => protocol::send_null_array(stream).await,
+    }
+}
+
+pub async fn invoke_llen<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let len = store
+        .read(&k, |entry| entry.value.as_list().map(VecDeque::len))
+        .transpose()?
+        .unwrap_or(0);
+    protocol::send(stream, &DataType::Integer(len as i64)).await
+}
+
+/// The elements between two inclusive indexes, where negative ones count
+/// from the end.
+pub async fn invoke_lrange<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(start)),
+        Some(DataType::BulkString(stop)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, start and stop must be bulk strings");
+    };
+    let (start, stop) = (integer_value(&start)?, integer_value(&stop)?);
+    let elements = store
+        .read(&k, |entry| {
+            let list = entry.value.as_list()?;
+            let range = index_range(list.len(), start, stop);
+            Ok::<_, WrongType>(list.range(range).cloned().collect::<Vec<_>>())
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let elements: Vec<_> = elements.into_iter().map(DataType::BulkString).collect();
+    protocol::send_array(stream, &elements).await
+}
+
+/// The element at an index, where negative ones count from the end.
+pub async fn invoke_lindex<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(index))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and index must be bulk strings");
+    };
+    let index = integer_value(&index)?;
+    let element = store
+        .read(&k, |entry| {
+            let list = entry.value.as_list()?;
+            Ok::<_, WrongType>(position(list.len(), index).map(|i| list[i].clone()))
+        })
+        .transpose()?
+        .flatten();
+    match element {
+        Some(element) => protocol::send_bulk_string(stream, &element).await,
+        None => protocol::send_null(stream).await,
+    }
+}
+
+pub async fn invoke_lset<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(index)),
+        Some(DataType::BulkString(element)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, index and element must be bulk strings");
+    };
+    let index = integer_value(&index)?;
+    store.update(k, |entry| {
+        let list = entry.as_mut().context("no such key")?.value.as_list_mut()?;
+        let i = position(list.len(), index).context("index out of range")?;
+        list[i] = element;
+        anyhow::Ok(())
+    })?;
+    protocol::send_simple_string(stream, "OK").await
+}
+
+/// LINSERT, replying with the new length, -1 if the pivot isn't in the
+/// list, or 0 if there is no list.
+pub async fn invoke_linsert<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(place)),
+        Some(DataType::BulkString(pivot)),
+        Some(DataType::BulkString(element)),
+    ) = (args.next(), args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, position, pivot and element must be bulk strings");
+    };
+    let after = match &place.to_ascii_uppercase()[..] {
+        b"BEFORE" => false,
+        b"AFTER" => true,
+        _ => anyhow::bail!("syntax error"),
+    };
+    let len = store.update(k, |entry| {
+        let Some(entry) = entry else {
+            return Ok(0);
+        };
+        let list = entry.value.as_list_mut()?;
+        let Some(i) = list.iter().position(|e| *e == pivot) else {
+            return Ok(-1);
+        };
+        list.insert(i + usize::from(after), element);
+        Ok::<_, WrongType>(list.len() as i64)
+    })?;
+    protocol::send(stream, &DataType::Integer(len)).await
+}
+
+/// LREM: removes up to `count` occurrences of an element, searching from
+/// the end if it is negative, or every occurrence if it is 0.
+pub async fn invoke_lrem<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(count)),
+        Some(DataType::BulkString(element)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, count and element must be bulk strings");
+    };
+    let count = integer_value(&count)?;
+    let limit = match count.unsigned_abs() {
+        0 => usize::MAX,
+        n => n.try_into().unwrap_or(usize::MAX),
+    };
+    let removed = store.update(k, |entry| {
+        let Some(entry) = entry else {
+            return Ok(0);
+        };
+        let list = entry.value.as_list_mut()?;
+        let mut matches: Vec<_> = list
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| **e == element)
+            .map(|(i, _)| i)
+            .collect();
+        if count < 0 {
+            matches.reverse();
+        }
+        matches.truncate(limit);
+        matches.sort_unstable();
+        // back to front, so that the indexes still to go stay put
+        for &i in matches.iter().rev() {
+            list.remove(i);
+        }
+        Ok::<_, WrongType>(matches.len())
+    })?;
+    protocol::send(stream, &DataType::Integer(removed as i64)).await
+}
+
+/// LTRIM: keeps only the elements between two inclusive indexes, as
+/// LRANGE would return them.
+pub async fn invoke_ltrim<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(start)),
+        Some(DataType::BulkString(stop)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, start and stop must be bulk strings");
+    };
+    let (start, stop) = (integer_value(&start)?, integer_value(&stop)?);
+    store.update(k, |entry| {
+        let Some(entry) = entry else {
+            return Ok(());
+        };
+        let list = entry.value.as_list_mut()?;
+        let range = index_range(list.len(), start, stop);
+        list.truncate(range.end);
+        list.drain(..range.start);
+        Ok::<_, WrongType>(())
+    })?;
+    protocol::send_simple_string(stream, "OK").await
+}
+
+/// LPOS: the index of an element, or with COUNT, of up to that many
+/// matches (all of them for 0). RANK picks which match comes first,
+/// counting from the end if negative, and MAXLEN caps how many elements
+/// are compared.
+pub async fn invoke_lpos<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(element))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and element must be bulk strings");
+    };
+    let mut rank = 1;
+    let mut count = None;
+    let mut max_len = 0;
+    while let Some(DataType::BulkString(option)) = args.next() {
+        let Some(DataType::BulkString(value)) = args.next() else {
+            anyhow::bail!("syntax error");
+        };
+        let value = integer_value(&value)?;
+        match &option.to_ascii_uppercase()[..] {
+            b"RANK" => {
+                anyhow::ensure!(
+                    value != 0 && value != i64::MIN,
+                    "RANK can't be zero: use 1 to start from the first match, 2 from the \
+                     second ... or use negative to start from the last match"
+                );
+                rank = value;
+            }
+            b"COUNT" => {
+                anyhow::ensure!(value >= 0, "COUNT can't be negative");
+                count = Some(value as usize);
+            }
+            b"MAXLEN" => {
+                anyhow::ensure!(value >= 0, "MAXLEN can't be negative");
+                max_len = value as usize;
+            }
+            _ => anyhow::bail!("syntax error"),
+        }
+    }
+    let wanted = match count {
+        Some(0) => usize::MAX,
+        Some(count) => count,
+        None => 1,
+    };
+    let skip = (rank.unsigned_abs() - 1).try_into().unwrap_or(usize::MAX);
+    let found = store
+        .read(&k, |entry| {
+            let list = entry.value.as_list()?;
+            let compared = if max_len == 0 {
+                list.len()
+            } else {
+                max_len.min(list.len())
+            };
+            let indexes: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+                Box::new(0..compared)
+            } else {
+                Box::new((list.len() - compared..list.len()).rev())
+            };
+            Ok::<_, WrongType>(
+                indexes
+                    .filter(|&i| list[i] == element)
+                    .skip(skip)
+                    .take(wanted)
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .transpose()?
+        .unwrap_or_default();
+    match (count, found.first()) {
+        (None, Some(&i)) => protocol::send(stream, &DataType::Integer(i as i64)).await,
+        (None, None) => protocol::send_null(stream).await,
+        (Some(_), _) => {
+            let found: Vec<_> = found
+                .into_iter()
+                .map(|i| DataType::Integer(i as i64))
+                .collect();
+            protocol::send_array(stream, &found).await
+        }
+    }
+}
+
+/// Resolves `index` against a list of `len` elements, where negative ones
+/// count from the end, if it is in range.
+fn position(len: usize, index: i64) -> Option<usize> {
+    let index = if index < 0 { index + len as i64 } else { index };
+    usize::try_from(index).ok().filter(|&i| i < len)
+}
+
+/// Pops up to `count` elements from `end` of the list at `key`, or `None`
+/// if there is no list there.
+fn pop(store: &Store, key: Bytes, count: usize, end: End) -> Result<Option<Vec<Bytes>>, WrongType> {
+    store.update(key, |entry| {
+        let Some(entry) = entry else {
+            return Ok(None);
+        };
+        let list = entry.value.as_list_mut()?;
+        let n = count.min(list.len());
+        Ok(Some(match end {
+            End::Left => list.drain(..n).collect(),
+            End::Right => list.drain(list.len() - n..).rev().collect(),
+        }))
+    })
+}
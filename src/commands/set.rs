@@ -0,0 +1,470 @@
+//! Set commands. A set is created by the first SADD to its key and
+//! removed once its last member has been removed.
+
+use bytes::Bytes;
+use tokio::io::AsyncWrite;
+
+use super::{bulk_args, cursor_value, integer_value, pick_count, send_repeated_picks};
+use crate::{
+    glob,
+    protocol::{self, DataType},
+    random::Rng,
+    store::{Store, StoreValue},
+    value::{Set, Value, WrongType},
+};
+
+/// The set at `entry`, which is created empty if the key doesn't exist.
+fn set_or_create(entry: &mut Option<StoreValue>) -> Result<&mut Set, WrongType> {
+    entry
+        .get_or_insert_with(|| StoreValue::new(Value::Set(Set::default()), None))
+        .value
+        .as_set_mut()
+}
+
+/// Runs `f` on the set at `key`, if there is one, without changing it.
+fn read_set<R>(
+    store: &Store,
+    key: &[u8],
+    f: impl FnOnce(&Set) -> R,
+) -> Result<Option<R>, WrongType> {
+    store
+        .read(key, |entry| entry.value.as_set().map(f))
+        .transpose()
+}
+
+/// SADD, replying with how many of the members are new.
+pub async fn invoke_sadd<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let members = bulk_args(args, "members")?;
+    let added = store.update(k, |entry| {
+        let set = set_or_create(entry)?;
+        let added = members
+            .into_iter()
+            .filter(|m| set.insert(m.clone()))
+            .count();
+        Ok::<_, WrongType>(added as i64)
+    })?;
+    protocol::send(stream, &DataType::Integer(added)).await
+}
+
+/// SREM, replying with how many of the members were in the set.
+pub async fn invoke_srem<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let members = bulk_args(args, "members")?;
+    let removed = store.update(k, |entry| {
+        let Some(entry) = entry else {
+            return Ok(0);
+        };
+        let set = entry.value.as_set_mut()?;
+        let removed = members.iter().filter(|m| set.remove(m)).count();
+        Ok::<_, WrongType>(removed as i64)
+    })?;
+    protocol::send(stream, &DataType::Integer(removed)).await
+}
+
+/// SMEMBERS, replying with a set, which RESP2 clients get as an array.
+pub async fn invoke_smembers<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let members =
+        read_set(store, &k, |set| set.iter().cloned().collect::<Vec<_>>())?.unwrap_or_default();
+    let members = members.into_iter().map(DataType::BulkString).collect();
+    protocol::send(stream, &DataType::Set(members)).await
+}
+
+pub async fn invoke_sismember<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(member))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and member must be bulk strings");
+    };
+    let is_member = read_set(store, &k, |set| set.contains(&member))?.unwrap_or(false);
+    protocol::send(stream, &DataType::Integer(is_member.into())).await
+}
+
+/// SMISMEMBER, replying with 1 or 0 for each member.
+pub async fn invoke_smismember<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let members = bulk_args(args, "members")?;
+    let found = read_set(store, &k, |set| {
+        members
+            .iter()
+            .map(|m| DataType::Integer(set.contains(m).into()))
+            .collect::<Vec<_>>()
+    })?
+    .unwrap_or_else(|| members.iter().map(|_| DataType::Integer(0)).collect());
+    protocol::send_array(stream, &found).await
+}
+
+pub async fn invoke_scard<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let len = read_set(store, &k, Set::len)?.unwrap_or(0);
+    protocol::send(stream, &DataType::Integer(len as i64)).await
+}
+
+/// How SINTER, SUNION, SDIFF and their variants combine their sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algebra {
+    Inter,
+    Union,
+    Diff,
+}
+
+impl Algebra {
+    /// Combines `sets`, where a missing key counts as an empty set. A
+    /// difference is the first set less all the others.
+    fn apply(self, sets: &[Option<&Set>]) -> Set {
+        match self {
+            Algebra::Inter => intersection(sets).cloned().collect(),
+            Algebra::Union => sets
+                .iter()
+                .flatten()
+                .flat_map(|set| set.iter().cloned())
+                .collect(),
+            Algebra::Diff => {
+                let Some(Some(first)) = sets.first() else {
+                    return Set::default();
+                };
+                first
+                    .iter()
+                    .filter(|m| !sets[1..].iter().flatten().any(|set| set.contains(m)))
+                    .cloned()
+                    .collect()
+            }
+        }
+    }
+}
+
+/// The members common to all of `sets`, none if one is missing.
+fn intersection<'s>(sets: &'s [Option<&'s Set>]) -> impl Iterator<Item = &'s Bytes> {
+    let all_present = sets.iter().all(Option::is_some);
+    // check the members of the smallest against the rest
+    let smallest = sets
+        .iter()
+        .flatten()
+        .min_by_key(|set| set.len())
+        .filter(|_| all_present);
+    smallest
+        .into_iter()
+        .flat_map(|set| set.iter())
+        .filter(move |m| sets.iter().flatten().all(|set| set.contains(m)))
+}
+
+/// Runs `f` on the entries at `keys`, which needn't be distinct, all locked
+/// together so that no write lands in between. `f` gets the distinct keys'
+/// entries, and for each of `keys`, the index of its entry.
+fn with_sets<R>(
+    store: &Store,
+    keys: &[Bytes],
+    f: impl FnOnce(&mut [Option<StoreValue>], &[usize]) -> anyhow::Result<R>,
+) -> anyhow::Result<R> {
+    let mut distinct = keys.to_vec();
+    distinct.sort_unstable();
+    distinct.dedup();
+    let indexes: Vec<_> = keys
+        .iter()
+        .map(|key| distinct.binary_search(key).unwrap())
+        .collect();
+    store.update_many(&distinct, |entries| f(entries, &indexes))
+}
+
+/// The sets in `entries` at `indexes`, or `None` for those missing.
+fn sets_at<'e>(
+    entries: &'e [Option<StoreValue>],
+    indexes: &[usize],
+) -> Result<Vec<Option<&'e Set>>, WrongType> {
+    indexes
+        .iter()
+        .map(|&i| entries[i].as_ref().map(|e| e.value.as_set()).transpose())
+        .collect()
+}
+
+/// SINTER, SUNION and SDIFF, replying with the resulting set.
+pub async fn invoke_algebra<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    algebra: Algebra,
+) -> anyhow::Result<()> {
+    let keys = bulk_args(args, "keys")?;
+    let members = with_sets(store, &keys, |entries, indexes| {
+        Ok(algebra.apply(&sets_at(entries, indexes)?))
+    })?;
+    let members = members.into_iter().map(DataType::BulkString).collect();
+    protocol::send(stream, &DataType::Set(members)).await
+}
+
+/// SINTERSTORE, SUNIONSTORE and SDIFFSTORE: replaces whatever the
+/// destination held with the resulting set, or removes it if that is empty,
+/// and replies with its size.
+pub async fn invoke_algebra_store<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    algebra: Algebra,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(destination)) = args.next() else {
+        anyhow::bail!("destination must be given!");
+    };
+    let mut keys = bulk_args(args, "keys")?;
+    keys.push(destination);
+    let len = with_sets(store, &keys, |entries, indexes| {
+        let (&destination, sources) = indexes.split_last().unwrap();
+        let members = algebra.apply(&sets_at(entries, sources)?);
+        let len = members.len();
+        entries[destination] = Some(StoreValue::new(Value::Set(members), None));
+        Ok(len)
+    })?;
+    protocol::send(stream, &DataType::Integer(len as i64)).await
+}
+
+/// SINTERCARD: the size of the intersection, counting no further than
+/// LIMIT if it is given and not 0.
+pub async fn invoke_sintercard<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(numkeys)) = args.next() else {
+        anyhow::bail!("numkeys must be given!");
+    };
+    let numkeys = integer_value(&numkeys)?;
+    anyhow::ensure!(numkeys > 0, "numkeys should be greater than 0");
+    let mut args = bulk_args(args, "keys")?;
+    anyhow::ensure!(
+        numkeys as usize <= args.len(),
+        "Number of keys can't be greater than number of args"
+    );
+    let options = args.split_off(numkeys as usize);
+    let keys = args;
+    let mut limit = 0;
+    let mut options = options.into_iter();
+    while let Some(option) = options.next() {
+        let (true, Some(value)) = (option.eq_ignore_ascii_case(b"LIMIT"), options.next()) else {
+            anyhow::bail!("syntax error");
+        };
+        limit = integer_value(&value)?;
+        anyhow::ensure!(limit >= 0, "LIMIT can't be negative");
+    }
+    let limit = match limit {
+        0 => usize::MAX,
+        limit => limit as usize,
+    };
+    let len = with_sets(store, &keys, |entries, indexes| {
+        Ok(intersection(&sets_at(entries, indexes)?)
+            .take(limit)
+            .count())
+    })?;
+    protocol::send(stream, &DataType::Integer(len as i64)).await
+}
+
+/// SPOP: removes one random member, or with a count, up to that many.
+pub async fn invoke_spop<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let count = match args.next() {
+        Some(DataType::BulkString(count)) => {
+            let count = integer_value(&count)?;
+            anyhow::ensure!(count >= 0, "value is out of range, must be positive");
+            Some(count)
+        }
+        _ => None,
+    };
+    let popped = store.update(k, |entry| {
+        let Some(entry) = entry else {
+            return Ok(Vec::new());
+        };
+        let set = entry.value.as_set_mut()?;
+        let n = set.len().min(count.unwrap_or(1) as usize);
+        let mut rng = Rng::new();
+        let popped: Vec<_> = (0..n)
+            .map(|_| set.remove_at(rng.below(set.len())))
+            .collect();
+        Ok::<_, WrongType>(popped)
+    })?;
+    if count.is_none() {
+        return match popped.first() {
+            Some(member) => protocol::send_bulk_string(stream, member).await,
+            None => protocol::send_null(stream).await,
+        };
+    }
+    let popped = popped.into_iter().map(DataType::BulkString).collect();
+    protocol::send(stream, &DataType::Set(popped)).await
+}
+
+/// SRANDMEMBER: one random member, or with a count, that many distinct
+/// ones, or if it is negative, that many picked independently.
+pub async fn invoke_srandmember<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let count = match args.next() {
+        Some(DataType::BulkString(count)) => pick_count(&count)?,
+        _ => {
+            let picked = read_set(store, &k, |set| {
+                set.member(Rng::new().below(set.len())).clone()
+            })?;
+            return match picked {
+                Some(member) => protocol::send_bulk_string(stream, &member).await,
+                None => protocol::send_null(stream).await,
+            };
+        }
+    };
+    let mut rng = Rng::new();
+    if count < 0 {
+        return send_repeated_picks(
+            stream,
+            count.unsigned_abs() as usize,
+            1,
+            |n| {
+                read_set(store, &k, |set| {
+                    (0..n)
+                        .map(|_| set.member(rng.below(set.len())).clone())
+                        .collect()
+                })
+            },
+            |member| vec![DataType::BulkString(member)],
+        )
+        .await;
+    }
+    let picked = read_set(store, &k, |set| {
+        rng.sample(set.len(), count as usize)
+            .into_iter()
+            .map(|place| DataType::BulkString(set.member(place).clone()))
+            .collect::<Vec<_>>()
+    })?
+    .unwrap_or_default();
+    protocol::send_array(stream, &picked).await
+}
+
+/// SMOVE: moves a member from one set to another, creating the destination
+/// if needed, and replies with whether the member was there to move.
+pub async fn invoke_smove<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(source)),
+        Some(DataType::BulkString(destination)),
+        Some(DataType::BulkString(member)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("source, destination and member must be bulk strings");
+    };
+    let moved = with_sets(store, &[source, destination], |entries, indexes| {
+        let &[source, destination] = indexes else {
+            unreachable!();
+        };
+        // a destination of the wrong type is an error even if there is
+        // nothing to move
+        if let Some(entry) = &entries[destination] {
+            entry.value.as_set()?;
+        }
+        let Some(entry) = &mut entries[source] else {
+            return Ok(false);
+        };
+        if !entry.value.as_set_mut()?.remove(&member) {
+            return Ok(false);
+        }
+        set_or_create(&mut entries[destination])?.insert(member);
+        Ok(true)
+    })?;
+    protocol::send(stream, &DataType::Integer(moved.into())).await
+}
+
+/// SSCAN: like SCAN, over the members of a set.
+pub async fn invoke_sscan<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(cursor))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and cursor must be bulk strings");
+    };
+    let cursor = cursor_value(&cursor)?;
+    let mut pattern = None;
+    let mut count = 10;
+    while let Some(DataType::BulkString(option)) = args.next() {
+        let Some(DataType::BulkString(value)) = args.next() else {
+            anyhow::bail!("syntax error");
+        };
+        match &option.to_ascii_uppercase()[..] {
+            b"MATCH" => pattern = Some(value),
+            b"COUNT" => {
+                count = integer_value(&value)?;
+                anyhow::ensure!(count >= 1, "syntax error");
+            }
+            _ => anyhow::bail!("syntax error"),
+        }
+    }
+    let (cursor, members) = read_set(store, &k, |set| {
+        let members = set.iter().map(|member| (member, ()));
+        let (cursor, members) = store.scan_members(cursor, count as usize, members);
+        let members: Vec<_> = members
+            .into_iter()
+            .map(|(member, ())| member)
+            .filter(|member| {
+                pattern
+                    .as_ref()
+                    .map_or(true, |pattern| glob::matches(pattern, member))
+            })
+            .cloned()
+            .collect();
+        (cursor, members)
+    })?
+    .unwrap_or_default();
+    let members = members.into_iter().map(DataType::BulkString).collect();
+    protocol::send_array(
+        stream,
+        &[
+            DataType::BulkString(cursor.to_string().into()),
+            DataType::Array(members),
+        ],
+    )
+    .await
+}
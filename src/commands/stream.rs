@@ -0,0 +1,927 @@
+//! Stream commands. Unlike other collections, a stream is kept once its
+//! last entry is gone, so that new IDs keep increasing.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    ops::Bound,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::{
+    io::{self, AsyncWrite},
+    time::{Duration, Instant},
+};
+
+use super::{block_on, bulk_args, integer_value};
+use crate::{
+    protocol::{self, DataType},
+    store::{Store, StoreValue},
+    value::{ConsumerGroup, GroupError, Stream, StreamId, Value, WrongType},
+};
+
+/// The stream at `entry`, which is created empty if the key doesn't exist.
+fn stream_or_create(entry: &mut Option<StoreValue>) -> Result<&mut Stream, WrongType> {
+    entry
+        .get_or_insert_with(|| StoreValue::new(Value::Stream(Stream::default()), None))
+        .value
+        .as_stream_mut()
+}
+
+/// The ID XADD gives a new entry: `*` for the current time, `<ms>-*` for
+/// the next sequence number at `ms`, or an explicit ID, all of which must
+/// come after `last`.
+fn next_id(requested: &[u8], last: StreamId) -> anyhow::Result<StreamId> {
+    const TOO_SMALL: &str =
+        "The ID specified in XADD is equal or smaller than the target stream top item";
+    let invalid = || anyhow::anyhow!("Invalid stream ID specified as stream command argument");
+    let requested = std::str::from_utf8(requested).map_err(|_| invalid())?;
+    if requested == "*" {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        if now > last.ms {
+            return Ok(StreamId { ms: now, seq: 0 });
+        }
+        // the clock is behind the last ID, which carries on from there
+        return match last.seq.checked_add(1) {
+            Some(seq) => Ok(StreamId { ms: last.ms, seq }),
+            None => {
+                let ms = last.ms.checked_add(1).context(
+                    "The stream has exhausted the last possible ID, unable to add more items",
+                )?;
+                Ok(StreamId { ms, seq: 0 })
+            }
+        };
+    }
+    if let Some(ms) = requested.strip_suffix("-*") {
+        let ms: u64 = ms.parse().map_err(|_| invalid())?;
+        let seq = match ms.cmp(&last.ms) {
+            std::cmp::Ordering::Less => anyhow::bail!(TOO_SMALL),
+            std::cmp::Ordering::Equal => last.seq.checked_add(1).context(TOO_SMALL)?,
+            // 0-0 is never a valid ID
+            std::cmp::Ordering::Greater => u64::from(ms == 0),
+        };
+        return Ok(StreamId { ms, seq });
+    }
+    let id: StreamId = requested.parse().map_err(|_| invalid())?;
+    anyhow::ensure!(
+        id != StreamId::MIN,
+        "The ID specified in XADD must be greater than 0-0"
+    );
+    anyhow::ensure!(id > last, TOO_SMALL);
+    Ok(id)
+}
+
+/// XADD, replying with the ID of the new entry.
+pub async fn invoke_xadd<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(id))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and ID must be bulk strings");
+    };
+    let fields = bulk_args(args, "fields and values")?;
+    anyhow::ensure!(
+        !fields.is_empty() && fields.len() % 2 == 0,
+        "wrong number of arguments for 'xadd' command"
+    );
+    let id = store.update(k, |entry| {
+        // a bad ID mustn't leave an empty stream behind
+        let last = match entry {
+            Some(entry) => entry.value.as_stream()?.last_id,
+            None => StreamId::MIN,
+        };
+        let id = next_id(&id, last)?;
+        let target = stream_or_create(entry)?;
+        let fields = fields
+            .chunks_exact(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        target.entries.insert(id, fields);
+        target.last_id = id;
+        anyhow::Ok(id)
+    })?;
+    protocol::send_bulk_string(stream, id.to_string()).await
+}
+
+pub async fn invoke_xlen<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let len = store
+        .read(&k, |entry| entry.value.as_stream().map(|s| s.entries.len()))
+        .transpose()?
+        .unwrap_or(0);
+    protocol::send(stream, &DataType::Integer(len as i64)).await
+}
+
+/// An entry as replied to XRANGE and XREAD: its ID, then its fields and
+/// values in one flat array.
+fn entry_reply<'a>(id: StreamId, fields: &[(Bytes, Bytes)]) -> DataType<'a> {
+    let fields = fields
+        .iter()
+        .flat_map(|(field, value)| [field, value])
+        .map(|data| DataType::BulkString(data.clone()))
+        .collect();
+    DataType::Array(vec![
+        DataType::BulkString(id.to_string().into()),
+        DataType::Array(fields),
+    ])
+}
+
+/// A bound of an XRANGE: `-` or `+` for either end of the stream, or an ID,
+/// exclusive after `(`. An ID without a sequence number covers all of its
+/// millisecond, so its sequence number depends on which `end` it is.
+fn range_bound(bound: &[u8], end: bool) -> anyhow::Result<Bound<StreamId>> {
+    let bound = std::str::from_utf8(bound)
+        .ok()
+        .context("Invalid stream ID specified as stream command argument")?;
+    let (exclusive, bound) = match bound.strip_prefix('(') {
+        Some(bound) => (true, bound),
+        None => (false, bound),
+    };
+    let id = match bound {
+        "-" if !exclusive => StreamId::MIN,
+        "+" if !exclusive => StreamId::MAX,
+        ms if end && !ms.contains('-') => StreamId {
+            seq: u64::MAX,
+            ..ms.parse()?
+        },
+        id => id.parse()?,
+    };
+    Ok(if exclusive {
+        Bound::Excluded(id)
+    } else {
+        Bound::Included(id)
+    })
+}
+
+/// Whether no ID lies between `start` and `end`.
+fn is_empty_range(start: Bound<StreamId>, end: Bound<StreamId>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// XRANGE and XREVRANGE, which takes its bounds end first and replies with
+/// the last entries first. COUNT caps how many entries are replied with.
+pub async fn invoke_xrange<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    rev: bool,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(first)),
+        Some(DataType::BulkString(second)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, start and end must be bulk strings");
+    };
+    let (start, end) = if rev {
+        (second, first)
+    } else {
+        (first, second)
+    };
+    let (start, end) = (range_bound(&start, false)?, range_bound(&end, true)?);
+    let count = match (args.next(), args.next()) {
+        (None, _) => usize::MAX,
+        (Some(DataType::BulkString(option)), Some(DataType::BulkString(count)))
+            if option.eq_ignore_ascii_case(b"COUNT") =>
+        {
+            usize::try_from(integer_value(&count)?).unwrap_or(0)
+        }
+        _ => anyhow::bail!("syntax error"),
+    };
+    anyhow::ensure!(args.next().is_none(), "syntax error");
+    let entries = store
+        .read(&k, |entry| {
+            let target = entry.value.as_stream()?;
+            if is_empty_range(start, end) {
+                return Ok(Vec::new());
+            }
+            let entries = target.entries.range((start, end));
+            let entries: Box<dyn Iterator<Item = _>> = if rev {
+                Box::new(entries.rev())
+            } else {
+                Box::new(entries)
+            };
+            Ok::<_, WrongType>(
+                entries
+                    .take(count)
+                    .map(|(&id, fields)| entry_reply(id, fields))
+                    .collect(),
+            )
+        })
+        .transpose()?
+        .unwrap_or_default();
+    protocol::send_array(stream, &entries).await
+}
+
+/// An ID given as a command argument.
+fn parse_id(id: &[u8]) -> anyhow::Result<StreamId> {
+    std::str::from_utf8(id)
+        .ok()
+        .context("Invalid stream ID specified as stream command argument")?
+        .parse()
+}
+
+/// The arguments of XREAD and XREADGROUP: options up to STREAMS, then the
+/// keys and as many IDs.
+struct ReadArgs {
+    count: usize,
+    /// Set by BLOCK, to when to stop waiting, if ever.
+    block: Option<Option<Instant>>,
+    /// The group and consumer XREADGROUP reads as.
+    group: Option<(Bytes, Bytes)>,
+    noack: bool,
+    keys: Vec<Bytes>,
+    ids: Vec<Bytes>,
+}
+
+impl ReadArgs {
+    fn parse(args: Vec<Bytes>, command: &str) -> anyhow::Result<Self> {
+        let mut args = args.into_iter();
+        let mut read = ReadArgs {
+            count: usize::MAX,
+            block: None,
+            group: None,
+            noack: false,
+            keys: Vec::new(),
+            ids: Vec::new(),
+        };
+        loop {
+            let Some(option) = args.next() else {
+                anyhow::bail!("syntax error");
+            };
+            match &option.to_ascii_uppercase()[..] {
+                b"STREAMS" => break,
+                b"COUNT" => {
+                    let value = integer_value(&args.next().context("syntax error")?)?;
+                    // as in redis, a count of 0 or less means no limit
+                    read.count = usize::try_from(value)
+                        .ok()
+                        .filter(|&c| c > 0)
+                        .unwrap_or(usize::MAX);
+                }
+                b"BLOCK" => {
+                    let ms = integer_value(&args.next().context("syntax error")?)?;
+                    anyhow::ensure!(ms >= 0, "timeout is negative");
+                    // 0 waits forever, as does a timeout too long to reach
+                    read.block = Some(Some(ms).filter(|&ms| ms > 0).and_then(|ms| {
+                        Instant::now().checked_add(Duration::from_millis(ms as u64))
+                    }));
+                }
+                b"GROUP" => {
+                    let (Some(group), Some(consumer)) = (args.next(), args.next()) else {
+                        anyhow::bail!("syntax error");
+                    };
+                    read.group = Some((group, consumer));
+                }
+                b"NOACK" => read.noack = true,
+                _ => anyhow::bail!("syntax error"),
+            }
+        }
+        read.keys = args.collect();
+        anyhow::ensure!(
+            !read.keys.is_empty() && read.keys.len() % 2 == 0,
+            "Unbalanced '{command}' list of streams: for each stream key an ID or '$' must be \
+             specified."
+        );
+        read.ids = read.keys.split_off(read.keys.len() / 2);
+        Ok(read)
+    }
+}
+
+/// The reply to XREAD and XREADGROUP: a map from key to entries, which
+/// RESP2 has as an array of pairs, or null if there are none.
+async fn send_streams(
+    stream: &mut (impl AsyncWrite + Unpin),
+    found: Option<Vec<(Bytes, Vec<DataType<'_>>)>>,
+) -> anyhow::Result<()> {
+    let Some(found) = found else {
+        return protocol::send_null_array(stream).await;
+    };
+    let found = found
+        .into_iter()
+        .map(|(key, entries)| (DataType::BulkString(key), DataType::Array(entries)));
+    let reply = if protocol::protocol_version() >= 3 {
+        DataType::Map(found.collect())
+    } else {
+        DataType::Array(found.map(|(k, v)| DataType::Array(vec![k, v])).collect())
+    };
+    protocol::send(stream, &reply).await
+}
+
+/// XREAD: the entries after the given ID of each stream, for those that
+/// have any, with `$` standing for the last ID a stream has now. With
+/// BLOCK, waits that many milliseconds (forever for 0) for one of them to
+/// get new entries if none has, replying null if none does.
+pub async fn invoke_xread<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    closed: impl Future<Output = io::Result<()>>,
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let read = ReadArgs::parse(bulk_args(args, "arguments")?, "xread")?;
+    anyhow::ensure!(
+        read.group.is_none(),
+        "The GROUP option is only supported by XREADGROUP. You called XREAD instead."
+    );
+    anyhow::ensure!(!read.noack, "syntax error");
+    let after = read
+        .keys
+        .iter()
+        .zip(&read.ids)
+        .map(|(key, id)| match &id[..] {
+            b"$" => Ok(store
+                .read(key, |entry| entry.value.as_stream().map(|s| s.last_id))
+                .transpose()?
+                .unwrap_or(StreamId::MIN)),
+            id => parse_id(id),
+        })
+        .collect::<anyhow::Result<Vec<StreamId>>>()?;
+    let attempt = || {
+        let mut found = Vec::new();
+        for (key, &after) in read.keys.iter().zip(&after) {
+            let entries = store
+                .read(key, |entry| {
+                    let target = entry.value.as_stream()?;
+                    Ok::<_, WrongType>(
+                        target
+                            .entries
+                            .range((Bound::Excluded(after), Bound::Unbounded))
+                            .take(read.count)
+                            .map(|(&id, fields)| entry_reply(id, fields))
+                            .collect::<Vec<_>>(),
+                    )
+                })
+                .transpose()?
+                .unwrap_or_default();
+            if !entries.is_empty() {
+                found.push((key.clone(), entries));
+            }
+        }
+        Ok((!found.is_empty()).then_some(found))
+    };
+    let found = match read.block {
+        Some(deadline) => block_on(stream, closed, store, &read.keys, deadline, attempt).await?,
+        None => attempt()?,
+    };
+    send_streams(stream, found).await
+}
+
+/// The consumer group `name` of `target`, the stream at `key`.
+fn group_mut<'t>(
+    target: &'t mut Stream,
+    key: &[u8],
+    name: &[u8],
+) -> Result<&'t mut ConsumerGroup, GroupError> {
+    target
+        .groups
+        .get_mut(name)
+        .ok_or_else(|| GroupError::no_group(key, name))
+}
+
+/// The entries of the stream at `entry` and its consumer group `name`.
+#[allow(clippy::type_complexity)]
+fn stream_group<'e>(
+    entry: &'e mut Option<StoreValue>,
+    key: &[u8],
+    name: &[u8],
+) -> anyhow::Result<(
+    &'e BTreeMap<StreamId, Vec<(Bytes, Bytes)>>,
+    &'e mut ConsumerGroup,
+)> {
+    let Some(entry) = entry else {
+        return Err(GroupError::no_group(key, name).into());
+    };
+    let target = entry.value.as_stream_mut()?;
+    let group = target
+        .groups
+        .get_mut(name)
+        .ok_or_else(|| GroupError::no_group(key, name))?;
+    Ok((&target.entries, group))
+}
+
+/// When an entry was delivered, if that was `ms` milliseconds before
+/// `now`.
+fn delivered_ago(now: Instant, ms: u64) -> Instant {
+    now.checked_sub(Duration::from_millis(ms)).unwrap_or(now)
+}
+
+/// How long ago `instant` was, in milliseconds.
+fn idle_ms(now: Instant, instant: Instant) -> i64 {
+    now.saturating_duration_since(instant).as_millis() as i64
+}
+
+/// XREADGROUP: with the ID `>`, the entries no consumer of the group has
+/// been given yet, which become pending with this one unless NOACK.
+/// Blocks like XREAD while there are none. Any other ID reads back the
+/// entries after it that are pending with the consumer, counting another
+/// delivery of each.
+pub async fn invoke_xreadgroup<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    closed: impl Future<Output = io::Result<()>>,
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let read = ReadArgs::parse(bulk_args(args, "arguments")?, "xreadgroup")?;
+    let (group, consumer) = read
+        .group
+        .as_ref()
+        .context("Missing GROUP option for XREADGROUP")?;
+    let history = read
+        .ids
+        .iter()
+        .map(|id| match &id[..] {
+            b">" => Ok(None),
+            id => parse_id(id).map(Some),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // the consumer is there from now on, whether or not it gets anything
+    let now = Instant::now();
+    for key in &read.keys {
+        store.update(key.clone(), |entry| {
+            stream_group(entry, key, group)?.1.consumer(consumer, now);
+            anyhow::Ok(())
+        })?;
+    }
+    let has_new = |key: &Bytes| {
+        store
+            .read(key, |entry| {
+                let target = entry.value.as_stream()?;
+                let Some(group) = target.groups.get(group) else {
+                    return Ok(false);
+                };
+                let mut new = target
+                    .entries
+                    .range((Bound::Excluded(group.last_delivered), Bound::Unbounded));
+                Ok::<_, WrongType>(new.next().is_some())
+            })
+            .transpose()
+            .map(|has_new| has_new.unwrap_or(false))
+    };
+    let read_key = |key: &Bytes, after: Option<StreamId>| {
+        // writing wakes those waiting on the key, this reader included,
+        // so a key without new entries is only looked at
+        if after.is_none() && !has_new(key)? {
+            return Ok(None);
+        }
+        store.update(key.clone(), |entry| {
+            let now = Instant::now();
+            let (entries, group) = stream_group(entry, key, group)?;
+            let mut replies = Vec::new();
+            match after {
+                None => {
+                    let start = Bound::Excluded(group.last_delivered);
+                    for (&id, fields) in entries.range((start, Bound::Unbounded)).take(read.count) {
+                        group.last_delivered = id;
+                        if !read.noack {
+                            group.assign(id, consumer, now).deliveries = 1;
+                        }
+                        replies.push(entry_reply(id, fields));
+                    }
+                }
+                Some(after) => {
+                    let ids: Vec<_> = group
+                        .consumer(consumer, now)
+                        .pending
+                        .range((Bound::Excluded(after), Bound::Unbounded))
+                        .take(read.count)
+                        .copied()
+                        .collect();
+                    for id in ids {
+                        group.assign(id, consumer, now).deliveries += 1;
+                        // an entry deleted since has no fields to give
+                        replies.push(match entries.get(&id) {
+                            Some(fields) => entry_reply(id, fields),
+                            None => DataType::Array(vec![
+                                DataType::BulkString(id.to_string().into()),
+                                DataType::Null,
+                            ]),
+                        });
+                    }
+                }
+            }
+            anyhow::Ok((after.is_some() || !replies.is_empty()).then_some(replies))
+        })
+    };
+    let attempt = || {
+        let mut found = Vec::new();
+        for (key, &after) in read.keys.iter().zip(&history) {
+            if let Some(entries) = read_key(key, after)? {
+                found.push((key.clone(), entries));
+            }
+        }
+        Ok((!found.is_empty()).then_some(found))
+    };
+    // reading back pending entries replies at once, even with none
+    let found = match read.block {
+        Some(deadline) if history.iter().all(Option::is_none) => {
+            block_on(stream, closed, store, &read.keys, deadline, attempt).await?
+        }
+        _ => attempt()?,
+    };
+    send_streams(stream, found).await
+}
+
+/// XACK, replying with how many of the IDs were pending in the group.
+pub async fn invoke_xack<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let args = bulk_args(args, "key, group and IDs")?;
+    let [key, group, ids @ ..] = &args[..] else {
+        anyhow::bail!("wrong number of arguments for 'xack' command");
+    };
+    let ids = ids
+        .iter()
+        .map(|id| parse_id(id))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let acked = store.update(key.clone(), |entry| {
+        let Some(entry) = entry else {
+            return Ok(0);
+        };
+        let Some(group) = entry.value.as_stream_mut()?.groups.get_mut(group) else {
+            return Ok(0);
+        };
+        Ok::<_, WrongType>(ids.iter().filter(|&&id| group.ack(id)).count())
+    })?;
+    protocol::send(stream, &DataType::Integer(acked as i64)).await
+}
+
+/// XPENDING: with only a key and group, a summary of the entries pending in
+/// the group: how many, the first and last IDs and how many each consumer
+/// has. Given a range and count, the pending entries in it with their
+/// consumer, idle time and delivery count, optionally only those idle for
+/// long enough or pending with one consumer.
+pub async fn invoke_xpending<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let args = bulk_args(args, "arguments")?;
+    let [key, group, rest @ ..] = &args[..] else {
+        anyhow::bail!("wrong number of arguments for 'xpending' command");
+    };
+    let (min_idle, rest) = match rest {
+        [option, idle, rest @ ..] if option.eq_ignore_ascii_case(b"IDLE") => {
+            (integer_value(idle)?.max(0), rest)
+        }
+        rest => (0, rest),
+    };
+    let range = match rest {
+        [] if min_idle == 0 => None,
+        [start, end, count, consumer @ ..] if consumer.len() <= 1 => Some((
+            range_bound(start, false)?,
+            range_bound(end, true)?,
+            usize::try_from(integer_value(count)?).unwrap_or(0),
+            consumer.first(),
+        )),
+        _ => anyhow::bail!("syntax error"),
+    };
+    let now = Instant::now();
+    let reply = store
+        .read(key, |entry| {
+            let group = entry
+                .value
+                .as_stream()?
+                .groups
+                .get(group)
+                .ok_or_else(|| GroupError::no_group(key, group))?;
+            let Some((start, end, count, consumer)) = range else {
+                let id = |id: Option<&StreamId>| match id {
+                    Some(id) => DataType::BulkString(id.to_string().into()),
+                    None => DataType::Null,
+                };
+                let mut consumers: Vec<_> = group
+                    .consumers
+                    .iter()
+                    .filter(|(_, c)| !c.pending.is_empty())
+                    .collect();
+                consumers.sort_unstable_by_key(|&(name, _)| name);
+                let consumers = consumers
+                    .into_iter()
+                    .map(|(name, c)| {
+                        DataType::Array(vec![
+                            DataType::BulkString(name.clone()),
+                            DataType::BulkString(c.pending.len().to_string().into()),
+                        ])
+                    })
+                    .collect::<Vec<_>>();
+                return anyhow::Ok(DataType::Array(vec![
+                    DataType::Integer(group.pending.len() as i64),
+                    id(group.pending.keys().next()),
+                    id(group.pending.keys().next_back()),
+                    if consumers.is_empty() {
+                        DataType::Null
+                    } else {
+                        DataType::Array(consumers)
+                    },
+                ]));
+            };
+            if is_empty_range(start, end) {
+                return Ok(DataType::Array(Vec::new()));
+            }
+            let pending = group
+                .pending
+                .range((start, end))
+                .filter(|(_, p)| idle_ms(now, p.delivered_at) >= min_idle)
+                .filter(|(_, p)| consumer.map_or(true, |c| p.consumer == c))
+                .take(count)
+                .map(|(id, p)| {
+                    DataType::Array(vec![
+                        DataType::BulkString(id.to_string().into()),
+                        DataType::BulkString(p.consumer.clone()),
+                        DataType::Integer(idle_ms(now, p.delivered_at)),
+                        DataType::Integer(p.deliveries as i64),
+                    ])
+                })
+                .collect();
+            Ok(DataType::Array(pending))
+        })
+        .unwrap_or_else(|| Err(GroupError::no_group(key, group).into()))?;
+    protocol::send(stream, &reply).await
+}
+
+/// XCLAIM: gives the consumer those of the IDs that are pending in the
+/// group and have been idle for at least the given milliseconds, replying
+/// with their entries, or only their IDs if JUSTID. IDLE or TIME say when
+/// they count as delivered, RETRYCOUNT sets their delivery count, FORCE
+/// claims IDs that aren't pending at all, and LASTID moves the group's
+/// last delivered ID forward.
+pub async fn invoke_xclaim<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let args = bulk_args(args, "arguments")?;
+    let [key, group, consumer, min_idle, rest @ ..] = &args[..] else {
+        anyhow::bail!("wrong number of arguments for 'xclaim' command");
+    };
+    let min_idle = integer_value(min_idle)
+        .ok()
+        .context("Invalid min-idle-time argument for XCLAIM")?
+        .max(0);
+    let mut rest = rest.iter().peekable();
+    let mut ids = Vec::new();
+    while let Some(id) = rest.peek().and_then(|id| parse_id(id).ok()) {
+        ids.push(id);
+        rest.next();
+    }
+    anyhow::ensure!(
+        !ids.is_empty(),
+        "Invalid stream ID specified as stream command argument"
+    );
+    let (mut idle, mut retry_count, mut force, mut just_id, mut last_id) =
+        (None, None, false, false, None);
+    while let Some(option) = rest.next() {
+        match &option.to_ascii_uppercase()[..] {
+            b"IDLE" => {
+                let ms = integer_value(rest.next().context("syntax error")?)?;
+                idle = Some(ms.max(0) as u64);
+            }
+            b"TIME" => {
+                let at = integer_value(rest.next().context("syntax error")?)?;
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+                idle = Some(now.saturating_sub(at).max(0) as u64);
+            }
+            b"RETRYCOUNT" => {
+                let count = integer_value(rest.next().context("syntax error")?)?;
+                retry_count = Some(count.max(0) as u64);
+            }
+            b"FORCE" => force = true,
+            b"JUSTID" => just_id = true,
+            b"LASTID" => last_id = Some(parse_id(rest.next().context("syntax error")?)?),
+            _ => anyhow::bail!("Unrecognized XCLAIM option '{}'", option.escape_ascii()),
+        }
+    }
+    let claimed = store.update(key.clone(), |entry| {
+        let now = Instant::now();
+        let (entries, group) = stream_group(entry, key, group)?;
+        if let Some(last_id) = last_id {
+            group.last_delivered = group.last_delivered.max(last_id);
+        }
+        group.consumer(consumer, now);
+        let mut claimed = Vec::new();
+        for id in ids {
+            let idle_enough = match group.pending.get(&id) {
+                Some(pending) => idle_ms(now, pending.delivered_at) >= min_idle,
+                None => force && entries.contains_key(&id),
+            };
+            if !idle_enough {
+                continue;
+            }
+            let Some(fields) = entries.get(&id) else {
+                // the entry is gone, so it can no longer be processed
+                group.ack(id);
+                continue;
+            };
+            let delivered_at = delivered_ago(now, idle.unwrap_or(0));
+            let pending = group.assign(id, consumer, delivered_at);
+            match retry_count {
+                Some(count) => pending.deliveries = count,
+                None if !just_id => pending.deliveries += 1,
+                None => {}
+            }
+            claimed.push(if just_id {
+                DataType::BulkString(id.to_string().into())
+            } else {
+                entry_reply(id, fields)
+            });
+        }
+        anyhow::Ok(claimed)
+    })?;
+    protocol::send_array(stream, &claimed).await
+}
+
+/// XAUTOCLAIM: like XCLAIM for the pending entries from an ID on that have
+/// been idle long enough, up to COUNT (100 by default) of them. Replies
+/// with the ID to carry on from, 0-0 once all have been looked at, the
+/// claimed entries, and the IDs of pending entries found to be deleted,
+/// which are acknowledged.
+pub async fn invoke_xautoclaim<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let args = bulk_args(args, "arguments")?;
+    let [key, group, consumer, min_idle, start, rest @ ..] = &args[..] else {
+        anyhow::bail!("wrong number of arguments for 'xautoclaim' command");
+    };
+    let min_idle = integer_value(min_idle)
+        .ok()
+        .context("Invalid min-idle-time argument for XAUTOCLAIM")?
+        .max(0);
+    let start = range_bound(start, false)?;
+    let (mut count, mut just_id) = (100, false);
+    let mut rest = rest.iter();
+    while let Some(option) = rest.next() {
+        match &option.to_ascii_uppercase()[..] {
+            b"COUNT" => {
+                count = integer_value(rest.next().context("syntax error")?)?;
+                anyhow::ensure!(count > 0, "COUNT must be > 0");
+            }
+            b"JUSTID" => just_id = true,
+            _ => anyhow::bail!("syntax error"),
+        }
+    }
+    let reply = store.update(key.clone(), |entry| {
+        let now = Instant::now();
+        let (entries, group) = stream_group(entry, key, group)?;
+        group.consumer(consumer, now);
+        // as in redis, give up after looking at ten times as many as may
+        // be claimed
+        let mut candidates = group.pending.range((start, Bound::Unbounded));
+        let looked_at: Vec<_> = candidates
+            .by_ref()
+            .take((count as usize).saturating_mul(10))
+            .map(|(&id, pending)| (id, idle_ms(now, pending.delivered_at) >= min_idle))
+            .collect();
+        let mut next = candidates.next().map_or(StreamId::MIN, |(&id, _)| id);
+        let (mut claimed, mut deleted) = (Vec::new(), Vec::new());
+        for &(id, idle_enough) in &looked_at {
+            if claimed.len() == count as usize {
+                next = id;
+                break;
+            }
+            if !idle_enough {
+                continue;
+            }
+            let Some(fields) = entries.get(&id) else {
+                group.ack(id);
+                deleted.push(DataType::BulkString(id.to_string().into()));
+                continue;
+            };
+            let pending = group.assign(id, consumer, now);
+            if !just_id {
+                pending.deliveries += 1;
+            }
+            claimed.push(if just_id {
+                DataType::BulkString(id.to_string().into())
+            } else {
+                entry_reply(id, fields)
+            });
+        }
+        anyhow::Ok(DataType::Array(vec![
+            DataType::BulkString(next.to_string().into()),
+            DataType::Array(claimed),
+            DataType::Array(deleted),
+        ]))
+    })?;
+    protocol::send(stream, &reply).await
+}
+
+/// The ID a group's last delivered ID is set to: `$` for the last entry of
+/// the stream, or any ID.
+fn group_start(id: &[u8], target: &Stream) -> anyhow::Result<StreamId> {
+    match id {
+        b"$" => Ok(target.last_id),
+        id => parse_id(id),
+    }
+}
+
+/// XGROUP CREATE, SETID, DESTROY, CREATECONSUMER and DELCONSUMER, all of
+/// which need the stream to exist, unless CREATE is given MKSTREAM.
+pub async fn invoke_xgroup<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let args = bulk_args(args, "arguments")?;
+    let subcommand = args[0].to_ascii_uppercase();
+    if subcommand == b"HELP" {
+        let help = [
+            "XGROUP <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+            "CREATE <key> <groupname> <id|$> [option]",
+            "    Create a new consumer group. Options are:",
+            "    * MKSTREAM",
+            "      Create the empty stream if it does not exist.",
+            "CREATECONSUMER <key> <groupname> <consumer>",
+            "    Create a new consumer in the specified group.",
+            "DELCONSUMER <key> <groupname> <consumer>",
+            "    Remove the specified consumer.",
+            "DESTROY <key> <groupname>",
+            "    Remove the specified group.",
+            "SETID <key> <groupname> <id|$>",
+            "    Set the current group ID.",
+            "HELP",
+            "    Print this help.",
+        ];
+        let help: Vec<_> = help
+            .into_iter()
+            .map(|line| DataType::SimpleString(line.into()))
+            .collect();
+        return protocol::send_array(stream, &help).await;
+    }
+    let (key, name, rest) = match &args[1..] {
+        [key, name, rest @ ..] => (key, name, rest),
+        _ => anyhow::bail!(
+            "unknown subcommand or wrong number of arguments for '{}'. Try XGROUP HELP.",
+            subcommand.escape_ascii()
+        ),
+    };
+    let mkstream = match (&subcommand[..], rest) {
+        (b"CREATE", [_, option]) if option.eq_ignore_ascii_case(b"MKSTREAM") => true,
+        (b"CREATE" | b"SETID" | b"CREATECONSUMER" | b"DELCONSUMER", [_]) | (b"DESTROY", []) => {
+            false
+        }
+        _ => anyhow::bail!(
+            "unknown subcommand or wrong number of arguments for '{}'. Try XGROUP HELP.",
+            subcommand.escape_ascii()
+        ),
+    };
+    let reply = store.update(key.clone(), |entry| {
+        if entry.is_none() && !mkstream {
+            anyhow::bail!(
+                "The XGROUP subcommand requires the key to exist. Note that for CREATE you may \
+                 want to use the MKSTREAM option to create an empty stream automatically."
+            );
+        }
+        let target = stream_or_create(entry)?;
+        let reply = match &subcommand[..] {
+            b"CREATE" => {
+                let last_delivered = group_start(&rest[0], target)?;
+                anyhow::ensure!(!target.groups.contains_key(name), GroupError::Busy);
+                let group = ConsumerGroup {
+                    last_delivered,
+                    ..ConsumerGroup::default()
+                };
+                target.groups.insert(name.clone(), group);
+                DataType::SimpleString("OK".into())
+            }
+            b"SETID" => {
+                let last_delivered = group_start(&rest[0], target)?;
+                group_mut(target, key, name)?.last_delivered = last_delivered;
+                DataType::SimpleString("OK".into())
+            }
+            b"DESTROY" => DataType::Integer(target.groups.remove(name).is_some().into()),
+            b"CREATECONSUMER" => {
+                let group = group_mut(target, key, name)?;
+                let created = !group.consumers.contains_key(&rest[0]);
+                group.consumer(&rest[0], Instant::now());
+                DataType::Integer(created.into())
+            }
+            _ => {
+                let removed = group_mut(target, key, name)?.remove_consumer(&rest[0]);
+                DataType::Integer(removed.unwrap_or(0) as i64)
+            }
+        };
+        Ok(reply)
+    })?;
+    protocol::send(stream, &reply).await
+}
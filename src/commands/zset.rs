@@ -0,0 +1,602 @@
+//! Sorted set commands. A sorted set is created by the first ZADD to its
+//! key and removed once its last member has been removed.
+
+use std::{future::Future, ops::Bound};
+
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::io::{self, AsyncWrite};
+
+use super::{block_on, bulk_args, deadline_from, index_range, integer_value};
+use crate::{
+    protocol::{self, DataType},
+    store::{Store, StoreValue},
+    value::{SortedSet, Value, WrongType},
+};
+
+/// The sorted set at `entry`, which is created empty if the key doesn't
+/// exist.
+fn sorted_set_or_create(entry: &mut Option<StoreValue>) -> Result<&mut SortedSet, WrongType> {
+    entry
+        .get_or_insert_with(|| StoreValue::new(Value::SortedSet(SortedSet::default()), None))
+        .value
+        .as_sorted_set_mut()
+}
+
+/// Runs `f` on the sorted set at `key`, if there is one, without changing
+/// it.
+fn read_sorted_set<R>(
+    store: &Store,
+    key: &[u8],
+    f: impl FnOnce(&SortedSet) -> R,
+) -> Result<Option<R>, WrongType> {
+    store
+        .read(key, |entry| entry.value.as_sorted_set().map(f))
+        .transpose()
+}
+
+/// A score argument. Unlike other floats these may be infinite, but never
+/// NaN.
+fn score_value(score: &[u8]) -> anyhow::Result<f64> {
+    std::str::from_utf8(score)
+        .ok()
+        .and_then(|score| score.parse().ok())
+        .filter(|score: &f64| !score.is_nan())
+        .context("value is not a valid float")
+}
+
+/// Members, each followed by its score if `with_scores`. Under RESP3 each
+/// member and score are paired in an array of their own.
+fn members_reply<'a>(members: Vec<(Bytes, f64)>, with_scores: bool) -> Vec<DataType<'a>> {
+    if !with_scores {
+        return members
+            .into_iter()
+            .map(|(member, _)| DataType::BulkString(member))
+            .collect();
+    }
+    if protocol::protocol_version() >= 3 {
+        return members
+            .into_iter()
+            .map(|(member, score)| {
+                DataType::Array(vec![DataType::BulkString(member), DataType::Double(score)])
+            })
+            .collect();
+    }
+    members
+        .into_iter()
+        .flat_map(|(member, score)| [DataType::BulkString(member), DataType::Double(score)])
+        .collect()
+}
+
+/// The flags ZADD takes before its scores and members.
+#[derive(Debug, Default)]
+struct AddOptions {
+    /// Only add new members.
+    nx: bool,
+    /// Only update existing members.
+    xx: bool,
+    /// Only update a score to a greater one.
+    gt: bool,
+    /// Only update a score to a lesser one.
+    lt: bool,
+    /// Count updated members as well as added ones.
+    ch: bool,
+    /// Add to the score rather than replace it.
+    incr: bool,
+}
+
+impl AddOptions {
+    fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !(self.nx && self.xx),
+            "XX and NX options at the same time are not compatible"
+        );
+        anyhow::ensure!(
+            !(self.gt && self.lt) && !(self.nx && (self.gt || self.lt)),
+            "GT, LT, and/or NX options at the same time are not compatible"
+        );
+        Ok(())
+    }
+
+    /// Whether a member currently at `old`, if it is one, may be set to
+    /// `score`.
+    fn allows(&self, old: Option<f64>, score: f64) -> bool {
+        match old {
+            None => !self.xx,
+            Some(_) if self.nx => false,
+            Some(old) if self.gt => score > old,
+            Some(old) if self.lt => score < old,
+            Some(_) => true,
+        }
+    }
+}
+
+/// ZADD, replying with how many members were added, or also updated with
+/// CH. With INCR the score is added to instead, and the reply is the new
+/// score, or null if the other options kept it from changing.
+pub async fn invoke_zadd<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let mut options = AddOptions::default();
+    let mut args = args.peekable();
+    while let Some(DataType::BulkString(option)) = args.peek() {
+        match &option.to_ascii_uppercase()[..] {
+            b"NX" => options.nx = true,
+            b"XX" => options.xx = true,
+            b"GT" => options.gt = true,
+            b"LT" => options.lt = true,
+            b"CH" => options.ch = true,
+            b"INCR" => options.incr = true,
+            _ => break,
+        }
+        args.next();
+    }
+    options.check()?;
+    let pairs = bulk_args(args, "scores and members")?;
+    anyhow::ensure!(!pairs.is_empty() && pairs.len() % 2 == 0, "syntax error");
+    anyhow::ensure!(
+        !options.incr || pairs.len() == 2,
+        "INCR option supports a single increment-element pair"
+    );
+    let pairs = pairs
+        .chunks_exact(2)
+        .map(|pair| Ok((score_value(&pair[0])?, pair[1].clone())))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (changed, score) = store.update(k, |entry| {
+        // XX never creates the key, even to leave it empty
+        if options.xx && entry.is_none() {
+            return Ok((0, None));
+        }
+        let set = sorted_set_or_create(entry)?;
+        let mut changed = 0;
+        let mut last = None;
+        for (score, member) in pairs {
+            let old = set.score(&member);
+            let score = match old {
+                Some(old) if options.incr => old + score,
+                _ => score,
+            };
+            anyhow::ensure!(!score.is_nan(), "resulting score is not a number (NaN)");
+            if !options.allows(old, score) {
+                continue;
+            }
+            if old.is_none() || (options.ch && old != Some(score)) {
+                changed += 1;
+            }
+            set.insert(member, score);
+            last = Some(score);
+        }
+        Ok((changed, last))
+    })?;
+    if options.incr {
+        return match score {
+            Some(score) => protocol::send(stream, &DataType::Double(score)).await,
+            None => protocol::send_null(stream).await,
+        };
+    }
+    protocol::send(stream, &DataType::Integer(changed)).await
+}
+
+pub async fn invoke_zscore<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(member))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and member must be bulk strings");
+    };
+    match read_sorted_set(store, &k, |set| set.score(&member))?.flatten() {
+        Some(score) => protocol::send(stream, &DataType::Double(score)).await,
+        None => protocol::send_null(stream).await,
+    }
+}
+
+pub async fn invoke_zcard<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let len = read_sorted_set(store, &k, SortedSet::len)?.unwrap_or(0);
+    protocol::send(stream, &DataType::Integer(len as i64)).await
+}
+
+/// ZRANK, replying with the position of a member counting from the lowest
+/// score, and with WITHSCORE, its score too.
+pub async fn invoke_zrank<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (Some(DataType::BulkString(k)), Some(DataType::BulkString(member))) =
+        (args.next(), args.next())
+    else {
+        anyhow::bail!("key and member must be bulk strings");
+    };
+    let with_score = match args.next() {
+        Some(DataType::BulkString(option)) if option.eq_ignore_ascii_case(b"WITHSCORE") => true,
+        None => false,
+        _ => anyhow::bail!("syntax error"),
+    };
+    let ranked = read_sorted_set(store, &k, |set| {
+        Some((set.rank(&member)?, set.score(&member)?))
+    })?
+    .flatten();
+    match ranked {
+        Some((rank, _)) if !with_score => {
+            protocol::send(stream, &DataType::Integer(rank as i64)).await
+        }
+        Some((rank, score)) => {
+            let reply = [DataType::Integer(rank as i64), DataType::Double(score)];
+            protocol::send_array(stream, &reply).await
+        }
+        None if with_score => protocol::send_null_array(stream).await,
+        None => protocol::send_null(stream).await,
+    }
+}
+
+/// What the bounds of a range of members are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeBy {
+    Rank,
+    Score,
+    Lex,
+}
+
+/// A score bound of a range, with a leading `(` making it exclusive.
+fn score_bound(bound: &[u8]) -> anyhow::Result<Bound<f64>> {
+    let (exclusive, score) = match bound {
+        [b'(', score @ ..] => (true, score),
+        _ => (false, bound),
+    };
+    let score = score_value(score)
+        .ok()
+        .context("min or max is not a float")?;
+    Ok(if exclusive {
+        Bound::Excluded(score)
+    } else {
+        Bound::Included(score)
+    })
+}
+
+/// A bound of a range of members in lexicographical order, as given to
+/// ZRANGEBYLEX: `-` or `+` for either end of the order, or a member after
+/// `[` to include it or `(` to exclude it.
+#[derive(Debug)]
+enum LexBound {
+    Min,
+    Max,
+    Included(Bytes),
+    Excluded(Bytes),
+}
+
+impl LexBound {
+    fn parse(bound: &Bytes) -> anyhow::Result<Self> {
+        match &bound[..] {
+            b"-" => Ok(LexBound::Min),
+            b"+" => Ok(LexBound::Max),
+            [b'[', ..] => Ok(LexBound::Included(bound.slice(1..))),
+            [b'(', ..] => Ok(LexBound::Excluded(bound.slice(1..))),
+            _ => anyhow::bail!("min or max not valid string range item"),
+        }
+    }
+
+    /// Whether `member` comes before a range starting at this bound.
+    fn is_after(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Included(bound) => member < &bound[..],
+            LexBound::Excluded(bound) => member <= &bound[..],
+        }
+    }
+
+    /// Whether `member` comes after a range ending at this bound.
+    fn is_before(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Included(bound) => member > &bound[..],
+            LexBound::Excluded(bound) => member >= &bound[..],
+        }
+    }
+}
+
+/// Members between `min` and `max` in lexicographical order, which is the
+/// order of the set as long as all scores are the same, as they should be.
+fn range_by_lex<'s>(
+    set: &'s SortedSet,
+    min: &'s LexBound,
+    max: &'s LexBound,
+    rev: bool,
+) -> Box<dyn Iterator<Item = (&'s Bytes, f64)> + 's> {
+    if rev {
+        Box::new(
+            set.iter()
+                .rev()
+                .skip_while(|(member, _)| max.is_before(member))
+                .take_while(|(member, _)| !min.is_after(member)),
+        )
+    } else {
+        Box::new(
+            set.iter()
+                .skip_while(|(member, _)| min.is_after(member))
+                .take_while(|(member, _)| !max.is_before(member)),
+        )
+    }
+}
+
+/// ZRANGE, ZRANGEBYSCORE and ZRANGEBYLEX. ZRANGE takes ranks unless given
+/// BYSCORE or BYLEX, and with REV counts from the highest score, taking
+/// score or lex bounds highest first. LIMIT skips and caps the members of a
+/// score or lex range.
+pub async fn invoke_zrange<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    mut by: RangeBy,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(start)),
+        Some(DataType::BulkString(stop)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, start and stop must be bulk strings");
+    };
+    // only ZRANGE itself takes the options choosing what the range is
+    let zrange = by == RangeBy::Rank;
+    let (mut rev, mut with_scores, mut limit) = (false, false, None);
+    while let Some(DataType::BulkString(option)) = args.next() {
+        match &option.to_ascii_uppercase()[..] {
+            b"WITHSCORES" => with_scores = true,
+            b"REV" if zrange => rev = true,
+            b"BYSCORE" if zrange => by = RangeBy::Score,
+            b"BYLEX" if zrange => by = RangeBy::Lex,
+            b"LIMIT" => {
+                let (Some(DataType::BulkString(offset)), Some(DataType::BulkString(count))) =
+                    (args.next(), args.next())
+                else {
+                    anyhow::bail!("syntax error");
+                };
+                limit = Some((integer_value(&offset)?, integer_value(&count)?));
+            }
+            _ => anyhow::bail!("syntax error"),
+        }
+    }
+    anyhow::ensure!(
+        limit.is_none() || by != RangeBy::Rank,
+        "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+    );
+    anyhow::ensure!(
+        !with_scores || by != RangeBy::Lex,
+        "syntax error, WITHSCORES not supported in combination with BYLEX"
+    );
+    let (min, max) = match by {
+        RangeBy::Score | RangeBy::Lex if rev => (stop, start),
+        _ => (start, stop),
+    };
+    // a negative offset leaves nothing, a negative count no cap
+    let (offset, count) = limit.unwrap_or((0, -1));
+    let skip = usize::try_from(offset).unwrap_or(usize::MAX);
+    let take = usize::try_from(count).unwrap_or(usize::MAX);
+    let collect = |members: Box<dyn Iterator<Item = (&Bytes, f64)> + '_>| {
+        members
+            .skip(skip)
+            .take(take)
+            .map(|(member, score)| (member.clone(), score))
+            .collect::<Vec<_>>()
+    };
+    let members = match by {
+        RangeBy::Rank => {
+            let (start, stop) = (integer_value(&min)?, integer_value(&max)?);
+            read_sorted_set(store, &k, |set| {
+                let range = index_range(set.len(), start, stop);
+                let members = set.iter().map(|(member, score)| (member.clone(), score));
+                if rev {
+                    members.rev().skip(range.start).take(range.len()).collect()
+                } else {
+                    members.skip(range.start).take(range.len()).collect()
+                }
+            })?
+        }
+        RangeBy::Score => {
+            let (min, max) = (score_bound(&min)?, score_bound(&max)?);
+            read_sorted_set(store, &k, |set| collect(set.range_by_score(min, max, rev)))?
+        }
+        RangeBy::Lex => {
+            let (min, max) = (LexBound::parse(&min)?, LexBound::parse(&max)?);
+            read_sorted_set(store, &k, |set| collect(range_by_lex(set, &min, &max, rev)))?
+        }
+    };
+    let members = members.unwrap_or_default();
+    protocol::send_array(stream, &members_reply(members, with_scores)).await
+}
+
+/// ZCOUNT: how many members have scores in a range.
+pub async fn invoke_zcount<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(min)),
+        Some(DataType::BulkString(max)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, min and max must be bulk strings");
+    };
+    let (min, max) = (score_bound(&min)?, score_bound(&max)?);
+    let count =
+        read_sorted_set(store, &k, |set| set.range_by_score(min, max, false).count())?.unwrap_or(0);
+    protocol::send(stream, &DataType::Integer(count as i64)).await
+}
+
+/// ZINCRBY, replying with the new score of the member, which is added with
+/// the increment as its score if missing.
+pub async fn invoke_zincrby<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(by)),
+        Some(DataType::BulkString(member)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, increment and member must be bulk strings");
+    };
+    let by = score_value(&by)?;
+    let score = store.update(k, |entry| {
+        let set = sorted_set_or_create(entry)?;
+        let score = set.score(&member).unwrap_or(0.0) + by;
+        anyhow::ensure!(!score.is_nan(), "resulting score is not a number (NaN)");
+        set.insert(member, score);
+        Ok(score)
+    })?;
+    protocol::send(stream, &DataType::Double(score)).await
+}
+
+/// ZREM, replying with how many of the members were in the set.
+pub async fn invoke_zrem<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let members = bulk_args(args, "members")?;
+    let removed = store.update(k, |entry| {
+        let Some(entry) = entry else {
+            return Ok(0);
+        };
+        let set = entry.value.as_sorted_set_mut()?;
+        let removed = members.iter().filter(|m| set.remove(m).is_some()).count();
+        Ok::<_, WrongType>(removed as i64)
+    })?;
+    protocol::send(stream, &DataType::Integer(removed)).await
+}
+
+/// ZREMRANGEBYSCORE, replying with how many members it removed.
+pub async fn invoke_zremrangebyscore<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+) -> anyhow::Result<()> {
+    let (
+        Some(DataType::BulkString(k)),
+        Some(DataType::BulkString(min)),
+        Some(DataType::BulkString(max)),
+    ) = (args.next(), args.next(), args.next())
+    else {
+        anyhow::bail!("key, min and max must be bulk strings");
+    };
+    let (min, max) = (score_bound(&min)?, score_bound(&max)?);
+    let removed = store.update(k, |entry| {
+        let Some(entry) = entry else {
+            return Ok(0);
+        };
+        let set = entry.value.as_sorted_set_mut()?;
+        let doomed: Vec<_> = set
+            .range_by_score(min, max, false)
+            .map(|(member, _)| member.clone())
+            .collect();
+        for member in &doomed {
+            set.remove(member);
+        }
+        Ok::<_, WrongType>(doomed.len() as i64)
+    })?;
+    protocol::send(stream, &DataType::Integer(removed)).await
+}
+
+/// Pops up to `count` members with the highest scores if `max`, otherwise
+/// the lowest.
+fn pop(store: &Store, key: Bytes, count: usize, max: bool) -> Result<Vec<(Bytes, f64)>, WrongType> {
+    store.update(key, |entry| {
+        let Some(entry) = entry else {
+            return Ok(Vec::new());
+        };
+        let set = entry.value.as_sorted_set_mut()?;
+        Ok((0..count).map_while(|_| set.pop(max)).collect())
+    })
+}
+
+/// ZPOPMIN and ZPOPMAX: the member with the lowest or highest score and its
+/// score, or with a count, up to that many.
+pub async fn invoke_zpop<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    max: bool,
+) -> anyhow::Result<()> {
+    let Some(DataType::BulkString(k)) = args.next() else {
+        anyhow::bail!("key must be given!");
+    };
+    let count = match args.next() {
+        Some(DataType::BulkString(count)) => {
+            let count = integer_value(&count)?;
+            anyhow::ensure!(count >= 0, "value is out of range, must be positive");
+            Some(count as usize)
+        }
+        _ => None,
+    };
+    let popped = pop(store, k, count.unwrap_or(1), max)?;
+    let reply = match count {
+        Some(_) => members_reply(popped, true),
+        // a lone member and its score stay a flat pair even under RESP3
+        None => popped
+            .into_iter()
+            .flat_map(|(member, score)| [DataType::BulkString(member), DataType::Double(score)])
+            .collect(),
+    };
+    protocol::send_array(stream, &reply).await
+}
+
+/// BZPOPMIN and BZPOPMAX: pops from the first of the keys holding a
+/// non-empty sorted set, waiting for one to be added to if none does, and
+/// replies with the key, member and score.
+pub async fn invoke_blocking_zpop<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    closed: impl Future<Output = io::Result<()>>,
+    args: impl Iterator<Item = DataType<'a>>,
+    store: &Store,
+    max: bool,
+) -> anyhow::Result<()> {
+    let mut keys = bulk_args(args, "keys and timeout")?;
+    let Some(timeout) = keys.pop() else {
+        anyhow::bail!("timeout must be given!");
+    };
+    let deadline = deadline_from(&timeout)?;
+    let popped = block_on(stream, closed, store, &keys, deadline, || {
+        for key in &keys {
+            if let Some((member, score)) = pop(store, key.clone(), 1, max)?.pop() {
+                return Ok(Some((key.clone(), member, score)));
+            }
+        }
+        Ok(None)
+    })
+    .await?;
+    match popped {
+        Some((key, member, score)) => {
+            let reply = [
+                DataType::BulkString(key),
+                DataType::BulkString(member),
+                DataType::Double(score),
+            ];
+            protocol::send_array(stream, &reply).await
+        }
+        None => protocol::send_null_array(stream).await,
+    }
+}
@@ -0,0 +1,634 @@
+//! Server configuration: defaults, an optional redis.conf-style file and
+//! `--directive value` overrides on the command line, like redis-server.
+
+use std::{
+    collections::HashMap,
+    env, fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::{atomic::AtomicUsize, Arc, Mutex, RwLock, RwLockReadGuard},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::sync::watch;
+
+use crate::{
+    audit, clients, commands, protocol,
+    replication::{self, ConnectedReplica, ReplicaState},
+    stats,
+};
+
+const DEFAULT_PORT: &str = "6379";
+
+const USAGE: &str = "\
+Usage: redis-starter-rust [/path/to/redis.conf] [--directive value ...]
+       redis-starter-rust bench [-h host] [-p port] [-c clients] [-n requests] [-P pipeline] [-t tests]
+       redis-starter-rust --version
+       redis-starter-rust --help
+
+Examples:
+       redis-starter-rust --port 7777
+       redis-starter-rust --replicaof 127.0.0.1 8888
+       redis-starter-rust /etc/redis/6379.conf --dir /tmp";
+
+#[derive(Debug)]
+pub struct ReplicaOf {
+    pub master_host: IpAddr,
+    pub master_port: u16,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub port: String,
+    pub replica_of: Option<ReplicaOf>,
+    pub replication_id: String,
+    pub replication_offset: u32,
+    /// Only meaningful when `replica_of` is set.
+    pub replica_state: Mutex<ReplicaState>,
+    /// Address a replica reports to its master instead of its own.
+    pub replica_announce_ip: Option<String>,
+    pub replica_announce_port: Option<u16>,
+    /// Replicas attached to this master, by connection.
+    pub replicas: Mutex<HashMap<SocketAddr, ConnectedReplica>>,
+    pub dir: String,
+    pub dbfilename: String,
+    pub tcp_backlog: u32,
+    pub tcp_nodelay: bool,
+    /// Set SO_REUSEPORT so several processes can share the listening port.
+    pub reuseport: bool,
+    /// Extra listener for RESP over WebSocket (see the `websocket` module).
+    pub websocket_port: Option<u16>,
+    /// Worker threads serving connections; one per core when unset.
+    pub io_threads: Option<usize>,
+    /// Original command name -> name clients must use ("" disables it).
+    pub renamed_commands: HashMap<String, String>,
+    /// Keyspace dump (see `DEBUG DUMP-JSON`) to import at startup.
+    pub load_json: Option<String>,
+    /// Where write commands are logged (see the `audit` module).
+    pub audit_log: Option<String>,
+    /// Opened from `audit_log` at startup.
+    pub audit: Option<audit::AuditLog>,
+    pub tunables: RwLock<Tunables>,
+    /// Where the configuration came from, so it can be reloaded.
+    pub sources: Sources,
+    /// Flipped to `true` to stop accepting and drain connections.
+    pub shutdown: watch::Sender<bool>,
+    pub faults: Faults,
+    pub stats: Arc<stats::Stats>,
+    pub clients: clients::Clients,
+}
+
+/// Directives that can change while the server runs. They are swapped as a
+/// whole on reload, so readers never see a half-applied configuration.
+#[derive(Debug, Clone)]
+pub struct Tunables {
+    /// Whether a replica answers data commands before its first full sync.
+    pub replica_serve_stale_data: bool,
+    pub limits: protocol::Limits,
+    pub enable_debug_command: EnableCommand,
+    pub trace_protocol: bool,
+    /// Trace one in this many connections.
+    pub trace_sample: u64,
+    /// Only trace these commands (all when empty).
+    pub trace_commands: Vec<String>,
+    /// How long a shutdown waits for connections to finish their commands.
+    pub shutdown_timeout: Duration,
+    /// Reject write commands, regardless of role.
+    pub read_only: bool,
+    /// Bytes all client buffers together may hold before the biggest
+    /// clients are evicted; 0 for no limit.
+    pub maxmemory_clients: usize,
+    /// Lower is preferred for promotion by failover tooling; 0 means never.
+    pub replica_priority: u32,
+    /// How many times a second background tasks such as active expiry run.
+    pub hz: u32,
+}
+
+impl Default for Tunables {
+    fn default() -> Self {
+        Self {
+            replica_serve_stale_data: true,
+            limits: protocol::Limits::default(),
+            enable_debug_command: EnableCommand::No,
+            trace_protocol: false,
+            trace_sample: 1,
+            trace_commands: Vec::new(),
+            shutdown_timeout: Duration::from_secs(10),
+            read_only: false,
+            maxmemory_clients: 0,
+            replica_priority: replication::DEFAULT_PRIORITY,
+            hz: 10,
+        }
+    }
+}
+
+/// Directives picked up again on SIGHUP and settable with `CONFIG SET`.
+const RELOADABLE: [&str; 13] = [
+    "replica-serve-stale-data",
+    "proto-max-bulk-len",
+    "proto-max-multibulk-len",
+    "proto-max-inline-len",
+    "enable-debug-command",
+    "trace-protocol",
+    "trace-sample",
+    "trace-commands",
+    "shutdown-timeout",
+    "read-only",
+    "maxmemory-clients",
+    "replica-priority",
+    "hz",
+];
+
+/// Directives that are only read at startup.
+const RESTART_ONLY: [&str; 12] = [
+    "port",
+    "replicaof",
+    "replica-announce-ip",
+    "replica-announce-port",
+    "dir",
+    "dbfilename",
+    "tcp-backlog",
+    "tcp-nodelay",
+    "reuseport",
+    "io-threads",
+    "audit-log",
+    "websocket-port",
+];
+
+/// The config file and command-line overrides the server was started with.
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    pub file: Option<String>,
+    /// `--name value...` pairs, in command-line order.
+    pub overrides: Vec<(String, Vec<String>)>,
+}
+
+/// Knobs armed through `DEBUG` to make failure paths reproducible.
+#[derive(Debug, Default)]
+pub struct Faults {
+    /// Cut the next RDB transfer short after this many bytes (0 = disarmed).
+    pub truncate_rdb: AtomicUsize,
+}
+
+/// Gate for sensitive commands, mirroring redis' `enable-*-command` options.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnableCommand {
+    No,
+    Yes,
+    Local,
+}
+
+impl EnableCommand {
+    pub fn allows(self, peer: &SocketAddr) -> bool {
+        match self {
+            EnableCommand::No => false,
+            EnableCommand::Yes => true,
+            EnableCommand::Local => peer.ip().is_loopback(),
+        }
+    }
+}
+
+impl fmt::Display for EnableCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EnableCommand::No => "no",
+            EnableCommand::Yes => "yes",
+            EnableCommand::Local => "local",
+        })
+    }
+}
+
+impl FromStr for EnableCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "no" => Ok(EnableCommand::No),
+            "yes" => Ok(EnableCommand::Yes),
+            "local" => Ok(EnableCommand::Local),
+            other => anyhow::bail!("{other} is not one of yes, no or local"),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT.to_string(),
+            replica_of: None,
+            replication_id: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
+            replication_offset: 0,
+            replica_state: Mutex::new(ReplicaState::Connect),
+            replica_announce_ip: None,
+            replica_announce_port: None,
+            replicas: Mutex::new(HashMap::new()),
+            dir: env::current_dir()
+                .map(|d| d.display().to_string())
+                .unwrap_or_else(|_| ".".to_string()),
+            dbfilename: "dump.rdb".to_string(),
+            tcp_backlog: 511,
+            tcp_nodelay: true,
+            reuseport: false,
+            io_threads: None,
+            renamed_commands: HashMap::new(),
+            load_json: None,
+            audit_log: None,
+            websocket_port: None,
+            audit: None,
+            tunables: RwLock::new(Tunables::default()),
+            sources: Sources::default(),
+            shutdown: watch::channel(false).0,
+            faults: Faults::default(),
+            stats: Arc::default(),
+            clients: clients::Clients::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from the command line (without the program
+    /// name). `--help` and `--version` print and exit the process.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut sources = Sources::default();
+        let mut args = args.into_iter().peekable();
+
+        match args.peek().map(String::as_str) {
+            Some("-h" | "--help") => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            Some("-v" | "--version") => {
+                println!("redis-starter-rust v={}", env!("CARGO_PKG_VERSION"));
+                std::process::exit(0);
+            }
+            _ => {}
+        }
+        sources.file = args.next_if(|a| !a.starts_with("--"));
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                anyhow::bail!("unexpected argument '{arg}'\n\n{USAGE}");
+            };
+            let mut values = Vec::new();
+            while let Some(value) = args.next_if(|a| !a.starts_with("--")) {
+                values.push(value);
+            }
+            sources.overrides.push((name.to_string(), values));
+        }
+        Config::build(sources)
+    }
+
+    fn build(sources: Sources) -> anyhow::Result<Self> {
+        let mut config = Config::default();
+        if let Some(path) = &sources.file {
+            config
+                .load_file(path)
+                .with_context(|| format!("failed to load config file {path}"))?;
+        }
+        for (name, values) in &sources.overrides {
+            config
+                .apply(name, values)
+                .with_context(|| format!("invalid option --{name}"))?;
+        }
+        config.sources = sources;
+        Ok(config)
+    }
+
+    /// Re-reads the config file and command-line overrides, applying the
+    /// directives that can change at runtime in one step.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let fresh = Config::build(self.sources.clone())?;
+        for name in RELOADABLE {
+            let (old, new) = (self.get(name), fresh.get(name));
+            if old != new {
+                eprintln!(
+                    "config reload: {name} changed from {:?} to {:?}",
+                    old.unwrap_or_default(),
+                    new.unwrap_or_default()
+                );
+            }
+        }
+        for name in RESTART_ONLY {
+            if self.get(name) != fresh.get(name) {
+                eprintln!("config reload: {name} changed, but only takes effect on restart");
+            }
+        }
+        *self.tunables.write().unwrap() = fresh.tunables.into_inner().unwrap();
+        Ok(())
+    }
+
+    /// Applies `CONFIG SET` name/value pairs: all of them or, if any is
+    /// invalid, none.
+    pub fn set(&self, pairs: &[(String, String)]) -> anyhow::Result<()> {
+        let mut scratch = Config {
+            tunables: RwLock::new(self.tunables().clone()),
+            ..Config::default()
+        };
+        for (name, value) in pairs {
+            let name = name.to_ascii_lowercase();
+            anyhow::ensure!(
+                RELOADABLE.contains(&name.as_str()),
+                "Unsupported CONFIG parameter: {name}"
+            );
+            scratch
+                .apply(&name, std::slice::from_ref(value))
+                .with_context(|| format!("Invalid argument '{value}' for CONFIG SET '{name}'"))?;
+        }
+        *self.tunables.write().unwrap() = scratch.tunables.into_inner().unwrap();
+        Ok(())
+    }
+
+    pub fn tunables(&self) -> RwLockReadGuard<'_, Tunables> {
+        self.tunables.read().unwrap()
+    }
+
+    fn tunables_mut(&mut self) -> &mut Tunables {
+        self.tunables.get_mut().unwrap()
+    }
+
+    fn load_file(&mut self, path: &str) -> anyhow::Result<()> {
+        let contents = std::fs::read_to_string(path)?;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words = split_args(line).with_context(|| format!("line {}", number + 1))?;
+            self.apply(&words[0], &words[1..])
+                .with_context(|| format!("line {}: {line}", number + 1))?;
+        }
+        Ok(())
+    }
+
+    /// Applies one configuration directive, as found in a config file or
+    /// given as `--name value...`.
+    pub fn apply(&mut self, name: &str, values: &[String]) -> anyhow::Result<()> {
+        let single = || match values {
+            [value] => Ok(value.as_str()),
+            _ => Err(anyhow::anyhow!(
+                "wrong number of arguments: expected 1, got {}",
+                values.len()
+            )),
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "port" => {
+                let port: u16 = single()?.parse().context("port must be 0-65535")?;
+                self.port = port.to_string();
+            }
+            "replicaof" | "slaveof" => {
+                // accepts both `host port` and the quoted form `"host port"`
+                let words: Vec<&str> = values.iter().flat_map(|v| v.split_whitespace()).collect();
+                self.replica_of = match words[..] {
+                    [no, one]
+                        if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") =>
+                    {
+                        None
+                    }
+                    [host, port] => {
+                        let host = if host == "localhost" {
+                            "127.0.0.1"
+                        } else {
+                            host
+                        };
+                        Some(ReplicaOf {
+                            master_host: IpAddr::V4(
+                                Ipv4Addr::from_str(host)
+                                    .with_context(|| format!("{host} is not an IPv4 address"))?,
+                            ),
+                            master_port: port.parse().context("port must be 0-65535")?,
+                        })
+                    }
+                    _ => anyhow::bail!("expected <host> <port> or 'no one'"),
+                };
+            }
+            "replica-serve-stale-data" | "slave-serve-stale-data" => {
+                self.tunables_mut().replica_serve_stale_data = parse_bool(single()?)?
+            }
+            "replica-announce-ip" | "slave-announce-ip" => {
+                self.replica_announce_ip = Some(single()?.to_string())
+            }
+            "replica-announce-port" | "slave-announce-port" => {
+                self.replica_announce_port =
+                    Some(single()?.parse().context("port must be 0-65535")?)
+            }
+            "dir" => self.dir = single()?.to_string(),
+            "dbfilename" => self.dbfilename = single()?.to_string(),
+            "tcp-backlog" => self.tcp_backlog = single()?.parse().context("not a valid count")?,
+            "tcp-nodelay" => self.tcp_nodelay = parse_bool(single()?)?,
+            "reuseport" => self.reuseport = parse_bool(single()?)?,
+            "io-threads" => {
+                let threads: usize = single()?.parse().context("not a valid count")?;
+                anyhow::ensure!(threads > 0, "io-threads must be at least 1");
+                self.io_threads = Some(threads);
+            }
+            "proto-max-bulk-len" => {
+                self.tunables_mut().limits.max_bulk_len = parse_memory(single()?)?
+            }
+            "proto-max-multibulk-len" => {
+                self.tunables_mut().limits.max_multibulk_len =
+                    single()?.parse().context("not a valid count")?
+            }
+            "proto-max-inline-len" => {
+                self.tunables_mut().limits.max_inline_len = parse_memory(single()?)?
+            }
+            "rename-command" => {
+                let [command, renamed] = values else {
+                    anyhow::bail!("expected <command> <new-name>");
+                };
+                self.renamed_commands
+                    .insert(command.to_ascii_uppercase(), renamed.to_ascii_uppercase());
+            }
+            "enable-debug-command" => {
+                self.tunables_mut().enable_debug_command = single()?.parse()?
+            }
+            "trace-protocol" => {
+                // a bare --trace-protocol switches it on
+                self.tunables_mut().trace_protocol = values.is_empty() || parse_bool(single()?)?;
+            }
+            "trace-sample" => {
+                self.tunables_mut().trace_sample = single()?
+                    .parse::<u64>()
+                    .context("not a valid count")?
+                    .max(1)
+            }
+            "trace-commands" => {
+                self.tunables_mut().trace_commands = single()?
+                    .split(',')
+                    .map(|c| c.trim().to_ascii_uppercase())
+                    .collect();
+            }
+            "read-only" => self.tunables_mut().read_only = parse_bool(single()?)?,
+            "maxmemory-clients" => self.tunables_mut().maxmemory_clients = parse_memory(single()?)?,
+            "replica-priority" | "slave-priority" => {
+                self.tunables_mut().replica_priority =
+                    single()?.parse().context("not a valid priority")?
+            }
+            "hz" => {
+                // out-of-range values are clamped, as redis does
+                self.tunables_mut().hz = single()?
+                    .parse::<u32>()
+                    .context("not a valid frequency")?
+                    .clamp(1, 500)
+            }
+            "load-json" => self.load_json = Some(single()?.to_string()),
+            "audit-log" => self.audit_log = Some(single()?.to_string()),
+            "websocket-port" => {
+                let port = single()?.parse().context("not a valid port")?;
+                // 0 disables it, like port 0 does for redis' TLS listener
+                self.websocket_port = (port != 0).then_some(port);
+            }
+            "shutdown-timeout" => {
+                let secs = single()?.parse().context("not a valid number of seconds")?;
+                self.tunables_mut().shutdown_timeout = Duration::from_secs(secs);
+            }
+            other => anyhow::bail!("Bad directive or wrong number of arguments: '{other}'"),
+        }
+        Ok(())
+    }
+
+    /// Current value of a directive, for `CONFIG GET`.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let tunables = self.tunables();
+        let value = match name.to_ascii_lowercase().as_str() {
+            "port" => self.port.clone(),
+            "replicaof" | "slaveof" => match &self.replica_of {
+                Some(r) => format!("{} {}", r.master_host, r.master_port),
+                None => String::new(),
+            },
+            "replica-serve-stale-data" | "slave-serve-stale-data" => {
+                yes_no(tunables.replica_serve_stale_data)
+            }
+            "replica-announce-ip" | "slave-announce-ip" => {
+                self.replica_announce_ip.clone().unwrap_or_default()
+            }
+            "replica-announce-port" | "slave-announce-port" => {
+                self.replica_announce_port.unwrap_or(0).to_string()
+            }
+            "dir" => self.dir.clone(),
+            "dbfilename" => self.dbfilename.clone(),
+            "tcp-backlog" => self.tcp_backlog.to_string(),
+            "tcp-nodelay" => yes_no(self.tcp_nodelay),
+            "reuseport" => yes_no(self.reuseport),
+            "io-threads" => self
+                .io_threads
+                .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+                .to_string(),
+            "proto-max-bulk-len" => tunables.limits.max_bulk_len.to_string(),
+            "proto-max-multibulk-len" => tunables.limits.max_multibulk_len.to_string(),
+            "proto-max-inline-len" => tunables.limits.max_inline_len.to_string(),
+            "enable-debug-command" => tunables.enable_debug_command.to_string(),
+            "trace-protocol" => yes_no(tunables.trace_protocol),
+            "trace-sample" => tunables.trace_sample.to_string(),
+            "trace-commands" => tunables.trace_commands.join(","),
+            "shutdown-timeout" => tunables.shutdown_timeout.as_secs().to_string(),
+            "audit-log" => self.audit_log.clone().unwrap_or_default(),
+            "read-only" => yes_no(tunables.read_only),
+            "maxmemory-clients" => tunables.maxmemory_clients.to_string(),
+            "replica-priority" | "slave-priority" => tunables.replica_priority.to_string(),
+            "hz" => tunables.hz.to_string(),
+            "websocket-port" => self.websocket_port.unwrap_or(0).to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub fn replica_state(&self) -> ReplicaState {
+        *self.replica_state.lock().unwrap()
+    }
+
+    pub fn set_replica_state(&self, state: ReplicaState) {
+        *self.replica_state.lock().unwrap() = state;
+    }
+
+    /// False for a replica that is out of sync with its master and configured
+    /// not to serve stale data.
+    pub fn serving_data(&self) -> bool {
+        self.replica_of.is_none()
+            || self.tunables().replica_serve_stale_data
+            || self.replica_state() == ReplicaState::Connected
+    }
+
+    /// Maps the name a client sent to the command it should run, honouring
+    /// `rename-command`; `None` means the name is unknown or disabled.
+    pub fn resolve_command(&self, name: &str) -> Option<&'static dyn commands::Command> {
+        if self.renamed_commands.is_empty() {
+            return commands::lookup(name);
+        }
+        if let Some((original, _)) = self
+            .renamed_commands
+            .iter()
+            .find(|(_, renamed)| !renamed.is_empty() && renamed.eq_ignore_ascii_case(name))
+        {
+            return commands::lookup(original);
+        }
+        commands::lookup(name).filter(|command| !self.renamed_commands.contains_key(command.name()))
+    }
+}
+
+fn parse_bool(value: &str) -> anyhow::Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        other => anyhow::bail!("{other} is not one of yes or no"),
+    }
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+/// Parses a size with an optional redis-style unit (`kb`, `mb`, `gb`, ...).
+fn parse_memory(value: &str) -> anyhow::Result<usize> {
+    let lower = value.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        unit => anyhow::bail!("unknown unit '{unit}' in {value}"),
+    };
+    let n: usize = digits
+        .parse()
+        .with_context(|| format!("{value} is not a valid size"))?;
+    n.checked_mul(multiplier)
+        .with_context(|| format!("{value} is too large"))
+}
+
+/// Splits a line into words, honouring double-quoted arguments with
+/// backslash escapes (like redis' `sdssplitargs`).
+pub fn split_args(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Ok(words);
+        };
+        let mut word = String::new();
+        if first == '"' {
+            loop {
+                match chars.next().context("unbalanced quotes")? {
+                    '"' => break,
+                    '\\' => match chars.next().context("unbalanced quotes")? {
+                        'n' => word.push('\n'),
+                        'r' => word.push('\r'),
+                        't' => word.push('\t'),
+                        c => word.push(c),
+                    },
+                    c => word.push(c),
+                }
+            }
+        } else {
+            word.push(first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+}
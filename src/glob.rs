@@ -0,0 +1,134 @@
+//! Glob-style patterns as redis matches keys against them: `*` matches any
+//! run of bytes, `?` any one byte, `[abc]`, `[a-z]` and `[^abc]` one byte in
+//! (or not in) a set, and `\` makes the next byte literal.
+
+/// Whether the whole of `text` matches `pattern`.
+pub fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // after a mismatch, the last `*` is made to swallow one more byte; it is
+    // never worth going back to an earlier one
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+            continue;
+        }
+        let Some((after_star, swallowed)) = star else {
+            return false;
+        };
+        p = after_star;
+        t = swallowed + 1;
+        star = Some((after_star, t));
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// If the token `pattern` starts with, which isn't a `*`, matches `c`, the
+/// length of that token. An exhausted pattern matches nothing.
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern {
+        [b'?', ..] => Some(1),
+        [b'\\', escaped, ..] => (*escaped == c).then_some(2),
+        [b'[', class @ ..] => {
+            let (negated, class) = match class {
+                [b'^', rest @ ..] => (true, rest),
+                _ => (false, class),
+            };
+            let (matched, len) = match_class(class, c);
+            (matched != negated).then_some(len + 1 + usize::from(negated))
+        }
+        [literal, ..] => (*literal == c).then_some(1),
+        [] => None,
+    }
+}
+
+/// Matches `c` against the members of a `[...]` set, returning whether it
+/// is one and how long the set is, closing `]` included. A set that is
+/// never closed runs to the end of the pattern.
+fn match_class(class: &[u8], c: u8) -> (bool, usize) {
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        match class[i..] {
+            [b']', ..] => return (matched, i + 1),
+            [b'\\', escaped, ..] => {
+                matched |= escaped == c;
+                i += 2;
+            }
+            [start, b'-', end, ..] => {
+                let (low, high) = (start.min(end), start.max(end));
+                matched |= (low..=high).contains(&c);
+                i += 3;
+            }
+            [member, ..] => {
+                matched |= member == c;
+                i += 1;
+            }
+            [] => unreachable!(),
+        }
+    }
+    (matched, i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pattern: &str, matching: &[&str], not_matching: &[&str]) {
+        for text in matching {
+            assert!(
+                matches(pattern.as_bytes(), text.as_bytes()),
+                "{pattern} should match {text}"
+            );
+        }
+        for text in not_matching {
+            assert!(
+                !matches(pattern.as_bytes(), text.as_bytes()),
+                "{pattern} shouldn't match {text}"
+            );
+        }
+    }
+
+    #[test]
+    fn literals_and_wildcards() {
+        check("", &[""], &["a"]);
+        check("key", &["key"], &["ke", "keys", "Key"]);
+        check("*", &["", "anything"], &[]);
+        check("h?llo", &["hello", "hallo"], &["hllo", "heello"]);
+        check("h*llo", &["hllo", "heeeello"], &["hello!"]);
+        check(
+            "user:*:name",
+            &["user:1:name", "user::name", "user:a:b:name"],
+            &["user:1:names"],
+        );
+        check("**a**", &["a", "bab"], &["bbb"]);
+        // the last star has to backtrack more than once
+        check("*ab*ab", &["aabxab", "abab"], &["abba", "abaab:"]);
+    }
+
+    #[test]
+    fn character_classes() {
+        check("h[ae]llo", &["hello", "hallo"], &["hillo", "hllo"]);
+        check("h[^e]llo", &["hallo", "hbllo"], &["hello", "hllo"]);
+        check("h[a-b]llo", &["hallo", "hbllo"], &["hcllo"]);
+        // a range may be given backwards
+        check("h[b-a]llo", &["hallo", "hbllo"], &["hcllo"]);
+        check("[\\]]", &["]"], &["\\"]);
+        check("x[-]", &["x-"], &["x"]);
+        // never closed, so the set runs to the end of the pattern
+        check("a[bc", &["ab", "ac"], &["a[bc"]);
+    }
+
+    #[test]
+    fn escapes() {
+        check("\\*", &["*"], &["a", ""]);
+        check("a\\?c", &["a?c"], &["abc"]);
+        check("\\[a]", &["[a]"], &["a"]);
+    }
+}
@@ -0,0 +1,862 @@
+//! Keyspace export/import as JSON, for diffing state between runs.
+//!
+//! The format is `{"keys":[{"key":..,"type":"string","value":..,"expires_at_ms":..}]}`
+//! with keys sorted and `expires_at_ms` (unix milliseconds) omitted for keys
+//! without a TTL. A key or string value that isn't UTF-8 is written as hex
+//! instead, under `key_hex` or `value_hex`.
+//!
+//! Other types have an array as their value: the elements of a list or set,
+//! `[field, value]` pairs for a hash, `[member, score]` pairs for a sorted
+//! set and `[id, [field, value, ..]]` entries for a stream. Sets and hashes
+//! are sorted, and a non-UTF-8 element is written as `{"hex":..}`.
+//!
+//! A stream also has its `last_id`, and its consumer groups, if any, under
+//! `groups`: `{"name":..,"last_delivered":..,"consumers":[..],"pending":[..]}`
+//! each, with `{"name":..,"seen_at_ms":..}` consumers and
+//! `[id, consumer, delivered_at_ms, deliveries]` pending entries. IDs are
+//! strings, as JSON numbers can't hold them exactly.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    iter::Peekable,
+    str::Chars,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::time::Instant;
+
+use crate::{
+    store::StoreValue,
+    value::{Consumer, ConsumerGroup, Pending, SortedSet, Stream, StreamId, Value},
+};
+
+/// Just enough of a JSON document model for the dump format.
+#[derive(Debug)]
+enum Json {
+    Null,
+    /// `true` or `false`; nothing in the dump format is boolean, so the value
+    /// itself is not kept.
+    Bool,
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, field: &str) -> Option<&Json> {
+        let Json::Object(fields) = self else {
+            return None;
+        };
+        fields.iter().find(|(k, _)| k == field).map(|(_, v)| v)
+    }
+}
+
+/// Renders live entries, as returned by `Store::entries`.
+pub fn dump(keys: Vec<(Bytes, StoreValue)>) -> String {
+    dump_at(keys, (Instant::now(), SystemTime::now()))
+}
+
+fn dump_at(mut keys: Vec<(Bytes, StoreValue)>, clock: Clock) -> String {
+    keys.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut out = String::from("{\"keys\":[");
+    for (i, (key, value)) in keys.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("\n  {");
+        write_field(&mut out, "key", &key);
+        let _ = write!(out, ",\"type\":\"{}\",", value.value.type_name());
+        write_value(&mut out, &value.value);
+        if let Value::Stream(stream) = &value.value {
+            write_stream_state(&mut out, stream, clock);
+        }
+        if let Some(expiry) = value.expiry {
+            let millis = unix_millis(expiry, clock);
+            let _ = write!(out, ",\"expires_at_ms\":{millis}");
+        }
+        out.push('}');
+    }
+    out.push_str("\n]}\n");
+    out
+}
+
+/// Parses a dump back into store entries, skipping keys whose expiry passed
+/// in the meantime.
+pub fn load(input: &str) -> anyhow::Result<HashMap<Bytes, StoreValue>> {
+    load_at(input, (Instant::now(), SystemTime::now()))
+}
+
+fn load_at(input: &str, (now, wall_now): Clock) -> anyhow::Result<HashMap<Bytes, StoreValue>> {
+    let mut chars = input.chars().peekable();
+    let doc = parse_value(&mut chars)?;
+    skip_whitespace(&mut chars);
+    anyhow::ensure!(chars.next().is_none(), "trailing data after JSON document");
+    let Some(Json::Array(entries)) = doc.get("keys") else {
+        anyhow::bail!("expected a top-level \"keys\" array");
+    };
+
+    let mut store = HashMap::with_capacity(entries.len());
+    for entry in entries {
+        let Some(key) = read_field(entry, "key")? else {
+            anyhow::bail!("every entry needs a string \"key\" field");
+        };
+        let key_text = key.escape_ascii();
+        let value = read_value(entry, (now, wall_now))
+            .with_context(|| format!("invalid value for key {key_text}"))?;
+        let expiry = match entry.get("expires_at_ms") {
+            None | Some(Json::Null) => None,
+            Some(Json::Number(millis)) => {
+                let at = UNIX_EPOCH + Duration::from_millis(*millis as u64);
+                match at.duration_since(wall_now) {
+                    Ok(remaining) => Some(now + remaining),
+                    // already expired
+                    Err(_) => continue,
+                }
+            }
+            Some(other) => anyhow::bail!("invalid expires_at_ms {other:?} for key {key_text}"),
+        };
+        store.insert(key, StoreValue::new(value, expiry));
+    }
+    Ok(store)
+}
+
+/// Writes the `"value"` field of an entry, in the form its type calls for.
+fn write_value(out: &mut String, value: &Value) {
+    match value {
+        Value::String(data) => write_field(out, "value", data),
+        collection => {
+            out.push_str("\"value\":");
+            write_collection(out, collection);
+        }
+    }
+}
+
+fn write_collection(out: &mut String, value: &Value) {
+    match value {
+        Value::String(data) => write_bytes(out, data),
+        Value::List(items) => write_array(out, items, |out, item| write_bytes(out, item)),
+        Value::Set(members) => {
+            let mut members: Vec<_> = members.iter().collect();
+            members.sort();
+            write_array(out, members, |out, member| write_bytes(out, member));
+        }
+        Value::Hash(fields) => {
+            let mut fields: Vec<_> = fields.iter().collect();
+            fields.sort();
+            write_array(out, fields, |out, (field, value)| {
+                write_array(out, [field, value], |out, data| write_bytes(out, data));
+            });
+        }
+        Value::SortedSet(set) => write_array(out, set.iter(), |out, (member, score)| {
+            out.push('[');
+            write_bytes(out, member);
+            out.push(',');
+            if score.is_finite() {
+                let _ = write!(out, "{score}");
+            } else {
+                // JSON has no infinities
+                let _ = write!(out, "\"{score}\"");
+            }
+            out.push(']');
+        }),
+        Value::Stream(stream) => write_array(out, &stream.entries, |out, (id, fields)| {
+            let _ = write!(out, "[\"{id}\",");
+            write_array(out, fields.iter().flat_map(|(f, v)| [f, v]), |out, data| {
+                write_bytes(out, data)
+            });
+            out.push(']');
+        }),
+    }
+}
+
+fn write_array<T>(
+    out: &mut String,
+    items: impl IntoIterator<Item = T>,
+    mut write: impl FnMut(&mut String, T),
+) {
+    out.push('[');
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write(out, item);
+    }
+    out.push(']');
+}
+
+/// Writes an element of a collection: a string, or `{"hex":".."}` if
+/// `data` isn't UTF-8.
+fn write_bytes(out: &mut String, data: &[u8]) {
+    match std::str::from_utf8(data) {
+        Ok(text) => write_string(out, text),
+        Err(_) => {
+            out.push_str("{\"hex\":");
+            write_hex(out, data);
+            out.push('}');
+        }
+    }
+}
+
+/// Reads the value of an entry, as written by [`write_value`] and, for a
+/// stream, [`write_stream_state`].
+fn read_value(entry: &Json, clock: Clock) -> anyhow::Result<Value> {
+    let type_name = match entry.get("type") {
+        None => "string",
+        Some(Json::String(t)) => t,
+        Some(other) => anyhow::bail!("invalid type {other:?}"),
+    };
+    if type_name == "string" {
+        let value = read_field(entry, "value")?.context("expected a string \"value\" field")?;
+        return Ok(Value::String(value));
+    }
+    let Some(Json::Array(items)) = entry.get("value") else {
+        anyhow::bail!("expected a \"value\" array");
+    };
+    let value = match type_name {
+        "list" => Value::List(
+            items
+                .iter()
+                .map(read_bytes)
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        "set" => Value::Set(
+            items
+                .iter()
+                .map(read_bytes)
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        "hash" => Value::Hash(
+            items
+                .iter()
+                .map(|item| {
+                    let (field, value) = read_pair(item)?;
+                    Ok((read_bytes(field)?, read_bytes(value)?))
+                })
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        "zset" => {
+            let mut set = SortedSet::default();
+            for item in items {
+                let (member, score) = read_pair(item)?;
+                let score = match score {
+                    Json::Number(score) => *score,
+                    Json::String(score) => score.parse().context("invalid score")?,
+                    _ => anyhow::bail!("invalid score {score:?}"),
+                };
+                anyhow::ensure!(!score.is_nan(), "score is NaN");
+                set.insert(read_bytes(member)?, score);
+            }
+            Value::SortedSet(set)
+        }
+        "stream" => {
+            let mut stream = Stream::default();
+            for item in items {
+                let (Json::String(id), Json::Array(fields)) = read_pair(item)? else {
+                    anyhow::bail!("expected [id, [field, value, ..]] stream entries");
+                };
+                anyhow::ensure!(fields.len() % 2 == 0, "odd number of stream fields");
+                let fields = fields
+                    .chunks(2)
+                    .map(|pair| Ok((read_bytes(&pair[0])?, read_bytes(&pair[1])?)))
+                    .collect::<anyhow::Result<_>>()?;
+                stream.entries.insert(id.parse()?, fields);
+            }
+            read_stream_state(entry, &mut stream, clock)?;
+            Value::Stream(stream)
+        }
+        other => anyhow::bail!("unsupported type {other:?}"),
+    };
+    Ok(value)
+}
+
+/// The current time, both as an [`Instant`] and as wall-clock time, to
+/// convert between the two.
+type Clock = (Instant, SystemTime);
+
+/// The unix time of `at`, in milliseconds.
+fn unix_millis(at: Instant, (now, wall_now): Clock) -> u128 {
+    let wall = if at >= now {
+        wall_now + (at - now)
+    } else {
+        wall_now - (now - at)
+    };
+    wall.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// The [`Instant`] at a unix time in milliseconds, or as close as it gets.
+fn instant_at(millis: f64, (now, wall_now): Clock) -> Instant {
+    let at = UNIX_EPOCH + Duration::from_millis(millis as u64);
+    match at.duration_since(wall_now) {
+        Ok(ahead) => now + ahead,
+        Err(behind) => now.checked_sub(behind.duration()).unwrap_or(now),
+    }
+}
+
+/// Writes what a stream has besides its entries: its last ID, and its
+/// consumer groups if it has any. Groups and consumers are sorted by name.
+fn write_stream_state(out: &mut String, stream: &Stream, clock: Clock) {
+    let _ = write!(out, ",\"last_id\":\"{}\"", stream.last_id);
+    if stream.groups.is_empty() {
+        return;
+    }
+    let mut groups: Vec<_> = stream.groups.iter().collect();
+    groups.sort_unstable_by_key(|&(name, _)| name);
+    out.push_str(",\"groups\":");
+    write_array(out, groups, |out, (name, group)| {
+        out.push('{');
+        write_field(out, "name", name);
+        let _ = write!(out, ",\"last_delivered\":\"{}\"", group.last_delivered);
+        let mut consumers: Vec<_> = group.consumers.iter().collect();
+        consumers.sort_unstable_by_key(|&(name, _)| name);
+        out.push_str(",\"consumers\":");
+        write_array(out, consumers, |out, (name, consumer)| {
+            out.push('{');
+            write_field(out, "name", name);
+            let seen_at = unix_millis(consumer.seen_at, clock);
+            let _ = write!(out, ",\"seen_at_ms\":{seen_at}}}");
+        });
+        out.push_str(",\"pending\":");
+        write_array(out, &group.pending, |out, (id, pending)| {
+            let _ = write!(out, "[\"{id}\",");
+            write_bytes(out, &pending.consumer);
+            let delivered_at = unix_millis(pending.delivered_at, clock);
+            let _ = write!(out, ",{delivered_at},{}]", pending.deliveries);
+        });
+        out.push('}');
+    });
+}
+
+/// Reads what [`write_stream_state`] writes into `stream`, whose entries
+/// are already in. A dump without a last ID gets the ID of the last entry.
+fn read_stream_state(entry: &Json, stream: &mut Stream, clock: Clock) -> anyhow::Result<()> {
+    let last_entry = stream.entries.keys().next_back().copied();
+    stream.last_id = match entry.get("last_id") {
+        None => last_entry.unwrap_or_default(),
+        Some(Json::String(id)) => id.parse()?,
+        Some(other) => anyhow::bail!("invalid last_id {other:?}"),
+    };
+    anyhow::ensure!(
+        last_entry.map_or(true, |id| id <= stream.last_id),
+        "last_id is before the last entry"
+    );
+    let groups = match entry.get("groups") {
+        None => return Ok(()),
+        Some(Json::Array(groups)) => groups,
+        Some(other) => anyhow::bail!("invalid groups {other:?}"),
+    };
+    let id = |json: Option<&Json>, what: &str| match json {
+        Some(Json::String(id)) => id.parse::<StreamId>(),
+        _ => anyhow::bail!("expected a string {what}"),
+    };
+    let millis = |json: Option<&Json>, what: &str| match json {
+        Some(Json::Number(millis)) => Ok(instant_at(*millis, clock)),
+        _ => anyhow::bail!("expected a number {what}"),
+    };
+    for group in groups {
+        let name = read_field(group, "name")?.context("every group needs a name")?;
+        let mut state = ConsumerGroup {
+            last_delivered: id(group.get("last_delivered"), "last_delivered")?,
+            ..ConsumerGroup::default()
+        };
+        let Some(Json::Array(consumers)) = group.get("consumers") else {
+            anyhow::bail!("expected a \"consumers\" array");
+        };
+        for consumer in consumers {
+            let name = read_field(consumer, "name")?.context("every consumer needs a name")?;
+            let consumer = Consumer {
+                pending: Default::default(),
+                seen_at: millis(consumer.get("seen_at_ms"), "seen_at_ms")?,
+            };
+            state.consumers.insert(name, consumer);
+        }
+        let Some(Json::Array(pending)) = group.get("pending") else {
+            anyhow::bail!("expected a \"pending\" array");
+        };
+        for item in pending {
+            let (id, consumer, delivered_at, deliveries) = match item {
+                Json::Array(item) if item.len() == 4 => (
+                    id(item.first(), "pending ID")?,
+                    read_bytes(&item[1])?,
+                    millis(item.get(2), "delivered_at_ms")?,
+                    match item[3] {
+                        Json::Number(deliveries) => deliveries as u64,
+                        _ => anyhow::bail!("expected a number of deliveries"),
+                    },
+                ),
+                _ => anyhow::bail!("expected [id, consumer, delivered_at_ms, deliveries]"),
+            };
+            state
+                .consumers
+                .get_mut(&consumer)
+                .with_context(|| format!("unknown consumer {}", consumer.escape_ascii()))?
+                .pending
+                .insert(id);
+            let pending = Pending {
+                consumer,
+                delivered_at,
+                deliveries,
+            };
+            state.pending.insert(id, pending);
+        }
+        stream.groups.insert(name, state);
+    }
+    Ok(())
+}
+
+fn read_pair(item: &Json) -> anyhow::Result<(&Json, &Json)> {
+    match item {
+        Json::Array(pair) if pair.len() == 2 => Ok((&pair[0], &pair[1])),
+        _ => anyhow::bail!("expected a pair, not {item:?}"),
+    }
+}
+
+/// Reads an element written by [`write_bytes`].
+fn read_bytes(item: &Json) -> anyhow::Result<Bytes> {
+    match item {
+        Json::String(text) => Ok(Bytes::from(text.clone())),
+        _ => match item.get("hex") {
+            Some(Json::String(hex)) => read_hex(hex),
+            _ => anyhow::bail!("expected a string, not {item:?}"),
+        },
+    }
+}
+
+/// Writes `"name":".."`, or `"name_hex":".."` if `data` isn't UTF-8.
+fn write_field(out: &mut String, name: &str, data: &[u8]) {
+    match std::str::from_utf8(data) {
+        Ok(text) => {
+            let _ = write!(out, "\"{name}\":");
+            write_string(out, text);
+        }
+        Err(_) => {
+            let _ = write!(out, "\"{name}_hex\":");
+            write_hex(out, data);
+        }
+    }
+}
+
+fn write_hex(out: &mut String, data: &[u8]) {
+    out.push('"');
+    for byte in data {
+        let _ = write!(out, "{byte:02x}");
+    }
+    out.push('"');
+}
+
+fn read_hex(hex: &str) -> anyhow::Result<Bytes> {
+    anyhow::ensure!(hex.len() % 2 == 0, "odd-length hex {hex:?}");
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()
+        .with_context(|| format!("invalid hex {hex:?}"))?;
+    Ok(bytes.into())
+}
+
+/// Reads a field written by [`write_field`], in either of its forms.
+fn read_field(entry: &Json, name: &str) -> anyhow::Result<Option<Bytes>> {
+    if let Some(Json::String(text)) = entry.get(name) {
+        return Ok(Some(Bytes::from(text.clone())));
+    }
+    let Some(Json::String(hex)) = entry.get(&format!("{name}_hex")) else {
+        return Ok(None);
+    };
+    read_hex(hex)
+        .with_context(|| format!("invalid {name}_hex"))
+        .map(Some)
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+}
+
+fn expect_literal(chars: &mut Peekable<Chars>, literal: &str) -> anyhow::Result<()> {
+    for expected in literal.chars() {
+        anyhow::ensure!(chars.next() == Some(expected), "expected {literal}");
+    }
+    Ok(())
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> anyhow::Result<Json> {
+    skip_whitespace(chars);
+    match chars.peek().context("unexpected end of JSON")? {
+        '{' => {
+            chars.next();
+            let mut fields = Vec::new();
+            skip_whitespace(chars);
+            if chars.next_if_eq(&'}').is_some() {
+                return Ok(Json::Object(fields));
+            }
+            loop {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+                skip_whitespace(chars);
+                anyhow::ensure!(chars.next() == Some(':'), "expected ':' after object key");
+                fields.push((key, parse_value(chars)?));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some('}') => return Ok(Json::Object(fields)),
+                    _ => anyhow::bail!("expected ',' or '}}' in object"),
+                }
+            }
+        }
+        '[' => {
+            chars.next();
+            let mut elements = Vec::new();
+            skip_whitespace(chars);
+            if chars.next_if_eq(&']').is_some() {
+                return Ok(Json::Array(elements));
+            }
+            loop {
+                elements.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some(']') => return Ok(Json::Array(elements)),
+                    _ => anyhow::bail!("expected ',' or ']' in array"),
+                }
+            }
+        }
+        '"' => Ok(Json::String(parse_string(chars)?)),
+        't' => expect_literal(chars, "true").map(|_| Json::Bool),
+        'f' => expect_literal(chars, "false").map(|_| Json::Bool),
+        'n' => expect_literal(chars, "null").map(|_| Json::Null),
+        _ => {
+            let mut number = String::new();
+            while let Some(c) =
+                chars.next_if(|c| matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+            {
+                number.push(c);
+            }
+            let value = number
+                .parse()
+                .with_context(|| format!("invalid JSON number '{number}'"))?;
+            Ok(Json::Number(value))
+        }
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> anyhow::Result<String> {
+    anyhow::ensure!(chars.next() == Some('"'), "expected a string");
+    let mut s = String::new();
+    loop {
+        match chars.next().context("unterminated string")? {
+            '"' => return Ok(s),
+            '\\' => match chars.next().context("unterminated string")? {
+                'n' => s.push('\n'),
+                'r' => s.push('\r'),
+                't' => s.push('\t'),
+                'b' => s.push('\u{8}'),
+                'f' => s.push('\u{c}'),
+                'u' => {
+                    let mut code = parse_code_unit(chars)?;
+                    // characters outside the BMP come as a surrogate pair
+                    if (0xd800..0xdc00).contains(&code) {
+                        let mut rest = chars.clone();
+                        if rest.next() == Some('\\') && rest.next() == Some('u') {
+                            let low = parse_code_unit(&mut rest)?;
+                            if (0xdc00..0xe000).contains(&low) {
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                                *chars = rest;
+                            }
+                        }
+                    }
+                    s.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                c => s.push(c),
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+/// The four hex digits of a `\\u` escape.
+fn parse_code_unit(chars: &mut Peekable<Chars>) -> anyhow::Result<u32> {
+    let hex: String = chars.by_ref().take(4).collect();
+    u32::from_str_radix(&hex, 16).with_context(|| format!("invalid escape \\u{hex}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock() -> Clock {
+        (Instant::now(), SystemTime::now())
+    }
+
+    fn entry(value: &[u8], expiry: Option<Instant>) -> StoreValue {
+        StoreValue::new(Value::String(Bytes::copy_from_slice(value)), expiry)
+    }
+
+    fn string(loaded: &HashMap<Bytes, StoreValue>, key: &[u8]) -> Bytes {
+        loaded[key].value.as_string().unwrap().clone()
+    }
+
+    fn stream_with(ids: &[&str], last_id: &str) -> Stream {
+        let mut stream = Stream::default();
+        for id in ids {
+            let fields = vec![(Bytes::from_static(b"f"), Bytes::from_static(b"v"))];
+            stream.entries.insert(id.parse().unwrap(), fields);
+        }
+        stream.last_id = last_id.parse().unwrap();
+        stream
+    }
+
+    /// Dumps `keys`, loads the dump back and dumps that, which should come
+    /// out the same, returning the loaded keys.
+    fn round_trip(keys: Vec<(&[u8], Value)>) -> HashMap<Bytes, StoreValue> {
+        let clock = clock();
+        let keys = keys
+            .into_iter()
+            .map(|(key, value)| (Bytes::copy_from_slice(key), StoreValue::new(value, None)))
+            .collect();
+        let dumped = dump_at(keys, clock);
+        let loaded = load_at(&dumped, clock).unwrap();
+        assert_eq!(dump_at(loaded.clone().into_iter().collect(), clock), dumped);
+        loaded
+    }
+
+    fn loaded_stream(loaded: &HashMap<Bytes, StoreValue>, key: &[u8]) -> Stream {
+        loaded[key].value.as_stream().unwrap().clone()
+    }
+
+    #[test]
+    fn round_trips_every_type() {
+        let mut zset = SortedSet::default();
+        zset.insert(Bytes::from_static(b"low"), f64::NEG_INFINITY);
+        zset.insert(Bytes::from_static(b"mid"), -1.5);
+        zset.insert(Bytes::from_static(b"\xff"), 1e300);
+        let loaded = round_trip(vec![
+            (
+                b"string",
+                Value::String(Bytes::from_static(b"quote \" and \\ \n")),
+            ),
+            (b"\x00\xfe", Value::String(Bytes::from_static(b"\xc3\x28"))),
+            (
+                b"list",
+                Value::List([&b"a"[..], b"", b"a"].map(Bytes::from_static).into()),
+            ),
+            (
+                b"set",
+                Value::Set(
+                    [&b"x"[..], b"y"]
+                        .map(Bytes::from_static)
+                        .into_iter()
+                        .collect(),
+                ),
+            ),
+            (
+                b"hash",
+                Value::Hash([(Bytes::from_static(b"k"), Bytes::from_static(b"\x80"))].into()),
+            ),
+            (b"zset", Value::SortedSet(zset)),
+        ]);
+        assert_eq!(loaded.len(), 6);
+        assert_eq!(
+            loaded[&b"\x00\xfe"[..]].value.as_string().unwrap(),
+            &b"\xc3\x28"[..]
+        );
+        let zset = loaded[&b"zset"[..]].value.as_sorted_set().unwrap();
+        assert_eq!(zset.score(b"low"), Some(f64::NEG_INFINITY));
+        assert_eq!(zset.score(b"\xff"), Some(1e300));
+    }
+
+    #[test]
+    fn keeps_future_expiries_and_skips_past_ones() {
+        let clock = clock();
+        let expiring = |expiry| StoreValue::new(Value::String(Bytes::new()), Some(expiry));
+        let keys = vec![
+            (
+                Bytes::from_static(b"later"),
+                expiring(clock.0 + Duration::from_secs(60)),
+            ),
+            (
+                Bytes::from_static(b"gone"),
+                expiring(clock.0 + Duration::from_secs(1)),
+            ),
+        ];
+        let dumped = dump_at(keys, clock);
+        let loaded = load_at(&dumped, (clock.0, clock.1 + Duration::from_secs(2))).unwrap();
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), [&b"later"[..]]);
+        let remaining = loaded[&b"later"[..]].expiry.unwrap() - clock.0;
+        assert!(remaining <= Duration::from_secs(58) && remaining > Duration::from_secs(57));
+    }
+
+    #[test]
+    fn round_trips_stream_ids_at_the_edges() {
+        let max = "18446744073709551615-18446744073709551615";
+        let loaded = round_trip(vec![
+            (
+                b"edges",
+                Value::Stream(stream_with(&["0-1", "5-18446744073709551615", max], max)),
+            ),
+            // entries deleted since, but new IDs must still be greater
+            (b"trimmed", Value::Stream(stream_with(&["1-1"], "9-3"))),
+            (b"empty", Value::Stream(stream_with(&[], "7-0"))),
+        ]);
+        let edges = loaded_stream(&loaded, b"edges");
+        let ids: Vec<_> = edges.entries.keys().map(ToString::to_string).collect();
+        assert_eq!(ids, ["0-1", "5-18446744073709551615", max]);
+        assert_eq!(edges.last_id, StreamId::MAX);
+        assert_eq!(
+            loaded_stream(&loaded, b"trimmed").last_id.to_string(),
+            "9-3"
+        );
+        assert_eq!(loaded_stream(&loaded, b"empty").last_id.to_string(), "7-0");
+    }
+
+    #[test]
+    fn reads_stream_ids_from_older_or_hand_written_dumps() {
+        let entry = |rest: &str| {
+            let input = format!(
+                r#"{{"keys":[{{"key":"s","type":"stream","value":[["5",["f","v"]]]{rest}}}]}}"#
+            );
+            load_at(&input, clock())
+        };
+        // without a last ID, the last entry's is taken; "5" is 5-0
+        let loaded = entry("").unwrap();
+        assert_eq!(loaded_stream(&loaded, b"s").last_id.to_string(), "5-0");
+        assert!(entry(r#","last_id":"4-9""#).is_err());
+        assert!(entry(r#","last_id":"5-x""#).is_err());
+        assert!(entry(r#","last_id":5"#).is_err());
+    }
+
+    #[test]
+    fn round_trips_consumer_groups() {
+        let clock = clock();
+        let mut stream = stream_with(&["1-0", "2-0", "3-0"], "3-0");
+        let mut group = ConsumerGroup {
+            last_delivered: "2-0".parse().unwrap(),
+            ..ConsumerGroup::default()
+        };
+        let (alice, bob) = (Bytes::from_static(b"alice"), Bytes::from_static(b"\xffbob"));
+        let an_hour_ago = clock
+            .0
+            .checked_sub(Duration::from_secs(3600))
+            .unwrap_or(clock.0);
+        group
+            .assign("1-0".parse().unwrap(), &alice, an_hour_ago)
+            .deliveries = 3;
+        group
+            .assign("2-0".parse().unwrap(), &bob, clock.0)
+            .deliveries = 1;
+        group.consumer(&Bytes::from_static(b"idle"), clock.0);
+        stream.groups.insert(Bytes::from_static(b"g1"), group);
+        stream
+            .groups
+            .insert(Bytes::from_static(b"g2"), ConsumerGroup::default());
+
+        let loaded = round_trip(vec![(b"s", Value::Stream(stream))]);
+        let stream = loaded_stream(&loaded, b"s");
+        assert_eq!(stream.groups.len(), 2);
+        assert!(stream.groups[&b"g2"[..]].pending.is_empty());
+        let group = &stream.groups[&b"g1"[..]];
+        assert_eq!(group.last_delivered.to_string(), "2-0");
+        assert_eq!(group.consumers.len(), 3);
+        assert_eq!(group.consumers[&bob].pending.len(), 1);
+        let pending: Vec<_> = group
+            .pending
+            .iter()
+            .map(|(id, p)| (id.to_string(), p.consumer.clone(), p.deliveries))
+            .collect();
+        assert_eq!(pending, [("1-0".into(), alice, 3), ("2-0".into(), bob, 1)]);
+    }
+
+    #[test]
+    fn rejects_pending_entries_of_unknown_consumers() {
+        let input = r#"{"keys":[{"key":"s","type":"stream","value":[],"last_id":"1-0",
+            "groups":[{"name":"g","last_delivered":"0-0","consumers":[],"pending":[["1-0","nobody",0,1]]}]}]}"#;
+        assert!(load_at(input, clock()).is_err());
+    }
+
+    #[test]
+    fn round_trips_strings() {
+        let values: [&[u8]; 7] = [
+            b"",
+            b"plain",
+            b"quote \" and \\",
+            b"line\nbreak\ttab\x01",
+            "héllo ☃ 𝄞".as_bytes(),
+            // not UTF-8, so written as hex
+            b"\xc3\x28",
+            b"\x00\xff",
+        ];
+        let store: HashMap<_, _> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let mut key = format!("key:{i} ").into_bytes();
+                key.extend_from_slice(value);
+                (Bytes::from(key), entry(value, None))
+            })
+            .collect();
+        let dumped = dump(store.clone().into_iter().collect());
+        assert!(dumped.contains(r#""value_hex":"00ff""#));
+        let loaded = load(&dumped).unwrap();
+        assert_eq!(loaded.len(), store.len());
+        for (key, value) in &store {
+            assert_eq!(&string(&loaded, key), value.value.as_string().unwrap());
+            assert_eq!(loaded[key].expiry, None);
+        }
+        assert_eq!(dump(loaded.into_iter().collect()), dumped);
+    }
+
+    #[test]
+    fn reads_hand_written_documents() {
+        let input = " {\"keys\" : [ {\"value\":\"\\u00e9\\ud834\\udd1e\\/\", \"key\":\"k\",\
+            \"expires_at_ms\":null, \"extra\":[true,false,1.5e3,{}]},\
+            {\"key_hex\":\"FF00\",\"value_hex\":\"\"} ] } \n";
+        let loaded = load(input).unwrap();
+        assert_eq!(string(&loaded, b"k"), "é𝄞/".as_bytes());
+        assert_eq!(string(&loaded, b"\xff\x00"), &b""[..]);
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        for input in [
+            "",
+            "{}",
+            r#"{"keys":{}}"#,
+            r#"{"keys":[{"key":"k"}]}"#,
+            r#"{"keys":[{"key":"k","value":1}]}"#,
+            r#"{"keys":[{"key":"k","value_hex":"abc"}]}"#,
+            r#"{"keys":[{"key":"k","value_hex":"zz"}]}"#,
+            r#"{"keys":[{"key":"k","value":"v","type":"list"}]}"#,
+            r#"{"keys":[{"key":"k","value":[],"type":"bogus"}]}"#,
+            r#"{"keys":[{"key":"k","value":[["a","NaN"]],"type":"zset"}]}"#,
+            r#"{"keys":[{"key":"k","value":[["1-x",[]]],"type":"stream"}]}"#,
+            r#"{"keys":[{"key":"k","value":[["1-1",["f"]]],"type":"stream"}]}"#,
+            r#"{"keys":[{"key":"k","value":"v","expires_at_ms":"soon"}]}"#,
+            r#"{"keys":[{"key":"k","value":"unterminated}]}"#,
+            r#"{"keys":[]} trailing"#,
+        ] {
+            assert!(load(input).is_err(), "{input:?} should be rejected");
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! A Redis-compatible server that can be embedded in any tokio application:
+//! build a [`RedisServer`] from a [`Config`], bind it and serve until
+//! [`RedisServer::shutdown`].
+
+use std::{
+    cell::Cell,
+    fmt::Write,
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::{TcpListener, TcpSocket},
+    sync::{mpsc, watch},
+    time::{self, Duration, Instant},
+};
+
+pub use crate::config::Config;
+use crate::{
+    clients::ClientMemory,
+    protocol::{DataType, RespReader},
+    replication::ReplicaHandshake,
+    store::Store,
+};
+
+mod audit;
+pub mod bench;
+mod blocking;
+pub mod cli;
+mod clients;
+mod commands;
+pub mod config;
+mod glob;
+mod json;
+pub mod protocol;
+mod random;
+mod replication;
+mod stats;
+mod store;
+mod value;
+mod websocket;
+
+/// How many pipelined requests are answered before their replies are
+/// flushed, even if more are queued.
+const MAX_PIPELINE_BATCH: usize = 1024;
+
+/// A server and its keyspace. Background tasks only run while [`serve`]
+/// does, so a server that was never served holds no resources but its
+/// keyspace.
+///
+/// [`serve`]: RedisServer::serve
+pub struct RedisServer {
+    config: Arc<Config>,
+    store: Arc<Store>,
+}
+
+impl RedisServer {
+    /// Loads the keyspace (`--load-json`) and opens the audit log, if the
+    /// config asks for them.
+    pub fn new(mut config: Config) -> anyhow::Result<Self> {
+        let store = match &config.load_json {
+            Some(path) => {
+                Store::from_entries(json::load(&std::fs::read_to_string(path)?)?, &config)
+            }
+            None => Store::new(&config),
+        };
+        if let Some(path) = &config.audit_log {
+            config.audit = Some(audit::AuditLog::open(path)?);
+        }
+        Ok(Self {
+            config: Arc::new(config),
+            store: Arc::new(store),
+        })
+    }
+
+    pub fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    /// Binds the configured port; a port of 0 picks a free one, which the
+    /// listener's `local_addr` reports.
+    pub fn bind(&self) -> anyhow::Result<TcpListener> {
+        bind_listener(&self.config, &self.config.port)
+    }
+
+    /// Serves clients from `listener` until [`shutdown`](Self::shutdown),
+    /// then waits up to `shutdown-timeout` for connections to finish.
+    pub async fn serve(&self, listener: TcpListener) -> anyhow::Result<()> {
+        let config = &self.config;
+        let websocket_listener = match config.websocket_port {
+            Some(port) => Some(bind_listener(config, &port.to_string())?),
+            None => None,
+        };
+        let background = [
+            tokio::spawn(replication::run_replica(Arc::clone(config))),
+            tokio::spawn(stats::run_sampler(Arc::clone(&config.stats))),
+            tokio::spawn(clients::run_evictor(Arc::clone(config))),
+            tokio::spawn(store::run_expirer(
+                Arc::clone(&self.store),
+                Arc::clone(config),
+            )),
+        ];
+        let mut shutdown = config.shutdown.subscribe();
+        let (drained_tx, mut drained) = mpsc::channel::<()>(1);
+        let (abort, aborted) = watch::channel(());
+        let connections = Connections {
+            drained: drained_tx,
+            aborted,
+        };
+        if let Some(listener) = websocket_listener {
+            tokio::spawn(websocket::serve(
+                listener,
+                Arc::clone(&self.store),
+                Arc::clone(config),
+                connections.clone(),
+            ));
+        }
+        // the flag may have been raised before we got here
+        while !*shutdown.borrow_and_update() {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.changed() => break,
+            };
+            match accepted {
+                Ok((stream, addr)) => {
+                    if let Err(e) = stream.set_nodelay(config.tcp_nodelay) {
+                        eprintln!("failed to set TCP_NODELAY for {addr}: {e}");
+                    }
+                    spawn_connection(
+                        stream,
+                        addr,
+                        Arc::clone(&self.store),
+                        Arc::clone(config),
+                        connections.clone(),
+                    );
+                }
+                Err(e) => {
+                    // usually transient (e.g. out of file descriptors), so back off
+                    // briefly instead of taking the whole server down
+                    eprintln!("failed to accept connection: {e}");
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+
+        drop(listener);
+        drop(connections);
+        let remaining = config.stats.connected_clients.load(Ordering::Relaxed);
+        eprintln!("shutting down, waiting for {remaining} connection(s) to finish");
+        let grace = config.tunables().shutdown_timeout;
+        if time::timeout(grace, drained.recv()).await.is_err() {
+            eprintln!("shutdown grace period expired, closing remaining connections");
+            abort.send_replace(());
+            // they stop at their next await, and none may touch the store
+            // once this returns
+            drained.recv().await;
+        }
+        for task in background {
+            task.abort();
+        }
+        Ok(())
+    }
+
+    /// Starts a graceful shutdown: listeners close, and connections are let
+    /// go of once their current command has been answered.
+    pub fn shutdown(&self) {
+        self.config.shutdown.send_replace(true);
+    }
+}
+
+fn bind_listener(config: &Config, port: &str) -> anyhow::Result<TcpListener> {
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse()?;
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(config.reuseport)?;
+    #[cfg(not(unix))]
+    if config.reuseport {
+        eprintln!("reuseport is not supported on this platform, ignoring it");
+    }
+    socket.bind(addr)?;
+    Ok(socket.listen(config.tcp_backlog)?)
+}
+
+/// What every connection holds on to, so that shutdown can wait for them
+/// to finish and stop those that outlast the grace period.
+#[derive(Clone)]
+struct Connections {
+    /// Every connection holds a clone; recv() returns None once all are
+    /// gone.
+    drained: mpsc::Sender<()>,
+    /// Changes once the grace period is over.
+    aborted: watch::Receiver<()>,
+}
+
+/// Runs a connection on its own task and reports how it ended, so that an
+/// error or panic only ever takes down the offending connection.
+fn spawn_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    addr: SocketAddr,
+    store: Arc<Store>,
+    config: Arc<Config>,
+    connections: Connections,
+) {
+    let number = config
+        .stats
+        .total_connections
+        .fetch_add(1, Ordering::Relaxed);
+    config
+        .stats
+        .connected_clients
+        .fetch_add(1, Ordering::Relaxed);
+    let memory = config.clients.register(addr);
+    let traced = {
+        let tunables = config.tunables();
+        tunables.trace_protocol && number % tunables.trace_sample == 0
+    };
+    let connection = protocol::NET.scope(
+        Arc::clone(&config.stats),
+        protocol::REPLY_BUF.scope(
+            Cell::default(),
+            protocol::REPLY_GAUGE.scope(
+                Arc::clone(&memory.reply_buffer),
+                protocol::PROTOCOL.scope(
+                    Cell::new(2),
+                    // ids start at 1, as in redis
+                    handle_connection(stream, number + 1, addr, store, Arc::clone(&config), memory),
+                ),
+            ),
+        ),
+    );
+    let mut aborted = connections.aborted.clone();
+    let connection = async move {
+        tokio::select! {
+            result = connection => result,
+            // also when serve() is gone, which only returns once this has
+            // been sent
+            _ = aborted.changed() => Err(anyhow::anyhow!("shutdown grace period expired")),
+        }
+    };
+    let connection = if traced {
+        tokio::spawn(protocol::TRACE.scope(Cell::new(None), connection))
+    } else {
+        tokio::spawn(connection)
+    };
+    tokio::spawn(async move {
+        let result = connection.await;
+        drop(connections.drained);
+        config.replicas.lock().unwrap().remove(&addr);
+        config.clients.unregister(&addr);
+        config
+            .stats
+            .connected_clients
+            .fetch_sub(1, Ordering::Relaxed);
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("connection {addr} closed: {e:#}"),
+            Err(e) if e.is_panic() => eprintln!("connection {addr} panicked"),
+            Err(e) => eprintln!("connection {addr} aborted: {e}"),
+        }
+    });
+}
+
+/// Runs `work` unless the connection is picked for eviction first, which
+/// is the outer error.
+async fn unless_evicted<F: Future>(memory: &ClientMemory, work: F) -> anyhow::Result<F::Output> {
+    tokio::select! {
+        output = work => Ok(output),
+        _ = memory.evicted.notified() => anyhow::bail!("evicted by maxmemory-clients"),
+    }
+}
+
+async fn handle_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send,
+    id: u64,
+    peer: SocketAddr,
+    store: Arc<Store>,
+    config: Arc<Config>,
+    memory: Arc<ClientMemory>,
+) -> anyhow::Result<()> {
+    let mut shutdown = config.shutdown.subscribe();
+    let mut replica_handshake = ReplicaHandshake::default();
+    let mut reader = RespReader::with_capacity_gauge(Arc::clone(&memory.query_buffer));
+    // a blocked command keeps reading the incoming side while it writes
+    // nothing to the other
+    let (mut input, output) = tokio::io::split(stream);
+    // replies collect here until the requests pipelined behind them have
+    // been handled, so a batch goes out in one write
+    let mut stream = BufWriter::new(output);
+    let mut batch = 0;
+    loop {
+        // the previous command has been answered, so this is a safe point to
+        // let go of the connection
+        if *shutdown.borrow() {
+            stream.flush().await?;
+            return Ok(());
+        }
+        let limits = config.tunables().limits.flat();
+        let parsed = match reader.buffered_frame(&limits) {
+            Ok(Some(frame)) => {
+                // a long pipeline still gets its replies in bounded batches
+                if batch == MAX_PIPELINE_BATCH {
+                    unless_evicted(&memory, stream.flush()).await??;
+                    batch = 0;
+                }
+                batch += 1;
+                Ok(Some(frame))
+            }
+            Ok(None) => {
+                unless_evicted(&memory, stream.flush()).await??;
+                batch = 1;
+                tokio::select! {
+                    parsed = reader.read_frame(&mut input, &limits) => parsed,
+                    _ = shutdown.changed() => return Ok(()),
+                    _ = memory.evicted.notified() => anyhow::bail!("evicted by maxmemory-clients"),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        let data_type = match parsed {
+            Ok(Some(data_type)) => data_type,
+            // client hung up
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                if let Some(e) = e.downcast_ref::<protocol::ProtocolError>() {
+                    protocol::send_simple_error(&mut stream, &format!("ERR {e}")).await?;
+                }
+                stream.flush().await?;
+                return Err(e);
+            }
+        };
+        match data_type {
+            DataType::Array(arr) => {
+                let mut args = arr.into_iter();
+                let Some(DataType::BulkString(command)) = args.next() else {
+                    continue;
+                };
+                let Some(resolved) = std::str::from_utf8(&command)
+                    .ok()
+                    .and_then(|command| config.resolve_command(command))
+                else {
+                    let mut msg = format!(
+                        "ERR unknown command '{}', with args beginning with: ",
+                        command.escape_ascii()
+                    );
+                    for arg in args {
+                        if let DataType::BulkString(arg) = arg {
+                            let _ = write!(msg, "'{}' ", arg.escape_ascii());
+                        }
+                    }
+                    protocol::send_simple_error(&mut stream, &msg).await?;
+                    continue;
+                };
+                let name = resolved.name();
+                let _ = protocol::TRACE.try_with(|trace| {
+                    let tunables = config.tunables();
+                    let wanted = tunables.trace_commands.is_empty()
+                        || tunables.trace_commands.iter().any(|c| c == name);
+                    trace.set(wanted.then_some(peer));
+                    if wanted {
+                        eprintln!(
+                            "[{peer}] <- {} {:?}",
+                            command.escape_ascii(),
+                            args.as_slice()
+                        );
+                    }
+                });
+                if !resolved.accepts(args.len() + 1) {
+                    let msg = format!(
+                        "ERR wrong number of arguments for '{}' command",
+                        name.to_ascii_lowercase()
+                    );
+                    protocol::send_simple_error(&mut stream, &msg).await?;
+                    continue;
+                }
+                let flags = resolved.flags();
+                if flags.accesses_data() && !config.serving_data() {
+                    protocol::send_simple_error(
+                        &mut stream,
+                        "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.",
+                    )
+                    .await?;
+                    continue;
+                }
+                if flags.write {
+                    if config.tunables().read_only {
+                        protocol::send_simple_error(
+                            &mut stream,
+                            "READONLY You can't write against a read only server.",
+                        )
+                        .await?;
+                        continue;
+                    }
+                    if let Some(audit) = &config.audit {
+                        audit.record(&peer, name, &resolved.key_args(args.as_slice()));
+                    }
+                }
+                let started = Instant::now();
+                let mut invocation = commands::Invocation {
+                    stream: &mut stream,
+                    input: &mut input,
+                    reader: &mut reader,
+                    id,
+                    peer,
+                    store: &store,
+                    config: &config,
+                    handshake: &mut replica_handshake,
+                    memory: &memory,
+                };
+                // a large reply can leave the command stuck writing it to a
+                // slow reader, which is what eviction is there for
+                let result =
+                    unless_evicted(&memory, resolved.execute(&mut invocation, args)).await?;
+                match result {
+                    Ok(()) => config.stats.record_command(name, started.elapsed()),
+                    // the connection itself failed, so there is no one to tell
+                    Err(e) if e.downcast_ref::<io::Error>().is_some() => return Err(e),
+                    Err(e) if e.downcast_ref::<blocking::HungUp>().is_some() => return Ok(()),
+                    Err(e)
+                        if e.downcast_ref::<value::WrongType>().is_some()
+                            || e.downcast_ref::<value::GroupError>().is_some() =>
+                    {
+                        protocol::send_simple_error(&mut stream, &e.to_string()).await?;
+                    }
+                    Err(e) => {
+                        let msg = format!("ERR {e:#}").replace(['\r', '\n'], " ");
+                        protocol::send_simple_error(&mut stream, &msg).await?;
+                    }
+                }
+            }
+            other => anyhow::bail!("{:?} not yet implemented!", other),
+        }
+    }
+}
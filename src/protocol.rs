@@ -1,115 +1,855 @@
-use std::borrow::Cow;
+//! RESP framing: a resumable decoder and reply encoders. Nothing here is
+//! tied to TCP, so any async byte stream (a TLS stream, a Unix socket, an
+//! in-memory duplex) can be read from and written to.
 
-use anyhow::Context;
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
-#[derive(PartialEq, Eq, Debug)]
+use anyhow::Context;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{config::split_args, stats::Stats};
+
+tokio::task_local! {
+    /// Present on connections sampled by `--trace-protocol`. While it holds the
+    /// peer address, every frame written on the connection is logged.
+    pub static TRACE: Cell<Option<SocketAddr>>;
+
+    /// Reused across the replies of one connection to save allocations.
+    pub static REPLY_BUF: Cell<BytesMut>;
+
+    /// Kept at the capacity of `REPLY_BUF`, including while a reply in it
+    /// is being written, so client eviction can see it.
+    pub static REPLY_GAUGE: Arc<AtomicUsize>;
+
+    /// Present on client connections; the bytes they read and write are
+    /// added to its network counters.
+    pub static NET: Arc<Stats>;
+
+    /// The RESP version the connection negotiated with `HELLO`.
+    pub static PROTOCOL: Cell<u8>;
+}
+
+/// The current connection's RESP version.
+pub fn protocol_version() -> u8 {
+    PROTOCOL.try_with(Cell::get).unwrap_or(2)
+}
+
+/// Whether replies on the current connection use RESP3 frame types.
+fn resp3() -> bool {
+    protocol_version() >= 3
+}
+
+fn count_input(bytes: usize) {
+    let _ = NET.try_with(|stats| {
+        stats
+            .net_input_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed)
+    });
+}
+
+/// A RESP frame. The RESP3-only types are downgraded when written to a
+/// connection that hasn't switched protocols: maps and sets become arrays,
+/// doubles and big numbers bulk strings, booleans integers.
+#[derive(PartialEq, Debug)]
 pub enum DataType<'a> {
     SimpleString(Cow<'a, str>),
     SimpleError(Cow<'a, str>),
     Integer(i64),
-    BulkString(Cow<'a, str>),
+    /// Binary-safe: exactly the declared number of bytes, CRLFs included.
+    BulkString(Bytes),
     Array(Vec<DataType<'a>>),
+    /// The RESP2 null bulk string / null array, or the RESP3 null.
+    Null,
+    Map(Vec<(DataType<'a>, DataType<'a>)>),
+    Set(Vec<DataType<'a>>),
+    Double(f64),
+    Boolean(bool),
+    /// Digits with an optional sign, of any length.
+    BigNumber(Cow<'a, str>),
+    /// Out-of-band data, such as pub/sub messages.
+    Push(Vec<DataType<'a>>),
 }
 
-pub async fn parse_data_type<'a>(
-    reader: &mut BufReader<&mut TcpStream>,
-) -> anyhow::Result<DataType<'a>> {
-    let mut current_array = None;
-    let mut s = String::new();
-    loop {
-        s.clear();
-        reader.read_line(&mut s).await?;
-        // println!("read at start of loop: {s}");
-        let mut bytes = s.chars();
-        let dt = match bytes.next().context("no data type given")? {
-            '+' => DataType::SimpleString(Cow::Owned(s[1..].trim_end().to_string())),
-            '-' => DataType::SimpleError(Cow::Owned(s[1..].trim_end().to_string())),
-            ':' => {
-                let value_str = s[1..].trim_end();
-                let value = value_str
-                    .parse::<i64>()
-                    .with_context(|| format!("{value_str} is not a valid integer"))?;
-                DataType::Integer(value)
-            }
-            '$' => {
-                let length_str = &s[1..bytes.take_while(|c| *c != '\r').count() + 1];
-                let length = length_str.parse().unwrap();
-                let mut data = String::new();
-                reader.read_line(&mut data).await?;
-                let data = data.trim_end().to_string();
-                assert_eq!(data.len(), length, "string length was wrong");
-                DataType::BulkString(Cow::Owned(data))
+/// The RESP types made of other frames.
+#[derive(Debug, Clone, Copy)]
+enum Aggregate {
+    Array,
+    Map,
+    Set,
+    Push,
+}
+
+/// Upper bounds on what a peer may declare, so a single request can't make us
+/// allocate unbounded memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_bulk_len: usize,
+    pub max_multibulk_len: usize,
+    pub max_inline_len: usize,
+    /// How many aggregates may be open at once. Frames are dropped and
+    /// formatted recursively, so this also bounds the stack they need.
+    pub max_nesting: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_multibulk_len: 1024 * 1024,
+            max_inline_len: 64 * 1024,
+            // replies nest a few levels at most, XREAD's deepest of all
+            max_nesting: 32,
+        }
+    }
+}
+
+impl Limits {
+    /// These limits for a peer that sends commands, which are flat arrays
+    /// of bulk strings.
+    pub fn flat(self) -> Self {
+        Self {
+            max_nesting: 1,
+            ..self
+        }
+    }
+}
+
+/// Malformed or abusive input; the connection should be answered with the
+/// error and then closed.
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Protocol error: invalid bulk length")]
+    InvalidBulkLength,
+    #[error("Protocol error: invalid multibulk length")]
+    InvalidMultibulkLength,
+    #[error("Protocol error: too big inline request")]
+    TooBigInlineRequest,
+    #[error("Protocol error: unbalanced quotes in request")]
+    UnbalancedQuotes,
+    #[error("Protocol error: too many nested aggregates")]
+    TooDeeplyNested,
+}
+
+/// The first byte of every RESP frame; a request line starting with anything
+/// else is an inline command.
+const TYPE_BYTES: [char; 12] = ['+', '-', ':', '$', '*', '%', '~', '>', '_', ',', '#', '('];
+
+/// How much more to ask the socket for when the buffer runs dry.
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Most elements reserved for an aggregate up front. Its declared length is
+/// only a claim, and a stack of large ones would reserve plenty for nothing.
+const MAX_PREALLOCATED: usize = 1024;
+
+/// Resumable RESP decoder. Each call consumes whatever complete pieces of a
+/// frame are buffered and keeps the partial frame in between, so a frame may
+/// arrive in any number of reads without being re-parsed from the start.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// aggregates still being filled, innermost last, with the number of
+    /// frames they were declared to hold (twice the pairs, for maps)
+    open: Vec<(Aggregate, Vec<DataType<'static>>, usize)>,
+    /// declared length of a bulk string whose header has been consumed
+    pending_bulk: Option<usize>,
+}
+
+impl Decoder {
+    /// Decodes the next frame from `buf`, or returns `None` if more data is
+    /// needed to complete it.
+    pub fn decode(
+        &mut self,
+        buf: &mut BytesMut,
+        limits: &Limits,
+    ) -> anyhow::Result<Option<DataType<'static>>> {
+        loop {
+            let dt = if let Some(length) = self.pending_bulk {
+                if buf.len() < length + 2 {
+                    // make room for the whole payload once, not read by read
+                    buf.reserve(length + 2 - buf.len());
+                    return Ok(None);
+                }
+                anyhow::ensure!(
+                    &buf[length..length + 2] == b"\r\n",
+                    "bulk string of {length} bytes is not followed by CRLF"
+                );
+                // copied out rather than split off, so a small value that
+                // ends up in the store doesn't pin the whole read buffer
+                let data = Bytes::copy_from_slice(&buf[..length]);
+                buf.advance(length + 2);
+                self.pending_bulk = None;
+                DataType::BulkString(data)
+            } else {
+                let Some(newline) = buf
+                    .iter()
+                    .take(limits.max_inline_len)
+                    .position(|b| *b == b'\n')
+                else {
+                    if buf.len() >= limits.max_inline_len {
+                        return Err(ProtocolError::TooBigInlineRequest.into());
+                    }
+                    return Ok(None);
+                };
+                let line = buf.split_to(newline + 1);
+                let line = std::str::from_utf8(&line).context("protocol line is not UTF-8")?;
+                let line = line.trim_end_matches('\n').trim_end_matches('\r');
+                if self.open.is_empty() && !line.starts_with(TYPE_BYTES) {
+                    // an inline command, as typed into telnet: words separated
+                    // by spaces, quoted like redis-cli does
+                    let words = split_args(line).map_err(|_| ProtocolError::UnbalancedQuotes)?;
+                    if words.is_empty() {
+                        continue;
+                    }
+                    let words = words
+                        .into_iter()
+                        .map(|word| DataType::BulkString(word.into()))
+                        .collect();
+                    return Ok(Some(DataType::Array(words)));
+                }
+                let mut chars = line.chars();
+                let kind = chars.next().context("no data type given")?;
+                let rest = chars.as_str();
+                match kind {
+                    '+' => DataType::SimpleString(Cow::Owned(rest.to_string())),
+                    '-' => DataType::SimpleError(Cow::Owned(rest.to_string())),
+                    ':' => {
+                        let value = rest
+                            .parse::<i64>()
+                            .with_context(|| format!("{rest} is not a valid integer"))?;
+                        DataType::Integer(value)
+                    }
+                    '$' if rest == "-1" => DataType::Null,
+                    '$' => {
+                        let length = match rest.parse() {
+                            Ok(length) if length <= limits.max_bulk_len => length,
+                            _ => return Err(ProtocolError::InvalidBulkLength.into()),
+                        };
+                        self.pending_bulk = Some(length);
+                        continue;
+                    }
+                    '*' if rest == "-1" => DataType::Null,
+                    '*' | '%' | '~' | '>' => {
+                        let element_count: usize = match rest.parse() {
+                            Ok(count) if count <= limits.max_multibulk_len => count,
+                            _ => return Err(ProtocolError::InvalidMultibulkLength.into()),
+                        };
+                        let (aggregate, frames) = match kind {
+                            '*' => (Aggregate::Array, element_count),
+                            '%' => (Aggregate::Map, element_count * 2),
+                            '~' => (Aggregate::Set, element_count),
+                            _ => (Aggregate::Push, element_count),
+                        };
+                        if frames > 0 {
+                            if self.open.len() >= limits.max_nesting {
+                                return Err(ProtocolError::TooDeeplyNested.into());
+                            }
+                            self.open.push((
+                                aggregate,
+                                Vec::with_capacity(frames.min(MAX_PREALLOCATED)),
+                                frames,
+                            ));
+                            continue;
+                        }
+                        aggregate.build(Vec::new())
+                    }
+                    '_' => DataType::Null,
+                    ',' => {
+                        let value = rest
+                            .parse::<f64>()
+                            .with_context(|| format!("{rest} is not a valid double"))?;
+                        DataType::Double(value)
+                    }
+                    '#' => match rest {
+                        "t" => DataType::Boolean(true),
+                        "f" => DataType::Boolean(false),
+                        _ => anyhow::bail!("{rest} is not a valid boolean"),
+                    },
+                    '(' => {
+                        let digits = rest.strip_prefix(['-', '+']).unwrap_or(rest);
+                        anyhow::ensure!(
+                            !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()),
+                            "{rest} is not a valid big number"
+                        );
+                        DataType::BigNumber(Cow::Owned(rest.to_string()))
+                    }
+                    other => anyhow::bail!("data type {other} is not implemented"),
+                }
+            };
+            // a completed element may in turn complete its enclosing aggregates
+            let mut dt = dt;
+            loop {
+                let Some((_, elements, frames)) = self.open.last_mut() else {
+                    return Ok(Some(dt));
+                };
+                elements.push(dt);
+                if elements.len() < *frames {
+                    break;
+                }
+                let (aggregate, elements, _) = self.open.pop().unwrap();
+                dt = aggregate.build(elements);
             }
-            '*' => {
-                let element_count = s[1..bytes.take_while(|c| *c != '\r').count() + 1]
-                    .parse()
-                    .unwrap();
-                // println!("array detected, element count: {element_count}");
-                if element_count > 0 {
-                    current_array = Some((Vec::with_capacity(element_count), element_count));
-                    continue;
-                } else {
-                    DataType::Array(Vec::new())
+        }
+    }
+
+    fn in_frame(&self) -> bool {
+        !self.open.is_empty() || self.pending_bulk.is_some()
+    }
+}
+
+impl Aggregate {
+    fn build(self, elements: Vec<DataType<'static>>) -> DataType<'static> {
+        match self {
+            Aggregate::Array => DataType::Array(elements),
+            Aggregate::Set => DataType::Set(elements),
+            Aggregate::Push => DataType::Push(elements),
+            Aggregate::Map => {
+                let mut elements = elements.into_iter();
+                let mut pairs = Vec::with_capacity(elements.len() / 2);
+                while let (Some(k), Some(v)) = (elements.next(), elements.next()) {
+                    pairs.push((k, v));
                 }
+                DataType::Map(pairs)
             }
-            other => anyhow::bail!("data type {other} is not implemented"),
-        };
-        if let Some((arr, element_count)) = &mut current_array {
-            arr.push(dt);
-            if arr.len() == *element_count {
-                return Ok(DataType::Array(current_array.take().unwrap().0));
+        }
+    }
+}
+
+/// The read side of a connection. Bytes past the current frame stay
+/// buffered for the next one.
+#[derive(Debug, Default)]
+pub struct RespReader {
+    buf: BytesMut,
+    decoder: Decoder,
+    /// Kept at the buffer's capacity, for client memory accounting.
+    capacity_gauge: Option<Arc<AtomicUsize>>,
+}
+
+impl RespReader {
+    pub fn with_capacity_gauge(gauge: Arc<AtomicUsize>) -> Self {
+        Self {
+            capacity_gauge: Some(gauge),
+            ..Self::default()
+        }
+    }
+
+    /// Decodes the next frame if it has already been read, without touching
+    /// the stream.
+    pub fn buffered_frame(&mut self, limits: &Limits) -> anyhow::Result<Option<DataType<'static>>> {
+        self.decoder.decode(&mut self.buf, limits)
+    }
+
+    /// Reads the next frame, returning `None` if the peer closed the
+    /// connection cleanly between frames. Cancel-safe: a partially read frame
+    /// is kept and resumed by the next call.
+    pub async fn read_frame(
+        &mut self,
+        stream: &mut (impl AsyncRead + Unpin),
+        limits: &Limits,
+    ) -> anyhow::Result<Option<DataType<'static>>> {
+        loop {
+            if let Some(frame) = self.decoder.decode(&mut self.buf, limits)? {
+                return Ok(Some(frame));
+            }
+            if !self.fill(stream).await? {
+                anyhow::ensure!(
+                    self.buf.is_empty() && !self.decoder.in_frame(),
+                    "connection closed mid-frame"
+                );
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Reads on until the peer closes the connection, keeping what it sends
+    /// meanwhile for the frames after. Cancel-safe like [`Self::read_frame`].
+    pub async fn until_closed(
+        &mut self,
+        stream: &mut (impl AsyncRead + Unpin),
+    ) -> std::io::Result<()> {
+        while self.fill(stream).await? {}
+        Ok(())
+    }
+
+    /// Reads a raw line outside of RESP framing, without its line ending.
+    pub async fn read_line(
+        &mut self,
+        stream: &mut (impl AsyncRead + Unpin),
+    ) -> anyhow::Result<String> {
+        loop {
+            if let Some(newline) = self.buf.iter().position(|b| *b == b'\n') {
+                let line = self.buf.split_to(newline + 1);
+                let line = String::from_utf8(line.to_vec()).context("line is not UTF-8")?;
+                return Ok(line
+                    .trim_end_matches('\n')
+                    .trim_end_matches('\r')
+                    .to_string());
             }
-        } else {
-            return Ok(dt);
+            anyhow::ensure!(self.fill(stream).await?, "connection closed mid-line");
         }
     }
+
+    /// Reads exactly `len` raw bytes, e.g. an RDB payload.
+    pub async fn read_exact(
+        &mut self,
+        stream: &mut (impl AsyncRead + Unpin),
+        len: usize,
+    ) -> anyhow::Result<BytesMut> {
+        self.buf.reserve(len.saturating_sub(self.buf.len()));
+        while self.buf.len() < len {
+            anyhow::ensure!(
+                self.fill(stream).await?,
+                "connection closed after {} of {len} bytes",
+                self.buf.len()
+            );
+        }
+        Ok(self.buf.split_to(len))
+    }
+
+    async fn fill(&mut self, stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<bool> {
+        self.buf.reserve(READ_CHUNK);
+        if let Some(gauge) = &self.capacity_gauge {
+            gauge.store(self.buf.capacity(), Ordering::Relaxed);
+        }
+        let read = stream.read_buf(&mut self.buf).await?;
+        count_input(read);
+        Ok(read > 0)
+    }
 }
 
-pub async fn send_simple_string(stream: &mut TcpStream, msg: &str) -> anyhow::Result<()> {
-    stream
-        .write_all(format!("+{}\r\n", msg).as_bytes())
+async fn write_frame(stream: &mut (impl AsyncWrite + Unpin), frame: &[u8]) -> std::io::Result<()> {
+    let _ = TRACE.try_with(|peer| {
+        if let Some(peer) = peer.get() {
+            let hex: Vec<_> = frame.iter().map(|b| format!("{b:02x}")).collect();
+            eprintln!("[{peer}] -> {} | {}", frame.escape_ascii(), hex.join(" "));
+        }
+    });
+    stream.write_all(frame).await?;
+    let _ = NET.try_with(|stats| {
+        stats
+            .net_output_bytes
+            .fetch_add(frame.len() as u64, Ordering::Relaxed)
+    });
+    Ok(())
+}
+
+/// Encodes a reply into the connection's reply buffer and writes it out.
+/// The buffer is handed back afterwards, so its allocation is reused.
+async fn write_encoded(
+    stream: &mut (impl AsyncWrite + Unpin),
+    encode: impl FnOnce(&mut BytesMut),
+) -> std::io::Result<()> {
+    let mut buf = REPLY_BUF.try_with(Cell::take).unwrap_or_default();
+    encode(&mut buf);
+    let _ = REPLY_GAUGE.try_with(|gauge| gauge.store(buf.capacity(), Ordering::Relaxed));
+    let result = write_frame(stream, &buf).await;
+    buf.clear();
+    let _ = REPLY_BUF.try_with(|cell| cell.set(buf));
+    result
+}
+
+/// Replies common enough to keep preformatted.
+fn shared_reply(msg: &str) -> Option<&'static [u8]> {
+    match msg {
+        "OK" => Some(b"+OK\r\n"),
+        "PONG" => Some(b"+PONG\r\n"),
+        _ => None,
+    }
+}
+
+/// Appends `n` in decimal without going through `fmt`.
+fn put_decimal(buf: &mut BytesMut, n: usize) {
+    let mut digits = [0; 20];
+    let mut i = digits.len();
+    let mut n = n;
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    buf.extend_from_slice(&digits[i..]);
+}
+
+fn put_integer(buf: &mut BytesMut, n: i64) {
+    if n < 0 {
+        buf.put_u8(b'-');
+    }
+    put_decimal(buf, n.unsigned_abs() as usize);
+}
+
+/// Appends a simple string or error line; `msg` must not contain CR or LF.
+fn put_line(buf: &mut BytesMut, kind: u8, msg: &str) {
+    buf.put_u8(kind);
+    buf.extend_from_slice(msg.as_bytes());
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn put_bulk_string(buf: &mut BytesMut, msg: &[u8]) {
+    buf.put_u8(b'$');
+    put_decimal(buf, msg.len());
+    buf.extend_from_slice(b"\r\n");
+    buf.extend_from_slice(msg);
+    buf.extend_from_slice(b"\r\n");
+}
+
+/// Appends the wire form of any frame, nested aggregates included, in RESP3
+/// or downgraded to RESP2.
+pub fn encode(buf: &mut BytesMut, data: &DataType, resp3: bool) {
+    match data {
+        DataType::SimpleString(s) => put_line(buf, b'+', s),
+        DataType::SimpleError(e) => put_line(buf, b'-', e),
+        DataType::Integer(n) => {
+            buf.put_u8(b':');
+            put_integer(buf, *n);
+            buf.extend_from_slice(b"\r\n");
+        }
+        DataType::BulkString(bs) => put_bulk_string(buf, bs),
+        DataType::Array(elements) => put_aggregate(buf, b'*', elements, resp3),
+        DataType::Set(elements) => {
+            put_aggregate(buf, if resp3 { b'~' } else { b'*' }, elements, resp3)
+        }
+        DataType::Push(elements) => {
+            put_aggregate(buf, if resp3 { b'>' } else { b'*' }, elements, resp3)
+        }
+        DataType::Map(pairs) => {
+            if resp3 {
+                buf.put_u8(b'%');
+                put_decimal(buf, pairs.len());
+            } else {
+                buf.put_u8(b'*');
+                put_decimal(buf, pairs.len() * 2);
+            }
+            buf.extend_from_slice(b"\r\n");
+            for (k, v) in pairs {
+                encode(buf, k, resp3);
+                encode(buf, v, resp3);
+            }
+        }
+        DataType::Null if resp3 => buf.extend_from_slice(b"_\r\n"),
+        DataType::Null => buf.extend_from_slice(b"$-1\r\n"),
+        DataType::Double(d) => {
+            // redis spells these out in lower case, Rust's NaN would not parse
+            let text = if d.is_nan() {
+                "nan".to_string()
+            } else {
+                d.to_string()
+            };
+            if resp3 {
+                put_line(buf, b',', &text);
+            } else {
+                put_bulk_string(buf, text.as_bytes());
+            }
+        }
+        DataType::Boolean(b) if resp3 => put_line(buf, b'#', if *b { "t" } else { "f" }),
+        DataType::Boolean(b) => put_line(buf, b':', if *b { "1" } else { "0" }),
+        DataType::BigNumber(n) if resp3 => put_line(buf, b'(', n),
+        DataType::BigNumber(n) => put_bulk_string(buf, n.as_bytes()),
+    }
+}
+
+fn put_aggregate(buf: &mut BytesMut, kind: u8, elements: &[DataType], resp3: bool) {
+    buf.put_u8(kind);
+    put_decimal(buf, elements.len());
+    buf.extend_from_slice(b"\r\n");
+    for element in elements {
+        encode(buf, element, resp3);
+    }
+}
+
+pub async fn send_simple_string(
+    stream: &mut (impl AsyncWrite + Unpin),
+    msg: &str,
+) -> anyhow::Result<()> {
+    let result = match shared_reply(msg) {
+        Some(frame) => write_frame(stream, frame).await,
+        None => write_encoded(stream, |buf| put_line(buf, b'+', msg)).await,
+    };
+    result.with_context(|| format!("failed to send simple string '{msg}'"))
+}
+
+pub async fn send_simple_error(
+    stream: &mut (impl AsyncWrite + Unpin),
+    msg: &str,
+) -> anyhow::Result<()> {
+    write_encoded(stream, |buf| put_line(buf, b'-', msg))
         .await
-        .with_context(|| format!("failed to send simple string '{msg}'"))
+        .with_context(|| format!("failed to send simple error '{msg}'"))
 }
 
-pub async fn send_bulk_string(stream: &mut TcpStream, msg: &str) -> anyhow::Result<()> {
-    stream
-        .write_all(format!("${}\r\n{}\r\n", msg.len(), msg).as_bytes())
+pub async fn send_bulk_string(
+    stream: &mut (impl AsyncWrite + Unpin),
+    msg: impl AsRef<[u8]>,
+) -> anyhow::Result<()> {
+    let msg = msg.as_ref();
+    write_encoded(stream, |buf| put_bulk_string(buf, msg))
         .await
-        .with_context(|| format!("failed to send bulk string '{msg}'"))
+        .with_context(|| format!("failed to send bulk string '{}'", msg.escape_ascii()))
 }
 
-pub async fn send_null(stream: &mut TcpStream) -> anyhow::Result<()> {
-    stream
-        .write_all(b"$-1\r\n")
+pub async fn send_null(stream: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<()> {
+    let frame: &[u8] = if resp3() { b"_\r\n" } else { b"$-1\r\n" };
+    write_frame(stream, frame)
         .await
         .context("failed to send <null> bulk string")
 }
 
-pub async fn send_array<'a>(stream: &mut TcpStream, data: &[DataType<'a>]) -> anyhow::Result<()> {
-    stream
-        .write_all(format!("*{}\r\n", data.len()).as_bytes())
+/// The reply for a missing aggregate, such as a pop from an empty list.
+pub async fn send_null_array(stream: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<()> {
+    let frame: &[u8] = if resp3() { b"_\r\n" } else { b"*-1\r\n" };
+    write_frame(stream, frame)
         .await
-        .with_context(|| format!("failed to send array length for {:?}", data))?;
-    for dt in data {
-        match dt {
-            DataType::BulkString(bs) => send_bulk_string(stream, bs).await?,
-            _ => anyhow::bail!("not yet implemented!"),
+        .context("failed to send <null> array")
+}
+
+/// Sends an array of any frames as a single write.
+pub async fn send_array<'a>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    data: &[DataType<'a>],
+) -> anyhow::Result<()> {
+    let resp3 = resp3();
+    write_encoded(stream, |buf| put_aggregate(buf, b'*', data, resp3))
+        .await
+        .with_context(|| format!("failed to send array {:?}", data))
+}
+
+/// Starts an array of `len` frames, for a reply too long to build whole,
+/// whose frames then follow with [`send_elements`].
+pub async fn send_array_header(
+    stream: &mut (impl AsyncWrite + Unpin),
+    len: usize,
+) -> anyhow::Result<()> {
+    write_encoded(stream, |buf| {
+        buf.put_u8(b'*');
+        put_decimal(buf, len);
+        buf.extend_from_slice(b"\r\n");
+    })
+    .await
+    .with_context(|| format!("failed to send header of array of {len}"))
+}
+
+/// Sends frames one after another as a single write, with no header.
+pub async fn send_elements(
+    stream: &mut (impl AsyncWrite + Unpin),
+    data: &[DataType<'_>],
+) -> anyhow::Result<()> {
+    let resp3 = resp3();
+    write_encoded(stream, |buf| {
+        for element in data {
+            encode(buf, element, resp3);
         }
-    }
-    Ok(())
+    })
+    .await
+    .with_context(|| format!("failed to send {} array elements", data.len()))
+}
+
+/// Sends any frame as a single write, in the connection's protocol.
+pub async fn send(
+    stream: &mut (impl AsyncWrite + Unpin),
+    data: &DataType<'_>,
+) -> anyhow::Result<()> {
+    let resp3 = resp3();
+    write_encoded(stream, |buf| encode(buf, data, resp3))
+        .await
+        .with_context(|| format!("failed to send {data:?}"))
 }
 
-pub async fn wait_for<'a>(stream: &mut TcpStream, expected: DataType<'a>) -> anyhow::Result<()> {
-    let mut reader = BufReader::new(stream);
-    let response = parse_data_type(&mut reader).await?;
+pub async fn wait_for<'a>(
+    reader: &mut RespReader,
+    stream: &mut (impl AsyncRead + Unpin),
+    expected: DataType<'a>,
+) -> anyhow::Result<()> {
+    let response = reader
+        .read_frame(stream, &Limits::default())
+        .await?
+        .context("connection closed while waiting for response")?;
     anyhow::ensure!(
         response == expected,
         "response differed from expected value"
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulk(s: &str) -> DataType<'static> {
+        DataType::BulkString(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    fn command(words: &[&str]) -> DataType<'static> {
+        DataType::Array(words.iter().map(|w| bulk(w)).collect())
+    }
+
+    /// Feeds `chunks` to one decoder as successive reads, collecting every
+    /// frame completed along the way.
+    fn decode_chunks(chunks: &[&[u8]]) -> anyhow::Result<Vec<DataType<'static>>> {
+        let (mut decoder, mut buf, limits) =
+            (Decoder::default(), BytesMut::new(), Limits::default());
+        let mut frames = Vec::new();
+        for chunk in chunks {
+            buf.extend_from_slice(chunk);
+            while let Some(frame) = decoder.decode(&mut buf, &limits)? {
+                frames.push(frame);
+            }
+        }
+        anyhow::ensure!(!decoder.in_frame() && buf.is_empty(), "left over input");
+        Ok(frames)
+    }
+
+    fn protocol_error(input: &[u8], limits: &Limits) -> ProtocolError {
+        let mut buf = BytesMut::from(input);
+        let e = Decoder::default().decode(&mut buf, limits).unwrap_err();
+        e.downcast().unwrap()
+    }
+
+    #[test]
+    fn decodes_a_frame_split_at_every_byte() {
+        let input = b"*2\r\n$3\r\nGET\r\n$5\r\nfo\r\no\r\n";
+        let chunks: Vec<&[u8]> = input.chunks(1).collect();
+        assert_eq!(
+            decode_chunks(&chunks).unwrap(),
+            [command(&["GET", "fo\r\no"])]
+        );
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_bulk_string() {
+        let (mut decoder, limits) = (Decoder::default(), Limits::default());
+        let mut buf = BytesMut::from(&b"*1\r\n$5\r\nhel"[..]);
+        assert_eq!(decoder.decode(&mut buf, &limits).unwrap(), None);
+        assert!(decoder.in_frame());
+        buf.extend_from_slice(b"lo\r");
+        assert_eq!(decoder.decode(&mut buf, &limits).unwrap(), None);
+        buf.extend_from_slice(b"\n");
+        let frame = decoder.decode(&mut buf, &limits).unwrap();
+        assert_eq!(frame, Some(command(&["hello"])));
+        assert!(!decoder.in_frame());
+    }
+
+    #[test]
+    fn decodes_pipelined_frames_from_one_read() {
+        let frames = decode_chunks(&[b"*1\r\n$4\r\nPING\r\n*2\r\n$4\r\nECHO\r\n$0\r\n", b"\r\n"]);
+        assert_eq!(
+            frames.unwrap(),
+            [command(&["PING"]), command(&["ECHO", ""])]
+        );
+    }
+
+    #[test]
+    fn decodes_nested_and_resp3_frames() {
+        let input = b"*3\r\n*1\r\n:-7\r\n%1\r\n+k\r\n#t\r\n~0\r\n(-123\r\n,1.5\r\n_\r\n";
+        let frames = decode_chunks(&[&input[..9], &input[9..]]).unwrap();
+        let expected = [
+            DataType::Array(vec![
+                DataType::Array(vec![DataType::Integer(-7)]),
+                DataType::Map(vec![(
+                    DataType::SimpleString("k".into()),
+                    DataType::Boolean(true),
+                )]),
+                DataType::Set(Vec::new()),
+            ]),
+            DataType::BigNumber("-123".into()),
+            DataType::Double(1.5),
+            DataType::Null,
+        ];
+        assert_eq!(frames, expected);
+    }
+
+    #[test]
+    fn decodes_inline_commands() {
+        let frames = decode_chunks(&[b"SET key \"hello wo", b"rld\"\r\n\r\nPING\n"]);
+        assert_eq!(
+            frames.unwrap(),
+            [command(&["SET", "key", "hello world"]), command(&["PING"])]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let limits = Limits {
+            max_bulk_len: 4,
+            max_multibulk_len: 2,
+            max_inline_len: 16,
+            max_nesting: 2,
+        };
+        assert!(matches!(
+            protocol_error(b"PING \"x\r\n", &limits),
+            ProtocolError::UnbalancedQuotes
+        ));
+        assert!(matches!(
+            protocol_error(b"$5\r\n", &limits),
+            ProtocolError::InvalidBulkLength
+        ));
+        assert!(matches!(
+            protocol_error(b"$x\r\n", &limits),
+            ProtocolError::InvalidBulkLength
+        ));
+        assert!(matches!(
+            protocol_error(b"*3\r\n", &limits),
+            ProtocolError::InvalidMultibulkLength
+        ));
+        assert!(matches!(
+            protocol_error(b"PING PING PING PING", &limits),
+            ProtocolError::TooBigInlineRequest
+        ));
+        assert!(matches!(
+            protocol_error(b"*1\r\n*1\r\n*1\r\n:1\r\n", &limits),
+            ProtocolError::TooDeeplyNested
+        ));
+        // as deep as allowed; an empty aggregate is complete at once
+        let mut buf = BytesMut::from(&b"*1\r\n*2\r\n:1\r\n*0\r\n"[..]);
+        let frame = Decoder::default().decode(&mut buf, &limits).unwrap();
+        let inner = vec![DataType::Integer(1), DataType::Array(Vec::new())];
+        assert_eq!(frame, Some(DataType::Array(vec![DataType::Array(inner)])));
+        assert!(matches!(
+            protocol_error(b"*1\r\n*1\r\n", &Limits::default().flat()),
+            ProtocolError::TooDeeplyNested
+        ));
+        let mut buf = BytesMut::from(&b"$2\r\nabc\r\n"[..]);
+        assert!(Decoder::default().decode(&mut buf, &limits).is_err());
+    }
+
+    #[test]
+    fn survives_deeply_nested_headers() {
+        let mut input = b"*1\r\n".repeat(200_000);
+        input.extend_from_slice(b":1\r\n");
+        let limits = Limits::default();
+        let e = protocol_error(&input, &limits);
+        assert!(matches!(e, ProtocolError::TooDeeplyNested));
+    }
+
+    #[test]
+    fn reserves_little_for_declared_lengths() {
+        let (mut decoder, limits) = (Decoder::default(), Limits::default());
+        let mut buf = BytesMut::from(&b"*1048576\r\n*1048576\r\n"[..]);
+        assert_eq!(decoder.decode(&mut buf, &limits).unwrap(), None);
+        assert_eq!(decoder.open.len(), 2);
+        for (_, elements, frames) in &decoder.open {
+            assert_eq!(*frames, 1048576);
+            assert!(elements.capacity() <= MAX_PREALLOCATED);
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_what_arrives_until_closed() {
+        let mut reader = RespReader::default();
+        let mut input = &b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nEC"[..];
+        reader.until_closed(&mut input).await.unwrap();
+        let ping = DataType::Array(vec![DataType::BulkString(Bytes::from_static(b"PING"))]);
+        let limits = Limits::default();
+        assert_eq!(reader.buffered_frame(&limits).unwrap(), Some(ping));
+        assert_eq!(reader.buffered_frame(&limits).unwrap(), None);
+    }
+}
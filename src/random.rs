@@ -0,0 +1,71 @@
+//! Cheap randomness, for spreading benchmark keys and sampling the keyspace
+//! and collections. Nothing here needs to be unpredictable.
+
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    hash::{BuildHasher, Hasher},
+};
+
+/// xorshift64, seeded from a fresh `RandomState`.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new() -> Self {
+        Self(RandomState::new().build_hasher().finish() | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `0..n`, which must not be empty.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// `count` distinct numbers in `0..n` in random order, or all of them
+    /// if there are fewer. Floyd's algorithm draws no more than are wanted.
+    pub fn sample(&mut self, n: usize, count: usize) -> Vec<usize> {
+        let mut picked: Vec<_> = if count >= n {
+            (0..n).collect()
+        } else {
+            let mut seen = HashSet::with_capacity(count);
+            (n - count..n)
+                .map(|j| {
+                    let t = self.below(j + 1);
+                    let t = if seen.contains(&t) { j } else { t };
+                    seen.insert(t);
+                    t
+                })
+                .collect()
+        };
+        // Floyd's picks are a random set, but not in a random order
+        self.shuffle(&mut picked);
+        picked
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_picks_distinct_numbers_in_range() {
+        let mut rng = Rng::new();
+        for (n, count) in [(0, 3), (5, 0), (5, 3), (5, 5), (5, 9), (1000, 10)] {
+            let picked = rng.sample(n, count);
+            assert_eq!(picked.len(), count.min(n));
+            assert!(picked.iter().all(|&i| i < n));
+            assert_eq!(picked.iter().collect::<HashSet<_>>().len(), picked.len());
+        }
+    }
+}
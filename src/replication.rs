@@ -0,0 +1,242 @@
+//! Replication: on the replica side, connecting to the master, the PSYNC
+//! handshake and the initial transfer; on the master side, what each
+//! connecting replica announced about itself.
+
+use std::{
+    borrow::Cow,
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::{
+    net::TcpStream,
+    time::{self, Duration},
+};
+
+use crate::{
+    config::{Config, ReplicaOf},
+    protocol::{self, DataType, RespReader},
+};
+
+/// `replica-priority` of replicas that don't announce one.
+pub const DEFAULT_PRIORITY: u32 = 100;
+
+/// How long to wait before reconnecting after the link to the master fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Where a replica is in its sync with the master.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicaState {
+    /// Not connected (yet, or any more) to the master.
+    Connect,
+    /// Connected, exchanging PING/REPLCONF/PSYNC.
+    Handshake,
+    /// Receiving the initial RDB snapshot.
+    Transfer,
+    /// In sync and following the master's stream.
+    Connected,
+}
+
+impl fmt::Display for ReplicaState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ReplicaState::Connect => "connect",
+            ReplicaState::Handshake => "handshake",
+            ReplicaState::Transfer => "transfer",
+            ReplicaState::Connected => "connected",
+        })
+    }
+}
+
+/// What a connecting replica told the master via `REPLCONF` before `PSYNC`.
+#[derive(Debug, Default)]
+pub struct ReplicaHandshake {
+    pub listening_port: Option<u16>,
+    /// Set by replicas behind NAT via `replica-announce-ip`.
+    pub ip_address: Option<String>,
+    /// Sent by replicas of this server; other replicas get the default.
+    pub priority: Option<u32>,
+    /// Lowercased `capa` values, e.g. `eof` and `psync2`.
+    pub capabilities: Vec<String>,
+}
+
+impl ReplicaHandshake {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// The address the replica can be reached at, preferring what it
+    /// announced over what the connection looks like from here.
+    pub fn announced(&self, peer: &SocketAddr) -> ConnectedReplica {
+        ConnectedReplica {
+            ip: self
+                .ip_address
+                .clone()
+                .unwrap_or_else(|| peer.ip().to_string()),
+            port: self.listening_port.unwrap_or(0),
+            priority: self.priority.unwrap_or(DEFAULT_PRIORITY),
+        }
+    }
+}
+
+/// A replica attached to this master, as listed by `INFO replication`.
+#[derive(Debug, Clone)]
+pub struct ConnectedReplica {
+    pub ip: String,
+    pub port: u16,
+    pub priority: u32,
+}
+
+/// Random hex string, as used for the EOF mark of diskless transfers.
+pub fn random_hex(len: usize) -> String {
+    let mut out = String::with_capacity(len);
+    while out.len() < len {
+        // every RandomState is seeded with fresh random keys
+        let word = RandomState::new().build_hasher().finish();
+        out.push_str(&format!("{word:016x}"));
+    }
+    out.truncate(len);
+    out
+}
+
+/// Keeps the link to the master up for the lifetime of the server,
+/// reconnecting whenever it drops.
+pub async fn run_replica(config: Arc<Config>) {
+    let Some(master) = &config.replica_of else {
+        return;
+    };
+    loop {
+        if let Err(e) = sync_with_master(master, &config).await {
+            eprintln!(
+                "replication link to {}:{} failed: {e:#}",
+                master.master_host, master.master_port
+            );
+        }
+        config.set_replica_state(ReplicaState::Connect);
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn sync_with_master(master: &ReplicaOf, config: &Config) -> anyhow::Result<()> {
+    config.set_replica_state(ReplicaState::Connect);
+    let mut stream =
+        TcpStream::connect(format!("{}:{}", master.master_host, master.master_port)).await?;
+    // one reader for the whole link, since the master sends the RDB right
+    // behind the FULLRESYNC line
+    let mut reader = RespReader::default();
+
+    config.set_replica_state(ReplicaState::Handshake);
+    handshake(&mut reader, &mut stream, config).await?;
+
+    config.set_replica_state(ReplicaState::Transfer);
+    let header = reader.read_line(&mut stream).await?;
+    let length: usize = header
+        .strip_prefix('$')
+        .and_then(|l| l.parse().ok())
+        .with_context(|| format!("expected RDB length, got {header:?}"))?;
+    anyhow::ensure!(
+        length <= config.tunables().limits.max_bulk_len,
+        "RDB of {length} bytes exceeds proto-max-bulk-len"
+    );
+    reader
+        .read_exact(&mut stream, length)
+        .await
+        .context("RDB transfer ended early")?;
+    // loading the snapshot is not implemented yet; the keyspace starts empty
+
+    config.set_replica_state(ReplicaState::Connected);
+    // applying the command stream is not implemented yet either, so just
+    // hold the link open until the master goes away
+    let limits = config.tunables().limits.flat();
+    while reader.read_frame(&mut stream, &limits).await?.is_some() {}
+    anyhow::bail!("master closed the connection")
+}
+
+async fn handshake(
+    reader: &mut RespReader,
+    stream: &mut TcpStream,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let port = config
+        .replica_announce_port
+        .map_or_else(|| config.port.clone(), |p| p.to_string());
+    protocol::send_array(stream, &[DataType::BulkString(Bytes::from_static(b"PING"))]).await?;
+    protocol::wait_for(
+        reader,
+        stream,
+        DataType::SimpleString(Cow::Borrowed("PONG")),
+    )
+    .await?;
+    protocol::send_array(
+        stream,
+        &[
+            DataType::BulkString(Bytes::from_static(b"REPLCONF")),
+            DataType::BulkString(Bytes::from_static(b"listening-port")),
+            DataType::BulkString(port.into()),
+        ],
+    )
+    .await?;
+    protocol::wait_for(reader, stream, DataType::SimpleString(Cow::Borrowed("OK"))).await?;
+    if let Some(ip) = &config.replica_announce_ip {
+        protocol::send_array(
+            stream,
+            &[
+                DataType::BulkString(Bytes::from_static(b"REPLCONF")),
+                DataType::BulkString(Bytes::from_static(b"ip-address")),
+                DataType::BulkString(ip.clone().into()),
+            ],
+        )
+        .await?;
+        protocol::wait_for(reader, stream, DataType::SimpleString(Cow::Borrowed("OK"))).await?;
+    }
+    // only announced when changed, so the handshake stays the standard one
+    // otherwise; real redis masters reject the option, which is not fatal
+    let priority = config.tunables().replica_priority;
+    if priority != DEFAULT_PRIORITY {
+        protocol::send_array(
+            stream,
+            &[
+                DataType::BulkString(Bytes::from_static(b"REPLCONF")),
+                DataType::BulkString(Bytes::from_static(b"priority")),
+                DataType::BulkString(priority.to_string().into()),
+            ],
+        )
+        .await?;
+        match reader
+            .read_frame(stream, &protocol::Limits::default())
+            .await?
+        {
+            Some(DataType::SimpleString(_) | DataType::SimpleError(_)) => {}
+            other => anyhow::bail!("unexpected reply to REPLCONF priority: {other:?}"),
+        }
+    }
+    protocol::send_array(
+        stream,
+        &[
+            DataType::BulkString(Bytes::from_static(b"REPLCONF")),
+            DataType::BulkString(Bytes::from_static(b"capa")),
+            DataType::BulkString(Bytes::from_static(b"psync2")),
+        ],
+    )
+    .await?;
+    protocol::wait_for(reader, stream, DataType::SimpleString(Cow::Borrowed("OK"))).await?;
+    protocol::send_array(
+        stream,
+        &[
+            DataType::BulkString(Bytes::from_static(b"PSYNC")),
+            DataType::BulkString(Bytes::from_static(b"?")),
+            DataType::BulkString(Bytes::from_static(b"-1")),
+        ],
+    )
+    .await?;
+    let limits = protocol::Limits::default();
+    match reader.read_frame(stream, &limits).await? {
+        Some(DataType::SimpleString(s)) if s.starts_with("FULLRESYNC ") => Ok(()),
+        other => anyhow::bail!("unexpected reply to PSYNC: {other:?}"),
+    }
+}
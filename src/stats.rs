@@ -0,0 +1,286 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::time::{self, Duration, Instant};
+
+use crate::commands;
+
+/// Upper bounds (in seconds) of the command latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+/// How many 100ms samples the instantaneous rates are averaged over.
+const RATE_SAMPLES: usize = 16;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Server-wide counters, updated from every connection.
+#[derive(Debug)]
+pub struct Stats {
+    pub connected_clients: AtomicU64,
+    pub total_connections: AtomicU64,
+    pub total_commands: AtomicU64,
+    /// Bytes read from and written to client connections.
+    pub net_input_bytes: AtomicU64,
+    pub net_output_bytes: AtomicU64,
+    /// Connections closed by `maxmemory-clients`.
+    pub evicted_clients: AtomicU64,
+    /// Keys removed because their TTL passed.
+    pub expired_keys: AtomicU64,
+    rates: Mutex<Rates>,
+    /// Keyed by the canonical upper-case name, with every command in from
+    /// the start so that recording one takes no lock.
+    commands: HashMap<&'static str, CommandStats>,
+    /// Non-cumulative counts per bucket, with a final overflow bucket.
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_micros: AtomicU64,
+}
+
+/// Recent per-second rates of the counters, sampled by [`Stats::sample`].
+#[derive(Debug, Default)]
+struct Rates {
+    last: Option<(Instant, [u64; 3])>,
+    /// Ring of (commands, input bytes, output bytes) per second.
+    samples: [[f64; 3]; RATE_SAMPLES],
+    next: usize,
+}
+
+/// The per-second rates averaged over the last samples.
+#[derive(Debug, Clone, Copy)]
+pub struct Instantaneous {
+    pub ops_per_sec: f64,
+    pub input_kbps: f64,
+    pub output_kbps: f64,
+}
+
+#[derive(Debug, Default)]
+struct CommandStats {
+    calls: AtomicU64,
+    duration_micros: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            connected_clients: AtomicU64::default(),
+            total_connections: AtomicU64::default(),
+            total_commands: AtomicU64::default(),
+            net_input_bytes: AtomicU64::default(),
+            net_output_bytes: AtomicU64::default(),
+            evicted_clients: AtomicU64::default(),
+            expired_keys: AtomicU64::default(),
+            rates: Mutex::default(),
+            commands: commands::names()
+                .map(|name| (name, CommandStats::default()))
+                .collect(),
+            latency_buckets: Default::default(),
+            latency_sum_micros: AtomicU64::default(),
+        }
+    }
+}
+
+impl Stats {
+    pub fn record_command(&self, name: &'static str, elapsed: Duration) {
+        self.total_commands.fetch_add(1, Ordering::Relaxed);
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| secs <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        if let Some(command) = self.commands.get(name) {
+            command.calls.fetch_add(1, Ordering::Relaxed);
+            command
+                .duration_micros
+                .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Zeroes everything except the gauges, for `CONFIG RESETSTAT`. Commands
+    /// running meanwhile may be counted in some counters but not others.
+    pub fn reset(&self) {
+        for command in self.commands.values() {
+            command.calls.store(0, Ordering::Relaxed);
+            command.duration_micros.store(0, Ordering::Relaxed);
+        }
+        self.total_commands.store(0, Ordering::Relaxed);
+        for bucket in &self.latency_buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.latency_sum_micros.store(0, Ordering::Relaxed);
+        self.total_connections.store(0, Ordering::Relaxed);
+        self.net_input_bytes.store(0, Ordering::Relaxed);
+        self.net_output_bytes.store(0, Ordering::Relaxed);
+        self.evicted_clients.store(0, Ordering::Relaxed);
+        self.expired_keys.store(0, Ordering::Relaxed);
+        *self.rates.lock().unwrap() = Rates::default();
+    }
+
+    /// Records the counter increments since the previous call as a rate
+    /// sample; called every 100ms by [`run_sampler`].
+    pub fn sample(&self) {
+        let now = Instant::now();
+        let current = [
+            self.total_commands.load(Ordering::Relaxed),
+            self.net_input_bytes.load(Ordering::Relaxed),
+            self.net_output_bytes.load(Ordering::Relaxed),
+        ];
+        let mut rates = self.rates.lock().unwrap();
+        if let Some((at, previous)) = rates.last {
+            let secs = (now - at).as_secs_f64().max(f64::EPSILON);
+            let slot = rates.next;
+            for i in 0..3 {
+                rates.samples[slot][i] = current[i].saturating_sub(previous[i]) as f64 / secs;
+            }
+            rates.next = (slot + 1) % RATE_SAMPLES;
+        }
+        rates.last = Some((now, current));
+    }
+
+    pub fn instantaneous(&self) -> Instantaneous {
+        let rates = self.rates.lock().unwrap();
+        let mean = |i: usize| rates.samples.iter().map(|s| s[i]).sum::<f64>() / RATE_SAMPLES as f64;
+        Instantaneous {
+            ops_per_sec: mean(0),
+            input_kbps: mean(1) / 1024.0,
+            output_kbps: mean(2) / 1024.0,
+        }
+    }
+
+    /// The `INFO stats` section.
+    pub fn info(&self) -> String {
+        let rates = self.instantaneous();
+        format!(
+            "total_connections_received:{}\r\ntotal_commands_processed:{}\r\ninstantaneous_ops_per_sec:{}\r\ntotal_net_input_bytes:{}\r\ntotal_net_output_bytes:{}\r\ninstantaneous_input_kbps:{:.2}\r\ninstantaneous_output_kbps:{:.2}\r\nexpired_keys:{}\r\nevicted_clients:{}",
+            self.total_connections.load(Ordering::Relaxed),
+            self.total_commands.load(Ordering::Relaxed),
+            rates.ops_per_sec.round(),
+            self.net_input_bytes.load(Ordering::Relaxed),
+            self.net_output_bytes.load(Ordering::Relaxed),
+            rates.input_kbps,
+            rates.output_kbps,
+            self.expired_keys.load(Ordering::Relaxed),
+            self.evicted_clients.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Renders all counters in the Prometheus text exposition format.
+    pub fn to_prometheus(&self, keys: usize) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        };
+        metric(
+            "redis_connected_clients",
+            "gauge",
+            "Number of client connections.",
+            self.connected_clients.load(Ordering::Relaxed),
+        );
+        metric(
+            "redis_connections_received_total",
+            "counter",
+            "Total number of connections accepted.",
+            self.total_connections.load(Ordering::Relaxed),
+        );
+        metric(
+            "redis_commands_processed_total",
+            "counter",
+            "Total number of commands processed.",
+            self.total_commands.load(Ordering::Relaxed),
+        );
+        metric(
+            "redis_net_input_bytes_total",
+            "counter",
+            "Total bytes read from clients.",
+            self.net_input_bytes.load(Ordering::Relaxed),
+        );
+        metric(
+            "redis_net_output_bytes_total",
+            "counter",
+            "Total bytes written to clients.",
+            self.net_output_bytes.load(Ordering::Relaxed),
+        );
+        metric(
+            "redis_expired_keys_total",
+            "counter",
+            "Total number of keys removed because their TTL passed.",
+            self.expired_keys.load(Ordering::Relaxed),
+        );
+        metric(
+            "redis_db_keys",
+            "gauge",
+            "Number of keys in the keyspace.",
+            keys as u64,
+        );
+
+        out.push_str("# HELP redis_commands_total Number of calls per command.\n");
+        out.push_str("# TYPE redis_commands_total counter\n");
+        // lowercased here rather than per call, as in redis' commandstats
+        let mut commands: Vec<_> = self
+            .commands
+            .iter()
+            .map(|(name, stats)| {
+                let calls = stats.calls.load(Ordering::Relaxed);
+                let micros = stats.duration_micros.load(Ordering::Relaxed);
+                (name.to_ascii_lowercase(), calls, micros)
+            })
+            .filter(|&(_, calls, _)| calls > 0)
+            .collect();
+        commands.sort();
+        for (name, calls, _) in &commands {
+            let _ = writeln!(out, "redis_commands_total{{cmd=\"{name}\"}} {calls}");
+        }
+        out.push_str(
+            "# HELP redis_commands_duration_seconds_total Time spent executing each command.\n",
+        );
+        out.push_str("# TYPE redis_commands_duration_seconds_total counter\n");
+        for (name, _, micros) in &commands {
+            let _ = writeln!(
+                out,
+                "redis_commands_duration_seconds_total{{cmd=\"{name}\"}} {}",
+                *micros as f64 / 1_000_000.0
+            );
+        }
+
+        out.push_str("# HELP redis_command_latency_seconds Command execution latency.\n");
+        out.push_str("# TYPE redis_command_latency_seconds histogram\n");
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "redis_command_latency_seconds_bucket{{le=\"{le}\"}} {cumulative}"
+            );
+        }
+        cumulative += self.latency_buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "redis_command_latency_seconds_bucket{{le=\"+Inf\"}} {cumulative}"
+        );
+        let _ = writeln!(
+            out,
+            "redis_command_latency_seconds_sum {}",
+            self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "redis_command_latency_seconds_count {cumulative}");
+        out
+    }
+}
+
+/// Feeds the instantaneous rates for the lifetime of the server.
+pub async fn run_sampler(stats: Arc<Stats>) {
+    let mut interval = time::interval(SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        stats.sample();
+    }
+}
@@ -0,0 +1,647 @@
+//! The keyspace, split into shards by key hash so that commands on
+//! unrelated keys don't contend for one lock.
+//!
+//! Shard locks are only ever held for a single map operation, never across
+//! an await, so plain mutexes are enough.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::Bytes;
+use tokio::time::{self, Duration, Instant};
+
+use crate::{
+    blocking::{Waiter, Waiters},
+    config::Config,
+    random::Rng,
+    stats::Stats,
+    value::{Value, WrongType},
+};
+
+/// Enough shards that a few dozen busy connections rarely collide. A power
+/// of two, so that SCAN cursors can carry the shard in their top bits.
+const SHARD_COUNT: usize = 64;
+const _: () = assert!(SHARD_COUNT.is_power_of_two());
+const SHARD_BITS: u32 = SHARD_COUNT.trailing_zeros();
+/// How many expired keys RANDOMKEY removes before giving up on finding a
+/// live one.
+const RANDOM_KEY_TRIES: usize = 100;
+/// Shards scanned per active expiry cycle, so the whole keyspace is covered
+/// every 64 / 8 = 8 cycles.
+const SHARDS_PER_CYCLE: usize = 8;
+/// Access frequency of a new key, as in redis, so that it isn't the first to
+/// go by LFU before it has had a chance to be used.
+const LFU_INIT: u8 = 5;
+/// redis' default `lfu-log-factor`: about a million accesses saturate the
+/// counter.
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+#[derive(Debug, Clone)]
+pub struct StoreValue {
+    pub value: Value,
+    pub expiry: Option<Instant>,
+    accessed: Instant,
+    /// Logarithmic access counter, as reported by OBJECT FREQ.
+    frequency: u8,
+}
+
+impl StoreValue {
+    pub fn new(value: Value, expiry: Option<Instant>) -> Self {
+        Self {
+            value,
+            expiry,
+            accessed: Instant::now(),
+            frequency: LFU_INIT,
+        }
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expiry.is_some_and(|expiry| expiry <= now)
+    }
+
+    /// How long since a command last read or wrote the key.
+    pub fn idle_time(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.accessed)
+    }
+
+    /// The access counter, which decays by one for every idle minute.
+    pub fn frequency(&self, now: Instant) -> u8 {
+        let idle_minutes = self.idle_time(now).as_secs() / 60;
+        self.frequency
+            .saturating_sub(idle_minutes.try_into().unwrap_or(u8::MAX))
+    }
+
+    /// Records an access. Like redis, the counter is incremented with a
+    /// probability that falls as it grows.
+    fn touch(&mut self, now: Instant) {
+        let mut frequency = self.frequency(now);
+        let base = f64::from(frequency.saturating_sub(LFU_INIT));
+        let chance = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+        if frequency < u8::MAX
+            && (chance >= 1.0 || (Rng::new().next() as f64 / u64::MAX as f64) < chance)
+        {
+            frequency += 1;
+        }
+        self.frequency = frequency;
+        self.accessed = now;
+    }
+}
+
+#[derive(Debug)]
+pub struct Store {
+    shards: Box<[Mutex<HashMap<Bytes, StoreValue>>]>,
+    hasher: RandomState,
+    /// Next shard for active expiry to scan.
+    expiry_cursor: AtomicUsize,
+    /// False on replicas, which hide expired keys but leave removing them
+    /// to their master.
+    delete_expired: bool,
+    stats: Arc<Stats>,
+    /// Connections blocked until one of their keys is written.
+    waiters: Waiters,
+}
+
+impl Store {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            shards: (0..SHARD_COUNT).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            expiry_cursor: AtomicUsize::new(0),
+            delete_expired: config.replica_of.is_none(),
+            stats: Arc::clone(&config.stats),
+            waiters: Waiters::default(),
+        }
+    }
+
+    pub fn from_entries(entries: HashMap<Bytes, StoreValue>, config: &Config) -> Self {
+        let store = Self::new(config);
+        store.replace(entries);
+        store
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        self.hasher.hash_one(key) as usize % self.shards.len()
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<HashMap<Bytes, StoreValue>> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    /// Runs `f` on the entry for `key` unless it doesn't exist or has
+    /// expired, in which case it is removed. All reads go through here, so
+    /// they agree on whether a key exists.
+    pub fn read<R>(&self, key: &[u8], f: impl FnOnce(&StoreValue) -> R) -> Option<R> {
+        self.lookup(key, true, f)
+    }
+
+    /// Like [`read`](Self::read), but not counted as an access of the key,
+    /// for commands that only ask about it.
+    pub fn peek<R>(&self, key: &[u8], f: impl FnOnce(&StoreValue) -> R) -> Option<R> {
+        self.lookup(key, false, f)
+    }
+
+    fn lookup<R>(&self, key: &[u8], touch: bool, f: impl FnOnce(&StoreValue) -> R) -> Option<R> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let value = shard.get_mut(key)?;
+        let now = Instant::now();
+        if !value.is_expired(now) {
+            if touch {
+                value.touch(now);
+            }
+            return Some(f(value));
+        }
+        if self.delete_expired {
+            if let Some((key, _)) = shard.remove_entry(key) {
+                drop(shard);
+                self.expired(&[key]);
+            }
+        }
+        None
+    }
+
+    /// The string at `key`, unless it doesn't exist or has expired.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>, WrongType> {
+        self.read(key, |v| v.value.as_string().cloned()).transpose()
+    }
+
+    pub fn exists(&self, key: &[u8]) -> bool {
+        self.peek(key, |_| ()).is_some()
+    }
+
+    /// How long `key` has left: `None` if it doesn't exist, `Some(None)` if
+    /// it has no TTL.
+    pub fn ttl(&self, key: &[u8]) -> Option<Option<Duration>> {
+        let now = Instant::now();
+        self.peek(key, |v| v.expiry.map(|e| e.saturating_duration_since(now)))
+    }
+
+    /// Removes `key`, returning whether it was there to remove: an expired
+    /// entry goes too, but as an expiry rather than a deletion.
+    pub fn remove(&self, key: &[u8]) -> bool {
+        let mut shard = self.shard(key).lock().unwrap();
+        let Some((key, value)) = shard.remove_entry(key) else {
+            return false;
+        };
+        drop(shard);
+        if value.is_expired(Instant::now()) {
+            self.expired(&[key]);
+            return false;
+        }
+        true
+    }
+
+    /// Runs `f` on the live entry for `key`, which it may change in place,
+    /// create by filling in `None`, or delete by taking. The shard stays
+    /// locked throughout, so read-modify-write commands don't race.
+    pub fn update<R>(&self, key: Bytes, f: impl FnOnce(&mut Option<StoreValue>) -> R) -> R {
+        let mut shard = self.shard(&key).lock().unwrap();
+        let (mut entry, dead) = self.take(&mut shard, &key);
+        let existed = entry.is_some();
+        let result = f(&mut entry);
+        remove_if_empty(&mut entry);
+        // a key that was just created hasn't been accessed yet
+        if let Some(value) = entry.as_mut().filter(|_| existed) {
+            value.touch(Instant::now());
+        }
+        let written = entry.is_some();
+        if let Some(value) = entry.or(dead) {
+            shard.insert(key.clone(), value);
+        }
+        drop(shard);
+        if written {
+            self.waiters.wake(&key);
+        }
+        result
+    }
+
+    /// Like [`update`](Self::update) for several distinct keys at once. The
+    /// shards involved are locked in index order, so concurrent calls can't
+    /// deadlock.
+    pub fn update_many<R>(
+        &self,
+        keys: &[Bytes],
+        f: impl FnOnce(&mut [Option<StoreValue>]) -> R,
+    ) -> R {
+        let shard_of: Vec<_> = keys.iter().map(|key| self.shard_index(key)).collect();
+        let mut indexes = shard_of.clone();
+        indexes.sort_unstable();
+        indexes.dedup();
+        let mut shards: Vec<_> = indexes
+            .iter()
+            .map(|&i| self.shards[i].lock().unwrap())
+            .collect();
+        let locked = |index| indexes.binary_search(&index).unwrap();
+        let (mut entries, dead): (Vec<_>, Vec<_>) = keys
+            .iter()
+            .zip(&shard_of)
+            .map(|(key, &index)| self.take(&mut shards[locked(index)], key))
+            .unzip();
+        let existed: Vec<_> = entries.iter().map(Option::is_some).collect();
+        let result = f(&mut entries);
+        entries.iter_mut().for_each(remove_if_empty);
+        let now = Instant::now();
+        for (entry, existed) in entries.iter_mut().zip(existed) {
+            if let Some(value) = entry.as_mut().filter(|_| existed) {
+                value.touch(now);
+            }
+        }
+        let mut written = Vec::new();
+        for (((key, index), entry), dead) in keys.iter().zip(shard_of).zip(entries).zip(dead) {
+            if entry.is_some() {
+                written.push(key);
+            }
+            if let Some(value) = entry.or(dead) {
+                shards[locked(index)].insert(key.clone(), value);
+            }
+        }
+        drop(shards);
+        for key in written {
+            self.waiters.wake(key);
+        }
+        result
+    }
+
+    /// Takes the entry for `key` out of `shard` to be updated. An expired
+    /// entry is dropped, except on replicas, which keep it for their master
+    /// to remove unless the update replaces it; it comes back second.
+    fn take(
+        &self,
+        shard: &mut HashMap<Bytes, StoreValue>,
+        key: &Bytes,
+    ) -> (Option<StoreValue>, Option<StoreValue>) {
+        let entry = shard.remove(key);
+        if !entry.as_ref().is_some_and(|v| v.is_expired(Instant::now())) {
+            return (entry, None);
+        }
+        if self.delete_expired {
+            self.expired(std::slice::from_ref(key));
+            return (None, None);
+        }
+        (None, entry)
+    }
+
+    /// Number of keys, counting expired ones not yet removed.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    /// Copies out every live entry. Shards are visited one at a time, so
+    /// this is not an atomic snapshot of concurrent writes.
+    pub fn entries(&self) -> Vec<(Bytes, StoreValue)> {
+        let now = Instant::now();
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            entries.extend(
+                shard
+                    .iter()
+                    .filter(|(_, v)| !v.is_expired(now))
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            );
+        }
+        entries
+    }
+
+    /// Queues up to be woken when one of `keys` is written, for as long as
+    /// the returned [`Waiter`] lives.
+    pub fn wait_on(&self, keys: &[Bytes]) -> Waiter<'_> {
+        self.waiters.register(keys)
+    }
+
+    /// A live key picked uniformly at random. This has to walk part of a
+    /// shard to get to the chosen key, but never locks more than one.
+    pub fn random_key(&self) -> Option<Bytes> {
+        let mut rng = Rng::new();
+        for _ in 0..RANDOM_KEY_TRIES {
+            let lens: Vec<_> = self
+                .shards
+                .iter()
+                .map(|s| s.lock().unwrap().len())
+                .collect();
+            let total = lens.iter().sum();
+            if total == 0 {
+                return None;
+            }
+            let mut pick = rng.below(total);
+            let mut index = 0;
+            while pick >= lens[index] {
+                pick -= lens[index];
+                index += 1;
+            }
+            let mut shard = self.shards[index].lock().unwrap();
+            // the shard may have shrunk since it was counted
+            let Some((key, value)) = shard.iter().nth(pick) else {
+                continue;
+            };
+            if !value.is_expired(Instant::now()) {
+                return Some(key.clone());
+            }
+            // on a replica, an expired key is as good as any
+            if !self.delete_expired {
+                return Some(key.clone());
+            }
+            let key = key.clone();
+            shard.remove(&key);
+            drop(shard);
+            self.expired(&[key]);
+        }
+        None
+    }
+
+    /// Every live key `filter` accepts. Like [`entries`](Self::entries),
+    /// this is not an atomic snapshot.
+    pub fn keys(&self, mut filter: impl FnMut(&[u8]) -> bool) -> Vec<Bytes> {
+        let now = Instant::now();
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            keys.extend(
+                shard
+                    .iter()
+                    .filter(|(k, v)| !v.is_expired(now) && filter(k))
+                    .map(|(k, _)| k.clone()),
+            );
+        }
+        keys
+    }
+
+    /// Where `key` comes in a SCAN: ordered by shard first, the shard
+    /// being the low bits of the hash, then by the rest of the hash.
+    fn scan_position(&self, key: &[u8]) -> u64 {
+        self.hasher.hash_one(key).rotate_right(SHARD_BITS)
+    }
+
+    /// Walks the keyspace a little at a time, so a big one can be iterated
+    /// without holding any lock for long. Starting at `cursor` (0 for the
+    /// beginning), looks at about `count` keys and returns the live ones
+    /// `filter` accepts, with the cursor to continue from, which is 0 once
+    /// the walk is done. Keys are visited in hash order, which doesn't
+    /// change as the keyspace does, so a key that exists for the whole walk
+    /// is returned at least once.
+    pub fn scan(
+        &self,
+        mut cursor: u64,
+        count: usize,
+        mut filter: impl FnMut(&[u8], &StoreValue) -> bool,
+    ) -> (u64, Vec<Bytes>) {
+        let now = Instant::now();
+        let mut keys = Vec::new();
+        let mut examined = 0;
+        loop {
+            let index = (cursor >> (u64::BITS - SHARD_BITS)) as usize;
+            let shard = self.shards[index].lock().unwrap();
+            let positioned = shard.iter().map(|(k, v)| (self.scan_position(k), (k, v)));
+            let (taken, next) = scan_step(positioned, cursor, count.saturating_sub(examined));
+            examined += taken.len();
+            for (k, v) in taken {
+                if !v.is_expired(now) && filter(k, v) {
+                    keys.push(k.clone());
+                }
+            }
+            if let Some(next) = next {
+                return (next, keys);
+            }
+            if index + 1 == SHARD_COUNT {
+                return (0, keys);
+            }
+            cursor = ((index + 1) as u64) << (u64::BITS - SHARD_BITS);
+            if examined >= count {
+                return (cursor, keys);
+            }
+        }
+    }
+
+    /// Like [`scan`](Self::scan), over the members of a collection such as
+    /// the fields of a hash, which come with whatever goes with them.
+    pub fn scan_members<'m, T>(
+        &self,
+        cursor: u64,
+        count: usize,
+        members: impl Iterator<Item = (&'m Bytes, T)>,
+    ) -> (u64, Vec<(&'m Bytes, T)>) {
+        let positioned = members.map(|(m, t)| (self.scan_position(m), (m, t)));
+        let (taken, next) = scan_step(positioned, cursor, count);
+        (next.unwrap_or(0), taken)
+    }
+
+    /// Removes the expired keys of the next few shards, returning how many
+    /// there were.
+    pub fn expire_cycle(&self) -> usize {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for _ in 0..SHARDS_PER_CYCLE {
+            let next = self.expiry_cursor.fetch_add(1, Ordering::Relaxed);
+            let mut shard = self.shards[next % self.shards.len()].lock().unwrap();
+            shard.retain(|key, value| {
+                let live = !value.is_expired(now);
+                if !live {
+                    expired.push(key.clone());
+                }
+                live
+            });
+        }
+        self.expired(&expired);
+        expired.len()
+    }
+
+    /// Every removal of expired keys ends up here, which is where keyspace
+    /// notifications will be sent from.
+    fn expired(&self, keys: &[Bytes]) {
+        self.stats
+            .expired_keys
+            .fetch_add(keys.len() as u64, Ordering::Relaxed);
+    }
+
+    /// Empties the keyspace, handing back what was in it so the caller can
+    /// choose where to pay for dropping it.
+    pub fn clear(&self) -> Vec<HashMap<Bytes, StoreValue>> {
+        self.shards
+            .iter()
+            .map(|shard| std::mem::take(&mut *shard.lock().unwrap()))
+            .collect()
+    }
+
+    /// Swaps the whole keyspace for `entries`.
+    pub fn replace(&self, entries: HashMap<Bytes, StoreValue>) {
+        let mut sharded: Vec<HashMap<_, _>> = vec![HashMap::new(); self.shards.len()];
+        for (key, value) in entries {
+            let hash = self.hasher.hash_one(&key[..]);
+            sharded[hash as usize % self.shards.len()].insert(key, value);
+        }
+        for (shard, entries) in self.shards.iter().zip(sharded) {
+            *shard.lock().unwrap() = entries;
+        }
+    }
+}
+
+/// Deletes a key whose collection an update left empty.
+fn remove_if_empty(entry: &mut Option<StoreValue>) {
+    if entry
+        .as_ref()
+        .is_some_and(|e| e.value.is_empty_collection())
+    {
+        *entry = None;
+    }
+}
+
+/// Takes about `count` of `items` at or after the position `cursor`, in
+/// position order, with the position to continue from if any are left.
+fn scan_step<T>(
+    items: impl Iterator<Item = (u64, T)>,
+    cursor: u64,
+    count: usize,
+) -> (Vec<T>, Option<u64>) {
+    let mut pending: Vec<_> = items.filter(|(position, _)| *position >= cursor).collect();
+    pending.sort_unstable_by_key(|(position, _)| *position);
+    let mut taken = pending.len().min(count.max(1));
+    // items whose positions collide can't be told apart by the cursor, so
+    // they have to be returned together
+    while taken < pending.len() && pending[taken].0 == pending[taken - 1].0 {
+        taken += 1;
+    }
+    let next = pending.get(taken).map(|(position, _)| *position);
+    pending.truncate(taken);
+    (pending.into_iter().map(|(_, item)| item).collect(), next)
+}
+
+/// Removes expired keys in the background, `hz` times a second, for the
+/// lifetime of the server. Replicas leave expiry to their master.
+pub async fn run_expirer(store: Arc<Store>, config: Arc<Config>) {
+    if !store.delete_expired {
+        return;
+    }
+    loop {
+        // re-read every cycle, as CONFIG SET may change it
+        let hz = config.tunables().hz;
+        time::sleep(Duration::from_secs(1) / hz).await;
+        store.expire_cycle();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn key(i: usize) -> Bytes {
+        Bytes::from(format!("key:{i}"))
+    }
+
+    fn value(expiry: Option<Instant>) -> StoreValue {
+        StoreValue::new(Value::String(Bytes::new()), expiry)
+    }
+
+    fn store_with(keys: impl IntoIterator<Item = Bytes>) -> Store {
+        let entries = keys.into_iter().map(|key| (key, value(None))).collect();
+        Store::from_entries(entries, &Config::default())
+    }
+
+    /// Runs a whole SCAN walk, `count` keys at a time, calling `between`
+    /// after each step.
+    fn scan_all(
+        store: &Store,
+        count: usize,
+        filter: impl Fn(&[u8]) -> bool,
+        mut between: impl FnMut(&Store),
+    ) -> Vec<Bytes> {
+        let (mut cursor, mut keys) = (0, Vec::new());
+        loop {
+            let (next, found) = store.scan(cursor, count, |key, _| filter(key));
+            keys.extend(found);
+            if next == 0 {
+                return keys;
+            }
+            assert!(next > cursor, "cursor went from {cursor} back to {next}");
+            cursor = next;
+            between(store);
+        }
+    }
+
+    #[test]
+    fn scan_visits_every_key_once() {
+        let all: HashSet<_> = (0..1000).map(key).collect();
+        let store = store_with(all.iter().cloned());
+        for count in [1, 7, 10, 100, 5000] {
+            let found = scan_all(&store, count, |_| true, |_| {});
+            assert_eq!(found.len(), all.len(), "count {count}");
+            assert_eq!(found.into_iter().collect::<HashSet<_>>(), all);
+        }
+        assert_eq!(store_with([]).scan(0, 10, |_, _| true), (0, Vec::new()));
+    }
+
+    #[test]
+    fn scan_returns_keys_that_exist_for_the_whole_walk() {
+        let store = store_with((0..500).map(key));
+        let (mut removed, mut added) = (0..250, 500..);
+        let found: HashSet<_> = scan_all(
+            &store,
+            10,
+            |_| true,
+            |store| {
+                // keys come and go between calls
+                store.remove(&key(removed.next().unwrap_or(0)));
+                store.update(key(added.next().unwrap()), |entry| {
+                    *entry = Some(value(None))
+                });
+            },
+        )
+        .into_iter()
+        .collect();
+        assert!((250..500).map(key).all(|key| found.contains(&key)));
+    }
+
+    #[test]
+    fn scan_skips_expired_and_filtered_keys() {
+        let store = store_with((1..100).map(key));
+        store.update(key(0), |entry| *entry = Some(value(Some(Instant::now()))));
+        let found = scan_all(&store, 10, |key| !key.ends_with(b"1"), |_| {});
+        let expected: HashSet<_> = (1..100).map(key).filter(|k| !k.ends_with(b"1")).collect();
+        assert_eq!(found.into_iter().collect::<HashSet<_>>(), expected);
+    }
+
+    #[test]
+    fn scan_step_resumes_in_position_order() {
+        let items = [(30, 'c'), (10, 'a'), (20, 'b'), (40, 'd')];
+        assert_eq!(
+            scan_step(items.into_iter(), 0, 2),
+            (vec!['a', 'b'], Some(30))
+        );
+        assert_eq!(scan_step(items.into_iter(), 30, 2), (vec!['c', 'd'], None));
+        assert_eq!(scan_step(items.into_iter(), 25, 1), (vec!['c'], Some(40)));
+        assert_eq!(scan_step(items.into_iter(), 41, 5), (vec![], None));
+        // a count of 0 still makes progress
+        assert_eq!(scan_step(items.into_iter(), 0, 0), (vec!['a'], Some(20)));
+    }
+
+    #[test]
+    fn scan_step_keeps_colliding_positions_together() {
+        let items = [(1, 'a'), (2, 'b'), (2, 'c'), (2, 'd'), (3, 'e')];
+        let (mut taken, next) = scan_step(items.into_iter(), 0, 2);
+        taken.sort_unstable();
+        assert_eq!((taken, next), (vec!['a', 'b', 'c', 'd'], Some(3)));
+    }
+
+    #[test]
+    fn scan_members_resumes_from_its_cursor() {
+        let store = store_with([]);
+        let fields: HashMap<_, _> = (0..200).map(|i| (key(i), i)).collect();
+        let (mut cursor, mut found) = (0, Vec::new());
+        loop {
+            let (next, taken) = store.scan_members(cursor, 15, fields.iter());
+            found.extend(taken.into_iter().map(|(field, i)| (field.clone(), *i)));
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(found.len(), fields.len());
+        assert_eq!(found.into_iter().collect::<HashMap<_, _>>(), fields);
+    }
+}
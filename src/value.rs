@@ -0,0 +1,540 @@
+//! The kinds of value a key can hold.
+
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt,
+    ops::Bound,
+    str::FromStr,
+};
+
+use bytes::Bytes;
+use thiserror::Error;
+use tokio::time::Instant;
+
+/// A command was run against a key holding another type. Unlike other
+/// command errors this has its own prefix, so it is sent as is.
+#[derive(Debug, Error)]
+#[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+pub struct WrongType;
+
+/// A consumer group is missing, or is already there when being created.
+/// Like [`WrongType`] these are sent as is.
+#[derive(Debug, Error)]
+pub enum GroupError {
+    #[error("NOGROUP No such key '{key}' or consumer group '{group}'")]
+    NoGroup { key: String, group: String },
+    #[error("BUSYGROUP Consumer Group name already exists")]
+    Busy,
+}
+
+impl GroupError {
+    pub fn no_group(key: &[u8], group: &[u8]) -> Self {
+        GroupError::NoGroup {
+            key: String::from_utf8_lossy(key).into_owned(),
+            group: String::from_utf8_lossy(group).into_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+    Set(Set),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+impl Value {
+    /// What the value is, as TYPE reports it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    /// How the value is held, as OBJECT ENCODING reports it, in the name
+    /// redis gives its closest encoding. Nothing is stored compactly here, so
+    /// there is no int, embstr, listpack or intset: strings are raw bytes,
+    /// hashes and sets hash tables, a sorted set a map with an ordered index
+    /// like redis' skiplist, and a list a ring buffer, which redis' general
+    /// list encoding stands for.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(_) => "raw",
+            Value::List(_) => "quicklist",
+            Value::Hash(_) | Value::Set(_) => "hashtable",
+            Value::SortedSet(_) => "skiplist",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    pub fn as_string(&self) -> Result<&Bytes, WrongType> {
+        match self {
+            Value::String(value) => Ok(value),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&VecDeque<Bytes>, WrongType> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Result<&mut VecDeque<Bytes>, WrongType> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_hash(&self) -> Result<&HashMap<Bytes, Bytes>, WrongType> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> Result<&mut HashMap<Bytes, Bytes>, WrongType> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_set(&self) -> Result<&Set, WrongType> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_set_mut(&mut self) -> Result<&mut Set, WrongType> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_sorted_set(&self) -> Result<&SortedSet, WrongType> {
+        match self {
+            Value::SortedSet(set) => Ok(set),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_sorted_set_mut(&mut self) -> Result<&mut SortedSet, WrongType> {
+        match self {
+            Value::SortedSet(set) => Ok(set),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_stream(&self) -> Result<&Stream, WrongType> {
+        match self {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_stream_mut(&mut self) -> Result<&mut Stream, WrongType> {
+        match self {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(WrongType),
+        }
+    }
+
+    /// Whether this is a collection with nothing left in it. redis never
+    /// keeps those around, so such a key is removed.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::SortedSet(set) => set.len() == 0,
+            // an empty stream still has its last ID
+            Value::Stream(_) => false,
+        }
+    }
+}
+
+/// Members in no particular order, each with its place in that order, so
+/// that a random one can be found or removed without a walk over the rest.
+#[derive(Debug, Clone, Default)]
+pub struct Set {
+    places: HashMap<Bytes, usize>,
+    members: Vec<Bytes>,
+}
+
+impl Set {
+    /// Adds `member`, returning whether it is new.
+    pub fn insert(&mut self, member: Bytes) -> bool {
+        match self.places.entry(member) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                self.members.push(entry.key().clone());
+                entry.insert(self.members.len() - 1);
+                true
+            }
+        }
+    }
+
+    /// Removes `member`, returning whether it was one.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        let Some(place) = self.places.remove(member) else {
+            return false;
+        };
+        self.take(place);
+        true
+    }
+
+    /// Removes the member at `place`, which must be below the length.
+    pub fn remove_at(&mut self, place: usize) -> Bytes {
+        let member = self.take(place);
+        self.places.remove(&member);
+        member
+    }
+
+    /// Takes the member at `place` out of the order, moving the last one
+    /// into its place.
+    fn take(&mut self, place: usize) -> Bytes {
+        let member = self.members.swap_remove(place);
+        if let Some(moved) = self.members.get(place) {
+            *self.places.get_mut(moved).unwrap() = place;
+        }
+        member
+    }
+
+    /// The member at `place`, which must be below the length.
+    pub fn member(&self, place: usize) -> &Bytes {
+        &self.members[place]
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        self.places.contains_key(member)
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Bytes> {
+        self.members.iter()
+    }
+}
+
+impl IntoIterator for Set {
+    type Item = Bytes;
+    type IntoIter = std::vec::IntoIter<Bytes>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.members.into_iter()
+    }
+}
+
+impl FromIterator<Bytes> for Set {
+    fn from_iter<I: IntoIterator<Item = Bytes>>(members: I) -> Self {
+        let mut set = Set::default();
+        for member in members {
+            set.insert(member);
+        }
+        set
+    }
+}
+
+/// Members ordered by score, ties broken by member, with a map to look up
+/// the score of a member.
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: BTreeSet<(Score, Bytes)>,
+}
+
+impl SortedSet {
+    /// Adds `member`, or moves it to `score`, returning its previous score.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> Option<f64> {
+        // 0 and -0 must not be two different places in the order
+        let score = if score == 0.0 { 0.0 } else { score };
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.ordered.remove(&(Score(old), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        old
+    }
+
+    /// Removes `member`, returning its score if it was one.
+    pub fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        let (member, score) = self.scores.remove_entry(member)?;
+        self.ordered.remove(&(Score(score), member));
+        Some(score)
+    }
+
+    /// Removes the member with the highest score if `max`, otherwise the
+    /// lowest.
+    pub fn pop(&mut self, max: bool) -> Option<(Bytes, f64)> {
+        let (score, member) = if max {
+            self.ordered.pop_last()?
+        } else {
+            self.ordered.pop_first()?
+        };
+        self.scores.remove(&member);
+        Some((member, score.0))
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// How many members come before `member`, if it is one.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let (member, &score) = self.scores.get_key_value(member)?;
+        Some(self.ordered.range(..(Score(score), member.clone())).count())
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    /// Members with scores between `min` and `max`, lowest score first, or
+    /// highest first if `rev`.
+    pub fn range_by_score(
+        &self,
+        min: Bound<f64>,
+        max: Bound<f64>,
+        rev: bool,
+    ) -> Box<dyn Iterator<Item = (&Bytes, f64)> + '_> {
+        let too_low = move |&(_, score): &(&Bytes, f64)| match min {
+            Bound::Included(min) => score < min,
+            Bound::Excluded(min) => score <= min,
+            Bound::Unbounded => false,
+        };
+        let too_high = move |&(_, score): &(&Bytes, f64)| match max {
+            Bound::Included(max) => score > max,
+            Bound::Excluded(max) => score >= max,
+            Bound::Unbounded => false,
+        };
+        // seek as close to the first member in range as the bound allows,
+        // the empty member being the first of those with a score
+        if rev {
+            let end = match max {
+                Bound::Excluded(max) => Bound::Excluded((Score(max), Bytes::new())),
+                _ => Bound::Unbounded,
+            };
+            Box::new(
+                self.ordered
+                    .range((Bound::Unbounded, end))
+                    .rev()
+                    .map(|(score, member)| (member, score.0))
+                    .skip_while(too_high)
+                    .take_while(move |entry| !too_low(entry)),
+            )
+        } else {
+            let start = match min {
+                Bound::Included(min) | Bound::Excluded(min) => {
+                    Bound::Included((Score(min), Bytes::new()))
+                }
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            Box::new(
+                self.ordered
+                    .range((start, Bound::Unbounded))
+                    .map(|(score, member)| (member, score.0))
+                    .skip_while(too_low)
+                    .take_while(move |entry| !too_high(entry)),
+            )
+        }
+    }
+
+    /// Members and their scores, lowest score first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Bytes, f64)> + ExactSizeIterator {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+}
+
+/// A score that can be ordered, which is fine as NaN is never stored.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// A stream entry ID, `<milliseconds>-<sequence>`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+impl FromStr for StreamId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (ms, seq) = s.split_once('-').unwrap_or((s, "0"));
+        match (ms.parse(), seq.parse()) {
+            (Ok(ms), Ok(seq)) => Ok(StreamId { ms, seq }),
+            _ => anyhow::bail!("Invalid stream ID specified as stream command argument"),
+        }
+    }
+}
+
+/// Entries in ID order, each a list of field-value pairs, and the consumer
+/// groups reading them.
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Vec<(Bytes, Bytes)>>,
+    /// The greatest ID ever added, which new ones must be greater than
+    /// even once its entry is gone.
+    pub last_id: StreamId,
+    pub groups: HashMap<Bytes, ConsumerGroup>,
+}
+
+/// Where a consumer group has read up to, and the entries it has delivered
+/// that are yet to be acknowledged, each pending with one of its consumers.
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerGroup {
+    pub last_delivered: StreamId,
+    pub pending: BTreeMap<StreamId, Pending>,
+    pub consumers: HashMap<Bytes, Consumer>,
+}
+
+/// A delivered entry that hasn't been acknowledged.
+#[derive(Debug, Clone)]
+pub struct Pending {
+    pub consumer: Bytes,
+    pub delivered_at: Instant,
+    pub deliveries: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Consumer {
+    /// The IDs pending with this consumer, a subset of its group's.
+    pub pending: BTreeSet<StreamId>,
+    pub seen_at: Instant,
+}
+
+impl ConsumerGroup {
+    /// The consumer called `name`, created if it isn't one yet, and seen
+    /// `now`.
+    pub fn consumer(&mut self, name: &Bytes, now: Instant) -> &mut Consumer {
+        let consumer = self
+            .consumers
+            .entry(name.clone())
+            .or_insert_with(|| Consumer {
+                pending: BTreeSet::new(),
+                seen_at: now,
+            });
+        consumer.seen_at = now;
+        consumer
+    }
+
+    /// Makes `id` pending with `consumer`, delivered `at`, taking it from
+    /// the consumer it was pending with, if any. Its delivery count is left
+    /// for the caller to bump.
+    pub fn assign(&mut self, id: StreamId, consumer: &Bytes, at: Instant) -> &mut Pending {
+        if let Some(pending) = self.pending.get(&id) {
+            if let Some(owner) = self.consumers.get_mut(&pending.consumer) {
+                owner.pending.remove(&id);
+            }
+        }
+        self.consumer(consumer, at).pending.insert(id);
+        let pending = self.pending.entry(id).or_insert_with(|| Pending {
+            consumer: consumer.clone(),
+            delivered_at: at,
+            deliveries: 0,
+        });
+        pending.consumer = consumer.clone();
+        pending.delivered_at = at;
+        pending
+    }
+
+    /// Acknowledges `id`, returning whether it was pending.
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        let Some(pending) = self.pending.remove(&id) else {
+            return false;
+        };
+        if let Some(owner) = self.consumers.get_mut(&pending.consumer) {
+            owner.pending.remove(&id);
+        }
+        true
+    }
+
+    /// Removes the consumer called `name` along with the entries pending
+    /// with it, returning how many those were if it was one.
+    pub fn remove_consumer(&mut self, name: &[u8]) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_keeps_places_through_removals() {
+        let mut set: Set = (0..100).map(|i| Bytes::from(i.to_string())).collect();
+        assert!(!set.insert(Bytes::from_static(b"7")));
+        assert!(set.remove(b"7"));
+        assert!(!set.remove(b"7"));
+        let first = set.remove_at(0);
+        assert!(!set.contains(&first));
+        for place in 0..set.len() {
+            let member = set.member(place).clone();
+            assert!(set.contains(&member));
+        }
+        while !set.is_empty() {
+            let member = set.remove_at(set.len() / 2);
+            assert!(!set.contains(&member));
+        }
+        assert!(set.places.is_empty());
+    }
+}
@@ -0,0 +1,386 @@
+//! RESP tunneled over WebSocket, for browser dashboards and other clients
+//! that can't open raw TCP. Enabled with `--websocket-port`.
+//!
+//! Every data message carries raw RESP bytes, in either direction. Clients
+//! may send text or binary messages, but replies always go out as binary:
+//! they may hold any bytes, and are cut up wherever the bridge's reads
+//! happen to end, which could be in the middle of a character.
+//! After the HTTP upgrade, each connection is bridged through an in-memory
+//! duplex to the same connection handler that serves TCP clients.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use bytes::{Buf, BytesMut};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::Mutex,
+    time,
+};
+
+use crate::{config::Config, store::Store, Connections};
+
+/// Appended to the client's key before hashing, per RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Upper bound on the size of the HTTP upgrade request.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+/// Buffer size of each direction of the bridge to the connection handler.
+const BRIDGE_CAPACITY: usize = 64 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Accepts WebSocket clients until shutdown.
+pub async fn serve(
+    listener: TcpListener,
+    store: Arc<Store>,
+    config: Arc<Config>,
+    connections: Connections,
+) {
+    let mut shutdown = config.shutdown.subscribe();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.changed() => return,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // usually transient (e.g. out of file descriptors), so back off
+                // briefly, as the TCP listener does
+                eprintln!("failed to accept WebSocket connection: {e}");
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_nodelay(config.tcp_nodelay) {
+            eprintln!("failed to set TCP_NODELAY for {addr}: {e}");
+        }
+        let (store, config, connections) =
+            (Arc::clone(&store), Arc::clone(&config), connections.clone());
+        tokio::spawn(async move {
+            if let Err(e) = bridge(stream, addr, store, config, connections).await {
+                eprintln!("WebSocket connection {addr} closed: {e:#}");
+            }
+        });
+    }
+}
+
+async fn bridge(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    store: Arc<Store>,
+    config: Arc<Config>,
+    connections: Connections,
+) -> anyhow::Result<()> {
+    let mut buf = BytesMut::new();
+    upgrade(&mut stream, &mut buf).await?;
+
+    let (server_side, bridge_side) = io::duplex(BRIDGE_CAPACITY);
+    crate::spawn_connection(server_side, addr, store, Arc::clone(&config), connections);
+
+    let (tcp_read, tcp_write) = stream.into_split();
+    let tcp_write = Arc::new(Mutex::new(tcp_write));
+    let (bridge_read, bridge_write) = io::split(bridge_side);
+    let closed = Arc::new(AtomicBool::new(false));
+    let max_message_len = config.tunables().limits.max_bulk_len;
+    // separate tasks for each direction, so a reply that fills the bridge
+    // can't stall reading the next request, and vice versa
+    let inbound = tokio::spawn(inbound(
+        tcp_read,
+        buf,
+        bridge_write,
+        Arc::clone(&tcp_write),
+        Arc::clone(&closed),
+        max_message_len,
+    ));
+    let outbound = outbound(bridge_read, tcp_write, closed).await;
+    inbound.abort();
+    outbound
+}
+
+/// Reads the HTTP upgrade request and answers it. Anything the client sent
+/// after the request stays in `buf`.
+async fn upgrade(stream: &mut TcpStream, buf: &mut BytesMut) -> anyhow::Result<()> {
+    let end = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        anyhow::ensure!(buf.len() < MAX_REQUEST_LEN, "upgrade request too long");
+        anyhow::ensure!(
+            stream.read_buf(buf).await? > 0,
+            "connection closed during upgrade"
+        );
+    };
+    let request = buf.split_to(end + 4);
+    let request = String::from_utf8_lossy(&request);
+    let mut lines = request.split("\r\n");
+    let is_get = lines.next().is_some_and(|l| l.starts_with("GET "));
+    let (mut upgrade, mut key) = (false, None);
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("upgrade") {
+            upgrade = value.eq_ignore_ascii_case("websocket");
+        } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+            key = Some(value.to_string());
+        }
+    }
+    let Some(key) = key.filter(|_| is_get && upgrade) else {
+        stream
+            .write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+            .await?;
+        anyhow::bail!("not a WebSocket upgrade request");
+    };
+    let accept = accept_key(&key);
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Unwraps client messages into the bridge, answering pings and closes.
+async fn inbound(
+    mut tcp: impl AsyncRead + Unpin,
+    mut buf: BytesMut,
+    mut bridge: impl AsyncWrite + Unpin,
+    tcp_write: Arc<Mutex<OwnedWriteHalf>>,
+    closed: Arc<AtomicBool>,
+    max_message_len: usize,
+) -> anyhow::Result<()> {
+    loop {
+        while let Some((opcode, payload)) = decode_frame(&mut buf, max_message_len)? {
+            match opcode {
+                OP_TEXT | OP_BINARY | OP_CONTINUATION => bridge.write_all(&payload).await?,
+                OP_PING => write_frame(&tcp_write, OP_PONG, &payload).await?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    // the echo is the only close this connection sends
+                    closed.store(true, Ordering::Relaxed);
+                    let _ = write_frame(&tcp_write, OP_CLOSE, &payload).await;
+                    // closing the bridge ends the connection handler; merely
+                    // dropping this half of it would leave it open
+                    bridge.shutdown().await?;
+                    return Ok(());
+                }
+                other => anyhow::bail!("unknown WebSocket opcode {other:#x}"),
+            }
+        }
+        if tcp.read_buf(&mut buf).await? == 0 {
+            bridge.shutdown().await?;
+            return Ok(());
+        }
+    }
+}
+
+/// Wraps everything the connection handler writes into binary messages.
+/// Once the handler is done, closes the connection unless the client
+/// already has.
+async fn outbound(
+    mut bridge: impl AsyncRead + Unpin,
+    tcp_write: Arc<Mutex<OwnedWriteHalf>>,
+    closed: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let mut chunk = vec![0; BRIDGE_CAPACITY];
+    loop {
+        let read = bridge.read(&mut chunk).await?;
+        if read == 0 {
+            if !closed.load(Ordering::Relaxed) {
+                let _ = write_frame(&tcp_write, OP_CLOSE, &[]).await;
+            }
+            return Ok(());
+        }
+        write_frame(&tcp_write, OP_BINARY, &chunk[..read]).await?;
+    }
+}
+
+/// Decodes one client frame, or returns `None` if it isn't complete yet.
+fn decode_frame(
+    buf: &mut BytesMut,
+    max_message_len: usize,
+) -> anyhow::Result<Option<(u8, BytesMut)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let opcode = buf[0] & 0x0f;
+    anyhow::ensure!(buf[1] & 0x80 != 0, "client frames must be masked");
+    let (len, header) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u64::from(u16::from_be_bytes([buf[2], buf[3]])), 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        len => (u64::from(len), 2),
+    };
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= max_message_len)
+        .context("WebSocket frame too large")?;
+    if buf.len() < header + 4 + len {
+        buf.reserve(header + 4 + len - buf.len());
+        return Ok(None);
+    }
+    buf.advance(header);
+    let mask = buf.split_to(4);
+    let mut payload = buf.split_to(len);
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some((opcode, payload)))
+}
+
+async fn write_frame(
+    tcp_write: &Mutex<OwnedWriteHalf>,
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    tcp_write.lock().await.write_all(&frame).await
+}
+
+/// The `Sec-WebSocket-Accept` answering a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{key}{ACCEPT_GUID}").as_bytes()))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut digest = [0; 20];
+    for (out, word) in digest.chunks_mut(4).zip(h) {
+        out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digest: &[u8]) -> String {
+        digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_6455() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn sha1_test_vectors() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // two blocks once padded
+        assert_eq!(
+            hex(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(
+            hex(&sha1(&[b'a'; 1000])),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn base64_pads_partial_groups() {
+        let encoded: Vec<_> = ["", "f", "fo", "foo", "foob", "fooba", "foobar"]
+            .iter()
+            .map(|s| base64(s.as_bytes()))
+            .collect();
+        assert_eq!(
+            encoded,
+            ["", "Zg==", "Zm8=", "Zm9v", "Zm9vYg==", "Zm9vYmE=", "Zm9vYmFy"]
+        );
+        assert_eq!(base64(&[0xff, 0xfe, 0x00]), "//4A");
+    }
+}